@@ -1,13 +1,6 @@
-use std::{
-    collections::{HashMap, HashSet},
-    iter::Inspect,
-};
+use std::collections::HashSet;
 
-use petgraph::{
-    graph::{self, NodeIndex},
-    visit::IntoNodeIdentifiers,
-    Directed,
-};
+use petgraph::{graph::NodeIndex, Directed};
 
 fn main() {
     let filename = std::env::args()
@@ -74,7 +67,7 @@ fn bfs_check_initiator(
         }
     }
 
-    !(nodes_covered.len() < nodes_total_num)
+    nodes_covered.len() >= nodes_total_num
 }
 
 fn bfs_check_all_initiators(graph: &petgraph::Graph<String, (), Directed>) -> Vec<NodeIndex> {
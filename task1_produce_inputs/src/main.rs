@@ -1,5 +1,4 @@
 #![allow(non_snake_case)]
-use std::collections::HashMap;
 
 fn main() {
     use std::io::Write;
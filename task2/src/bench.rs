@@ -0,0 +1,288 @@
+use std::io::Write;
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+    input::NodeTaskInstruction, network::NetworkBuilder, node::Algorithm, replay::Replay,
+    time::VirtualTime, trace::TraceEvent,
+};
+
+/// Parameters of the whole sweep
+#[derive(Debug, Clone)]
+pub struct BenchConfig {
+    pub algorithms: Vec<Algorithm>,
+    pub node_counts: Vec<usize>,
+    /// How often every node wants to enter the critical section, in requests
+    /// per second of the virtual time. The time spent idle between the
+    /// requests is drawn from the exponential distribution.
+    pub request_rates: Vec<f64>,
+    /// How many times every node enters the critical section in a single run
+    pub requests_per_node: usize,
+    /// Time spent in the critical section, in virtual milliseconds
+    pub cs_duration_ms: u64,
+    /// Delivering a message takes a random time from this range, in virtual
+    /// milliseconds
+    pub message_delay_ms: (u64, u64),
+    pub seed: u64,
+}
+
+impl Default for BenchConfig {
+    fn default() -> Self {
+        Self {
+            algorithms: vec![Algorithm::RicartAgrawala, Algorithm::SuzukiKasami],
+            node_counts: vec![2, 4, 8, 16, 32, 64, 128],
+            request_rates: vec![0.1, 1.0, 10.0],
+            requests_per_node: 20,
+            cs_duration_ms: 10,
+            message_delay_ms: (1, 5),
+            seed: 2023,
+        }
+    }
+}
+
+/// Parses either a comma separated list (`2,4,8`) or an inclusive range
+/// (`2..=128`) of node counts.
+pub fn parse_node_counts(text: &str) -> Result<Vec<usize>, String> {
+    let parse = |n: &str| {
+        n.trim()
+            .parse::<usize>()
+            .map_err(|e| format!("invalid node count `{n}`: {e}"))
+    };
+    let counts = if let Some((from, to)) = text.split_once("..=") {
+        (parse(from)?..=parse(to)?).collect::<Vec<_>>()
+    } else {
+        text.split(',').map(parse).collect::<Result<Vec<_>, _>>()?
+    };
+    if counts.is_empty() || counts.contains(&0) {
+        return Err(format!("`{text}` does not give any positive node counts"));
+    }
+    Ok(counts)
+}
+
+/// Parses a comma separated list of request rates
+pub fn parse_rates(text: &str) -> Result<Vec<f64>, String> {
+    text.split(',')
+        .map(|r| match r.trim().parse::<f64>() {
+            Ok(rate) if rate > 0.0 => Ok(rate),
+            _ => Err(format!(
+                "invalid request rate `{r}`, expected a positive number"
+            )),
+        })
+        .collect()
+}
+
+/// The outcome of a single run
+#[derive(Debug, Clone)]
+pub struct BenchResult {
+    pub algorithm: Algorithm,
+    pub nodes: usize,
+    pub request_rate: f64,
+    pub cs_entries: usize,
+    pub messages: usize,
+    /// Time between wanting and entering the critical section, one value per
+    /// entry, in virtual milliseconds
+    pub waits_ms: Vec<u64>,
+    /// The virtual time at which the last node left the critical section
+    pub makespan_ms: u64,
+}
+
+impl BenchResult {
+    pub const CSV_HEADER: &'static str = "algorithm,nodes,request_rate,cs_entries,messages,messages_per_cs_entry,mean_wait_ms,p50_wait_ms,p95_wait_ms,p99_wait_ms,throughput_per_s";
+
+    pub fn messages_per_cs_entry(&self) -> f64 {
+        if self.cs_entries == 0 {
+            0.0
+        } else {
+            self.messages as f64 / self.cs_entries as f64
+        }
+    }
+    pub fn mean_wait_ms(&self) -> f64 {
+        if self.waits_ms.is_empty() {
+            0.0
+        } else {
+            self.waits_ms.iter().sum::<u64>() as f64 / self.waits_ms.len() as f64
+        }
+    }
+    /// Nearest-rank percentile of the waiting times
+    pub fn wait_percentile_ms(&self, percentile: f64) -> u64 {
+        let mut waits = self.waits_ms.clone();
+        waits.sort_unstable();
+        if waits.is_empty() {
+            return 0;
+        }
+        let rank = ((percentile / 100.0) * waits.len() as f64).ceil() as usize;
+        waits[rank.clamp(1, waits.len()) - 1]
+    }
+    /// Entries to the critical section per second of the virtual time
+    pub fn throughput_per_s(&self) -> f64 {
+        if self.makespan_ms == 0 {
+            0.0
+        } else {
+            self.cs_entries as f64 * 1000.0 / self.makespan_ms as f64
+        }
+    }
+    pub fn csv_row(&self) -> String {
+        format!(
+            "{},{},{},{},{},{:.3},{:.3},{},{},{},{:.3}",
+            self.algorithm.name(),
+            self.nodes,
+            self.request_rate,
+            self.cs_entries,
+            self.messages,
+            self.messages_per_cs_entry(),
+            self.mean_wait_ms(),
+            self.wait_percentile_ms(50.0),
+            self.wait_percentile_ms(95.0),
+            self.wait_percentile_ms(99.0),
+            self.throughput_per_s()
+        )
+    }
+}
+
+/// Runs every combination of the parameters and writes a CSV row per run.
+/// The nodes run the real algorithms on their threads but in the virtual time
+/// (see [`crate::time`]) so that even big networks can be measured in
+/// moments and the same seed gives the same numbers.
+pub fn run(config: &BenchConfig, out: &mut impl Write) -> std::io::Result<()> {
+    writeln!(out, "{}", BenchResult::CSV_HEADER)?;
+    for &algorithm in &config.algorithms {
+        for &nodes in &config.node_counts {
+            for &request_rate in &config.request_rates {
+                let result = simulate(config, algorithm, nodes, request_rate);
+                writeln!(out, "{}", result.csv_row())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Runs a single simulation and gathers its numbers from the trace
+pub fn simulate(
+    config: &BenchConfig,
+    algorithm: Algorithm,
+    nodes: usize,
+    request_rate: f64,
+) -> BenchResult {
+    let mut rng =
+        StdRng::seed_from_u64(config.seed ^ ((nodes as u64) << 32) ^ request_rate.to_bits());
+    let network = (0..nodes)
+        .fold(NetworkBuilder::new(), |builder, idx| {
+            let instructions = (0..config.requests_per_node)
+                .flat_map(|_| {
+                    // exponentially distributed time with the mean of 1/rate seconds
+                    let uniform: f64 = rng.gen_range(f64::EPSILON..1.0);
+                    let idle_ms = (-uniform.ln() * 1000.0 / request_rate).round() as u64;
+                    [
+                        NodeTaskInstruction::Idle { duration: idle_ms },
+                        NodeTaskInstruction::CriticalSection {
+                            duration: config.cs_duration_ms,
+                        },
+                    ]
+                })
+                .collect();
+            builder.node(format!("node{idx}"), instructions)
+        })
+        .algorithm(algorithm)
+        .virtual_time(VirtualTime::new(config.message_delay_ms, rng.gen()))
+        .quiet(true)
+        .start();
+
+    let replay = Replay::new(network.wait());
+    assert!(
+        replay.check_safety().is_empty(),
+        "two nodes were in the critical section at once"
+    );
+    let statistics = replay.statistics();
+    BenchResult {
+        algorithm,
+        nodes,
+        request_rate,
+        cs_entries: statistics.cs_entries(),
+        messages: statistics.messages_sent(),
+        waits_ms: statistics
+            .0
+            .values()
+            .flat_map(|node| node.waits_us.iter().map(|wait| wait / 1000))
+            .collect(),
+        makespan_ms: replay
+            .records()
+            .iter()
+            .filter(|record| record.event == TraceEvent::CsExit)
+            .map(|record| record.at_us / 1000)
+            .max()
+            .unwrap_or(0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(requests_per_node: usize) -> BenchConfig {
+        BenchConfig {
+            requests_per_node,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn node_counts_as_a_list_or_a_range() {
+        assert_eq!(parse_node_counts("2, 4,8"), Ok(vec![2, 4, 8]));
+        assert_eq!(parse_node_counts("2..=5"), Ok(vec![2, 3, 4, 5]));
+        assert!(parse_node_counts("0,2").is_err());
+        assert!(parse_node_counts("5..=2").is_err());
+        assert!(parse_node_counts("two").is_err());
+    }
+
+    #[test]
+    fn rates_have_to_be_positive() {
+        assert_eq!(parse_rates("0.5, 10"), Ok(vec![0.5, 10.0]));
+        assert!(parse_rates("1,0").is_err());
+        assert!(parse_rates("-1").is_err());
+        assert!(parse_rates("fast").is_err());
+    }
+
+    #[test]
+    fn nearest_rank_percentiles() {
+        let result = BenchResult {
+            algorithm: Algorithm::RicartAgrawala,
+            nodes: 2,
+            request_rate: 1.0,
+            cs_entries: 4,
+            messages: 8,
+            waits_ms: vec![40, 10, 30, 20],
+            makespan_ms: 2000,
+        };
+        assert_eq!(result.wait_percentile_ms(0.0), 10);
+        assert_eq!(result.wait_percentile_ms(50.0), 20);
+        assert_eq!(result.wait_percentile_ms(99.0), 40);
+        assert_eq!(result.mean_wait_ms(), 25.0);
+        assert_eq!(result.messages_per_cs_entry(), 2.0);
+        assert_eq!(result.throughput_per_s(), 2.0);
+    }
+
+    #[test]
+    fn ricart_agrawala_takes_a_request_and_a_reply_per_other_node() {
+        let config = config(5);
+        let result = simulate(&config, Algorithm::RicartAgrawala, 4, 10.0);
+        assert_eq!(result.cs_entries, 4 * 5);
+        assert_eq!(result.waits_ms.len(), result.cs_entries);
+        assert_eq!(result.messages, result.cs_entries * 2 * 3);
+    }
+
+    #[test]
+    fn suzuki_kasami_takes_at_most_a_broadcast_and_the_token() {
+        let config = config(5);
+        let result = simulate(&config, Algorithm::SuzukiKasami, 4, 10.0);
+        assert_eq!(result.cs_entries, 4 * 5);
+        assert!(result.messages <= result.cs_entries * 4);
+    }
+
+    #[test]
+    fn the_same_seed_gives_the_same_numbers() {
+        let config = config(3);
+        let first = simulate(&config, Algorithm::SuzukiKasami, 8, 1.0);
+        let second = simulate(&config, Algorithm::SuzukiKasami, 8, 1.0);
+        assert_eq!(first.csv_row(), second.csv_row());
+    }
+}
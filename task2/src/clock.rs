@@ -0,0 +1,85 @@
+use std::collections::BTreeMap;
+
+/// A vector clock indexed with the identifiers of the nodes (as used in the
+/// traces). Entries missing from the map are equal to 0.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct VectorClock(pub BTreeMap<String, u128>);
+
+impl VectorClock {
+    pub fn get(&self, node: &str) -> u128 {
+        self.0.get(node).copied().unwrap_or(0)
+    }
+    /// Marks a new event at the node
+    pub fn tick(&mut self, node: &str) {
+        *self.0.entry(node.to_string()).or_insert(0) += 1;
+    }
+    /// Takes in the knowledge carried by a received clock, the receive event
+    /// itself still has to be marked with [`VectorClock::tick`].
+    pub fn merge(&mut self, other: &VectorClock) {
+        for (node, value) in &other.0 {
+            let own = self.0.entry(node.clone()).or_insert(0);
+            *own = (*own).max(*value);
+        }
+    }
+    /// Tells whether the event stamped with `self` happened before the event
+    /// stamped with `other`
+    pub fn happened_before(&self, other: &VectorClock) -> bool {
+        self != other && self.0.iter().all(|(node, value)| *value <= other.get(node))
+    }
+    pub fn is_concurrent_with(&self, other: &VectorClock) -> bool {
+        self != other && !self.happened_before(other) && !other.happened_before(self)
+    }
+}
+
+impl std::fmt::Display for VectorClock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[")?;
+        for (idx, (node, value)) in self.0.iter().enumerate() {
+            if idx > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{node}: {value}")?;
+        }
+        write!(f, "]")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clock(entries: &[(&str, u128)]) -> VectorClock {
+        VectorClock(
+            entries
+                .iter()
+                .map(|(node, value)| (node.to_string(), *value))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn tick_and_merge() {
+        let mut a = VectorClock::default();
+        a.tick("A");
+        a.tick("A");
+        let mut b = clock(&[("A", 1), ("B", 3)]);
+        b.merge(&a);
+        assert_eq!(b, clock(&[("A", 2), ("B", 3)]));
+        assert_eq!(b.get("C"), 0);
+        assert_eq!(b.to_string(), "[A: 2, B: 3]");
+    }
+
+    #[test]
+    fn happened_before_and_concurrent() {
+        let send = clock(&[("A", 2)]);
+        let receive = clock(&[("A", 2), ("B", 1)]);
+        let other = clock(&[("A", 1), ("B", 2)]);
+        assert!(send.happened_before(&receive));
+        assert!(!receive.happened_before(&send));
+        assert!(!send.happened_before(&send));
+        assert!(receive.is_concurrent_with(&other));
+        assert!(!send.is_concurrent_with(&receive));
+        assert!(!send.is_concurrent_with(&send));
+    }
+}
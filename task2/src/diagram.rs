@@ -0,0 +1,218 @@
+use std::{collections::HashMap, fmt::Write};
+
+use crate::{
+    replay::Replay,
+    trace::{MessageKind, TraceEvent},
+};
+
+/// Horizontal distance between two consecutive events
+const STEP: usize = 28;
+/// Vertical distance between the lines of two nodes
+const ROW_HEIGHT: usize = 70;
+/// Space on the left reserved for the names of the nodes
+const LEFT_MARGIN: usize = 140;
+const TOP_MARGIN: usize = 60;
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn color_of(kind: MessageKind) -> &'static str {
+    match kind {
+        MessageKind::Request => "#1f77b4",
+        MessageKind::Approval => "#2ca02c",
+        MessageKind::TokenRequest => "#9467bd",
+        MessageKind::Token => "#d62728",
+    }
+}
+
+/// Draws a space-time (Lamport) diagram of the replayed run as an SVG image.
+/// Every node gets its own horizontal line, time flows from the left to the
+/// right following the order of the events in the trace. Messages are drawn
+/// as arrows between the lines and stays in the critical section as bars.
+/// Hovering over an event shows its description and clocks.
+pub fn render_svg(replay: &Replay) -> String {
+    let records = replay.records();
+    let nodes = replay.nodes();
+    let rows = nodes
+        .iter()
+        .enumerate()
+        .map(|(idx, node)| (*node, TOP_MARGIN + idx * ROW_HEIGHT))
+        .collect::<HashMap<_, _>>();
+    let x_of = |idx: usize| LEFT_MARGIN + STEP + idx * STEP;
+    let y_of = |idx: usize| rows[records[idx].node.as_str()];
+
+    let width = x_of(records.len()) + STEP;
+    let height = TOP_MARGIN + nodes.len() * ROW_HEIGHT;
+
+    // writing into a String cannot fail, hence the ignored results
+    let mut svg = String::new();
+    let _ = writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" viewBox="0 0 {width} {height}" font-family="monospace" font-size="12">"#
+    );
+    let _ = writeln!(svg, "<defs>");
+    for kind in MessageKind::ALL {
+        let _ = writeln!(
+            svg,
+            r#"<marker id="arrow-{kind:?}" viewBox="0 0 10 10" refX="10" refY="5" markerWidth="6" markerHeight="6" orient="auto-start-reverse"><path d="M 0 0 L 10 5 L 0 10 z" fill="{}"/></marker>"#,
+            color_of(kind)
+        );
+    }
+    let _ = writeln!(svg, "</defs>");
+    let _ = writeln!(
+        svg,
+        r#"<rect width="{width}" height="{height}" fill="white"/>"#
+    );
+
+    // legend
+    let _ = write!(
+        svg,
+        r##"<text x="10" y="20">critical section: <tspan fill="#ff7f0e">&#9632;</tspan>"##
+    );
+    for kind in MessageKind::ALL {
+        let _ = write!(
+            svg,
+            r#"  {kind:?}: <tspan fill="{}">&#8594;</tspan>"#,
+            color_of(kind)
+        );
+    }
+    let _ = writeln!(svg, "</text>");
+
+    // a line per node
+    for node in &nodes {
+        let y = rows[node];
+        let _ = writeln!(
+            svg,
+            r#"<text x="10" y="{}">{}</text>"#,
+            y + 4,
+            escape(replay.display_name(node))
+        );
+        let _ = writeln!(
+            svg,
+            r##"<line x1="{LEFT_MARGIN}" y1="{y}" x2="{}" y2="{y}" stroke="#444" stroke-width="1"/>"##,
+            width - STEP / 2
+        );
+    }
+
+    // stays in the critical section
+    for (enter, exit) in replay.critical_sections() {
+        let x1 = x_of(enter);
+        let x2 = exit.map(x_of).unwrap_or(width - STEP / 2);
+        let _ = writeln!(
+            svg,
+            r##"<rect x="{x1}" y="{}" width="{}" height="12" fill="#ff7f0e" fill-opacity="0.6"/>"##,
+            y_of(enter) - 6,
+            x2 - x1
+        );
+    }
+
+    // messages
+    for (sent, received) in replay.messages() {
+        let kind = records[sent]
+            .event
+            .sent_message()
+            .map(|(_, kind)| kind)
+            .unwrap_or(MessageKind::Request);
+        let _ = writeln!(
+            svg,
+            r#"<line x1="{}" y1="{}" x2="{}" y2="{}" stroke="{}" stroke-width="1.2" marker-end="url(#arrow-{kind:?})"/>"#,
+            x_of(sent),
+            y_of(sent),
+            x_of(received),
+            y_of(received),
+            color_of(kind)
+        );
+    }
+
+    // events
+    for (idx, record) in records.iter().enumerate() {
+        let fill = match record.event {
+            TraceEvent::CsEnter | TraceEvent::CsExit => "#d62728",
+            _ => "#000",
+        };
+        let mut title = format!(
+            "#{} {} {}\nclock: {}",
+            record.seq,
+            replay.display_name(&record.node),
+            replay.describe(&record.event),
+            record.clock
+        );
+        if let Some(vector) = &record.vector {
+            title.push_str(&format!("\nvector: {vector}"));
+        }
+        let _ = writeln!(
+            svg,
+            r#"<circle cx="{}" cy="{}" r="3.5" fill="{fill}"><title>{}</title></circle>"#,
+            x_of(idx),
+            y_of(idx),
+            escape(&title)
+        );
+        if let Some(vector) = &record.vector {
+            let _ = writeln!(
+                svg,
+                r##"<text x="{}" y="{}" font-size="8" fill="#666" text-anchor="middle">{}</text>"##,
+                x_of(idx),
+                y_of(idx) + 18 + (idx % 2) * 10,
+                escape(
+                    &nodes
+                        .iter()
+                        .map(|node| vector.get(node).to_string())
+                        .collect::<Vec<_>>()
+                        .join(",")
+                )
+            );
+        }
+    }
+
+    let _ = writeln!(svg, "</svg>");
+    svg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::TraceRecord;
+
+    #[test]
+    fn arrows_bars_and_escaped_names() {
+        let events = [
+            ("<A>", TraceEvent::CsEnter),
+            (
+                "<A>",
+                TraceEvent::ApprovalSent {
+                    to: "ThreadId(3)".to_string(),
+                },
+            ),
+            ("<A>", TraceEvent::CsExit),
+            (
+                "B",
+                TraceEvent::ApprovalReceived {
+                    from: "ThreadId(2)".to_string(),
+                },
+            ),
+        ];
+        let records = events
+            .into_iter()
+            .enumerate()
+            .map(|(seq, (name, event))| TraceRecord {
+                seq: seq as u64,
+                at_us: 0,
+                node: format!("ThreadId({})", if name == "B" { 3 } else { 2 }),
+                name: Some(name.to_string()),
+                clock: seq as u128,
+                vector: None,
+                event,
+            })
+            .collect();
+        let svg = render_svg(&Replay::new(records));
+        assert!(svg.starts_with("<svg") && svg.trim_end().ends_with("</svg>"));
+        assert_eq!(svg.matches("marker-end=").count(), 1);
+        assert_eq!(svg.matches("fill-opacity").count(), 1);
+        assert!(svg.contains("&lt;A&gt;"));
+        assert!(!svg.contains("<A>"));
+    }
+}
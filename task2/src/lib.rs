@@ -0,0 +1,38 @@
+//! Simulation of distributed mutual exclusion where every node runs on its own
+//! thread and communicates with the others only through channels.
+//!
+//! A network is described with a [`NetworkBuilder`] and observed through the
+//! [`RunningNetwork`] it starts, which yields a [`trace::TraceRecord`] for
+//! every event at the nodes:
+//! ```no_run
+//! use task2::{Algorithm, NetworkBuilder, NodeTaskInstruction};
+//!
+//! let network = NetworkBuilder::new()
+//!     .algorithm(Algorithm::SuzukiKasami)
+//!     .node("A", vec![NodeTaskInstruction::CriticalSection { duration: 100 }])
+//!     .node("B", vec![
+//!         NodeTaskInstruction::Idle { duration: 50 },
+//!         NodeTaskInstruction::CriticalSection { duration: 100 },
+//!     ])
+//!     .start();
+//!
+//! let replay = task2::replay::Replay::new(network.wait());
+//! assert!(replay.check_safety().is_empty());
+//! ```
+//! The recorded events can be checked and summarized with
+//! [`replay::Replay`], drawn with [`diagram::render_svg`] and the algorithms
+//! can be compared in virtual time with [`bench`].
+
+pub mod bench;
+pub mod clock;
+pub mod diagram;
+pub mod input;
+pub use input::*;
+pub mod network;
+pub use network::*;
+pub mod node;
+pub use node::*;
+pub mod replay;
+pub mod suzuki_kasami;
+pub mod time;
+pub mod trace;
@@ -1,52 +1,156 @@
-pub mod input;
-pub use input::*;
-pub mod node;
-pub use node::*;
+use task2::{bench, diagram, replay, trace, trace::TraceWriter, Algorithm, NetworkBuilder};
+
+fn replay(path: &str) {
+    let records = trace::read_trace(path).unwrap_or_else(|e| panic!("{e}"));
+    println!("Replaying {} events from {path}", records.len());
+
+    let replay = replay::Replay::new(records);
+    replay.print_timeline();
+    if !replay.report() {
+        std::process::exit(1);
+    }
+}
+
+fn diagram(trace_path: &str, svg_path: &str) {
+    let records = trace::read_trace(trace_path).unwrap_or_else(|e| panic!("{e}"));
+    let svg = diagram::render_svg(&replay::Replay::new(records));
+    std::fs::write(svg_path, svg).expect("failed to write the diagram");
+    println!("Space-time diagram written into: {svg_path}");
+}
+
+fn bench(mut args: Vec<String>) {
+    let mut config = bench::BenchConfig::default();
+    if let Some(algorithm) = take_option(&mut args, "--algorithm") {
+        config.algorithms = vec![algorithm.parse().unwrap_or_else(|e| panic!("{e}"))];
+    }
+    if let Some(nodes) = take_option(&mut args, "--nodes") {
+        config.node_counts = bench::parse_node_counts(&nodes).unwrap_or_else(|e| panic!("{e}"));
+    }
+    if let Some(rates) = take_option(&mut args, "--rates") {
+        config.request_rates = bench::parse_rates(&rates).unwrap_or_else(|e| panic!("{e}"));
+    }
+    if let Some(requests) = take_option(&mut args, "--requests") {
+        config.requests_per_node = requests
+            .parse()
+            .expect("expected a number of requests per node");
+    }
+    if let Some(seed) = take_option(&mut args, "--seed") {
+        config.seed = seed.parse().expect("expected a number as the seed");
+    }
+
+    match take_option(&mut args, "--output") {
+        Some(path) => {
+            let mut file = std::fs::File::create(&path).expect("failed to create the output file");
+            bench::run(&config, &mut file).expect("failed to write the results");
+            println!("Benchmark results written into: {path}");
+        }
+        None => match bench::run(&config, &mut std::io::stdout().lock()) {
+            // nobody reads the rest of the results, e.g. piped into `head`
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {}
+            result => result.expect("failed to print the results"),
+        },
+    }
+}
+
+/// Removes the flag from the arguments, returns whether it was there
+fn take_flag(args: &mut Vec<String>, flag: &str) -> bool {
+    match args.iter().position(|a| a == flag) {
+        Some(idx) => {
+            args.remove(idx);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Removes the option and its value from the arguments, returns the value
+fn take_option(args: &mut Vec<String>, option: &str) -> Option<String> {
+    args.iter().position(|a| a == option).map(|idx| {
+        args.remove(idx);
+        assert!(idx < args.len(), "expected a value after `{option}`");
+        args.remove(idx)
+    })
+}
 
 fn main() {
+    let mut args = std::env::args().skip(1).collect::<Vec<_>>();
+
+    match args.first().map(String::as_str) {
+        Some("replay") => {
+            let path = args
+                .get(1)
+                .expect("expected a path to a trace file after `replay`");
+            return replay(path);
+        }
+        Some("bench") => return bench(args.split_off(1)),
+        Some("diagram") => {
+            let (Some(trace_path), Some(svg_path)) = (args.get(1), args.get(2)) else {
+                panic!("expected paths to a trace file and to the output svg after `diagram`");
+            };
+            return diagram(trace_path, svg_path);
+        }
+        _ => {}
+    }
+
+    let trace_path = take_option(&mut args, "--trace");
+    let vector_clocks = take_flag(&mut args, "--vector-clocks");
+    let algorithm = take_option(&mut args, "--algorithm")
+        .map(|name| name.parse::<Algorithm>().unwrap_or_else(|e| panic!("{e}")))
+        .unwrap_or_default();
+
     println!("Starting system simulation...");
 
-    if let Some(filename) = std::env::args().nth(1) {
-        let file = std::fs::File::open(&filename).expect("failed to open the file");
+    // the random nodes never finish
+    let finite = !args.is_empty();
+    let network = if let Some(filename) = args.first() {
+        let file = std::fs::File::open(filename).expect("failed to open the file");
         println!("Reading file: {filename}");
 
-        let instructions: input::Task2StudyCaseInstructions =
+        let instructions: task2::Task2StudyCaseInstructions =
             serde_json::from_reader(file).unwrap();
 
-        let mut runners = Vec::<NodeRunner>::new();
-
-        for (node_name, node_instructions) in instructions.0 {
-            let nr = NodeRunner::new(Node::new(node_name, node_instructions.0));
-            for other in &runners {
-                let own = nr.give_registration_data();
-                other.register_new_connection(own.0, own.1);
-                let other = other.give_registration_data();
-                nr.register_new_connection(other.0, other.1);
-            }
-            runners.push(nr);
-        }
-        for nr in runners {
-            nr.start();
-        }
+        NetworkBuilder::from_instructions(instructions)
     } else {
         println!("No filename provided as an input, proceeding to run a simulation with 10 nodes and rondomized task durations");
-        let mut runners = Vec::<NodeRunner>::new();
-
-        for _ in 0..9 {
-            let nr = NodeRunner::new(Node::default());
-            for other in &runners {
-                let own = nr.give_registration_data();
-                other.register_new_connection(own.0, own.1);
-                let other = other.give_registration_data();
-                nr.register_new_connection(other.0, other.1);
-            }
-            runners.push(nr);
+        (0..9).fold(NetworkBuilder::new(), |network, _| network.random_node())
+    };
+
+    let mut writer = trace_path.map(|path| {
+        println!("Writing the trace into: {path}");
+        TraceWriter::create(&path).expect("failed to create the trace file")
+    });
+
+    println!("Running the {algorithm:?} algorithm");
+
+    let network = network
+        .algorithm(algorithm)
+        .vector_clocks(vector_clocks)
+        .start();
+
+    let mut write = |record: &trace::TraceRecord| {
+        if let Some(writer) = &mut writer {
+            writer
+                .write(record)
+                .expect("failed to write into the trace file");
         }
-        for nr in runners {
-            nr.start();
+    };
+
+    if !finite {
+        // nothing is kept but the nodes in the critical section, the whole
+        // run can be replayed from the trace once it is stopped
+        let mut monitor = replay::SafetyMonitor::default();
+        for record in network {
+            write(&record);
+            for violation in monitor.observe(record) {
+                println!("Safety check FAILED: {violation}");
+            }
         }
+        return;
     }
 
     // make sure we do not exit too early
-    loop {}
+    let records = network.inspect(|record| write(record)).collect::<Vec<_>>();
+
+    println!("All nodes finished their tasks");
+    replay::Replay::new(records).report();
 }
@@ -0,0 +1,292 @@
+use std::sync::{mpsc::Receiver, Arc};
+
+use crate::{
+    input::{NodeTaskInstruction, Task2StudyCaseInstructions},
+    node::{Algorithm, Node, NodeRunner},
+    time::VirtualTime,
+    trace::{Sequence, TraceEvent, TraceRecord},
+};
+
+/// Describes a network of fully connected nodes before it is started. Every
+/// node runs on its own thread (see [`NodeRunner`]) once the network starts.
+#[derive(Default)]
+pub struct NetworkBuilder {
+    nodes: Vec<Node>,
+    algorithm: Algorithm,
+    vector_clocks: bool,
+    /// The real time if none
+    time: Option<Arc<VirtualTime>>,
+    quiet: bool,
+}
+
+impl NetworkBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A network with a node per entry of the study case. The nodes are added
+    /// in the order of their names so that the same node always gets the token
+    /// in the token-based algorithms.
+    pub fn from_instructions(instructions: Task2StudyCaseInstructions) -> Self {
+        let mut instructions = instructions.0.into_iter().collect::<Vec<_>>();
+        instructions.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        instructions
+            .into_iter()
+            .fold(Self::new(), |builder, (name, node_instructions)| {
+                builder.node(name, node_instructions.0)
+            })
+    }
+
+    /// Adds a node which executes the given tasks and finishes
+    pub fn node(mut self, name: impl Into<String>, instructions: Vec<NodeTaskInstruction>) -> Self {
+        self.nodes.push(Node::new(name.into(), instructions));
+        self
+    }
+
+    /// Adds a node which keeps executing tasks of random durations forever
+    pub fn random_node(mut self) -> Self {
+        self.nodes.push(Node::default());
+        self
+    }
+
+    pub fn algorithm(mut self, algorithm: Algorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// Makes the nodes keep vector clocks and attach them to the messages and
+    /// the trace records
+    pub fn vector_clocks(mut self, enabled: bool) -> Self {
+        self.vector_clocks = enabled;
+        self
+    }
+
+    /// Runs the nodes in the given virtual time instead of the real one, see
+    /// [`crate::time`]
+    pub fn virtual_time(mut self, time: Arc<VirtualTime>) -> Self {
+        self.time = Some(time);
+        self
+    }
+
+    /// Stops the nodes from printing their progress, the events still get
+    /// recorded
+    pub fn quiet(mut self, quiet: bool) -> Self {
+        self.quiet = quiet;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Spawns the nodes, connects every node with every other node and starts
+    /// the simulation. The first node added holds the token initially.
+    pub fn start(self) -> RunningNetwork {
+        let (trace_tx, trace_rx) = std::sync::mpsc::channel();
+        // every network numbers its own records from zero
+        let sequence = Sequence::default();
+        let nodes_count = self.nodes.len();
+        let mut runners = Vec::<NodeRunner>::new();
+
+        for (idx, node) in self.nodes.into_iter().enumerate() {
+            let mut node = node
+                .with_trace(trace_tx.clone(), sequence.clone())
+                .with_algorithm(self.algorithm);
+            if idx == 0 {
+                node = node.with_token();
+            }
+            if self.vector_clocks {
+                node = node.with_vector_clocks();
+            }
+            if let Some(time) = &self.time {
+                node = node.with_time(time.join());
+            }
+            if self.quiet {
+                node = node.quiet();
+            }
+
+            let nr = NodeRunner::new(node);
+            for other in &runners {
+                let own = nr.give_registration_data();
+                other.register_new_connection(own.0, own.1);
+                let other = other.give_registration_data();
+                nr.register_new_connection(other.0, other.1);
+            }
+            runners.push(nr);
+        }
+        for nr in &runners {
+            nr.start();
+        }
+
+        RunningNetwork {
+            _runners: runners,
+            events: trace_rx,
+            nodes_count,
+            finished: 0,
+        }
+    }
+}
+
+/// A handle to a started network which allows observing the events at the
+/// nodes. Iterating over it yields the [`TraceRecord`]s as they come until
+/// all the nodes finish their tasks (which never happens for the nodes added
+/// with [`NetworkBuilder::random_node`]). The threads of the nodes keep
+/// running (answering the requests of the others) until the process exits.
+pub struct RunningNetwork {
+    /// Kept so that the connections to the nodes outlive the observation
+    _runners: Vec<NodeRunner>,
+    events: Receiver<TraceRecord>,
+    nodes_count: usize,
+    finished: usize,
+}
+
+impl RunningNetwork {
+    /// Whether every node has finished its tasks
+    pub fn is_finished(&self) -> bool {
+        self.finished == self.nodes_count
+    }
+
+    /// Waits until all the nodes finish and returns everything that happened
+    pub fn wait(self) -> Vec<TraceRecord> {
+        self.collect()
+    }
+}
+
+impl Iterator for RunningNetwork {
+    type Item = TraceRecord;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.is_finished() {
+            return None;
+        }
+        let record = self.events.recv().ok()?;
+        if record.event == TraceEvent::Finished {
+            self.finished += 1;
+        }
+        Some(record)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replay::Replay;
+
+    /// A holds the token, B asks for it while A is in the critical section
+    fn handover() -> NetworkBuilder {
+        NetworkBuilder::new()
+            .algorithm(Algorithm::SuzukiKasami)
+            .node(
+                "A",
+                vec![NodeTaskInstruction::CriticalSection { duration: 10 }],
+            )
+            .node(
+                "B",
+                vec![
+                    NodeTaskInstruction::Idle { duration: 5 },
+                    NodeTaskInstruction::CriticalSection { duration: 10 },
+                ],
+            )
+            .virtual_time(VirtualTime::new((1, 1), 0))
+            .quiet(true)
+    }
+
+    #[test]
+    fn records_follow_the_events() {
+        // the second network numbers its records from zero again
+        for _ in 0..2 {
+            let records = handover().start().wait();
+            let label = |name: &str| {
+                records
+                    .iter()
+                    .find(|r| r.name.as_deref() == Some(name))
+                    .unwrap()
+                    .node
+                    .clone()
+            };
+            let (a, b) = (label("A"), label("B"));
+
+            let expected = [
+                ("A", 0, TraceEvent::Initialized),
+                ("B", 0, TraceEvent::Initialized),
+                ("A", 0, TraceEvent::CsRequested),
+                ("A", 0, TraceEvent::CsEnter),
+                ("B", 5, TraceEvent::CsRequested),
+                (
+                    "B",
+                    5,
+                    TraceEvent::TokenRequestSent {
+                        to: a.clone(),
+                        number: 1,
+                    },
+                ),
+                ("A", 10, TraceEvent::CsExit),
+                (
+                    "A",
+                    10,
+                    TraceEvent::TokenRequestReceived {
+                        from: b.clone(),
+                        number: 1,
+                    },
+                ),
+                ("A", 10, TraceEvent::TokenSent { to: b.clone() }),
+                ("A", 10, TraceEvent::Finished),
+                ("B", 11, TraceEvent::TokenReceived { from: a.clone() }),
+                ("B", 11, TraceEvent::CsEnter),
+                ("B", 21, TraceEvent::CsExit),
+                ("B", 21, TraceEvent::Finished),
+            ];
+            let recorded = records
+                .iter()
+                .map(|r| {
+                    (
+                        r.seq,
+                        r.name.as_deref().unwrap(),
+                        r.at_us / 1000,
+                        r.event.clone(),
+                    )
+                })
+                .collect::<Vec<_>>();
+            let expected = expected
+                .into_iter()
+                .zip(0..)
+                .map(|((name, at_ms, event), seq)| (seq, name, at_ms, event))
+                .collect::<Vec<_>>();
+            assert_eq!(recorded, expected);
+        }
+    }
+
+    #[test]
+    fn every_node_finishes_in_real_time() {
+        let network = ["A", "B", "C"]
+            .into_iter()
+            .fold(NetworkBuilder::new(), |builder, name| {
+                builder.node(
+                    name,
+                    vec![
+                        NodeTaskInstruction::Idle { duration: 1 },
+                        NodeTaskInstruction::CriticalSection { duration: 1 },
+                    ],
+                )
+            })
+            .quiet(true)
+            .start();
+        let records = network.wait();
+
+        let mut seqs = records.iter().map(|r| r.seq).collect::<Vec<_>>();
+        seqs.sort_unstable();
+        assert_eq!(seqs, (0..records.len() as u64).collect::<Vec<_>>());
+
+        let replay = Replay::new(records);
+        assert!(replay.check_safety().is_empty());
+        assert!(replay.unserved_nodes().is_empty());
+        // a request and an approval for each of the other two nodes
+        assert_eq!(replay.statistics().cs_entries(), 3);
+        assert_eq!(replay.statistics().messages_sent(), 3 * 2 * 2);
+    }
+}
@@ -1,8 +1,16 @@
 use std::{
+    collections::HashMap,
     sync::mpsc::{Receiver, Sender},
     thread::ThreadId,
 };
 
+use crate::{
+    clock::VectorClock,
+    suzuki_kasami::Token,
+    time::Time,
+    trace::{node_label, Sequence, TraceEvent, TraceRecord},
+};
+
 /// The node as a unit executable on the [`NodeRunner`]. This struct stores
 /// properties of a node which are decided before the simulation even starts.
 #[derive(Default)]
@@ -10,6 +18,54 @@ pub struct Node {
     /// Optional, improves logs
     pub given_name: Option<String>,
     pub instructions: Option<Vec<crate::input::NodeTaskInstruction>>,
+    /// Optional, receives a [`TraceRecord`] for every event at the node
+    pub trace: Option<Sender<TraceRecord>>,
+    /// Numbers the records, shared by all the nodes of the network
+    pub sequence: Sequence,
+    /// Whether the node keeps a vector clock and attaches it to the messages
+    pub vector_clocks: bool,
+    pub algorithm: Algorithm,
+    /// Whether the node starts holding the token, matters only to the
+    /// token-based algorithms
+    pub holds_token: bool,
+    /// Where the node takes the time from, the real time by default
+    pub time: Time,
+    /// Whether the node keeps its progress to itself, it still gets traced
+    pub quiet: bool,
+}
+
+/// The algorithms of mutual exclusion the nodes can run
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    /// Permission-based, the node asks everyone for an approval
+    #[default]
+    RicartAgrawala,
+    /// Token-based, the node asks everyone for the single token
+    SuzukiKasami,
+}
+
+impl Algorithm {
+    /// The name used to select the algorithm in the command line
+    pub fn name(&self) -> &'static str {
+        match self {
+            Algorithm::RicartAgrawala => "ricart-agrawala",
+            Algorithm::SuzukiKasami => "suzuki-kasami",
+        }
+    }
+}
+
+impl std::str::FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ricart-agrawala" => Ok(Algorithm::RicartAgrawala),
+            "suzuki-kasami" => Ok(Algorithm::SuzukiKasami),
+            other => Err(format!(
+                "unknown algorithm `{other}`, expected `ricart-agrawala` or `suzuki-kasami`"
+            )),
+        }
+    }
 }
 
 impl Node {
@@ -17,6 +73,70 @@ impl Node {
         Self {
             given_name: Some(given_name),
             instructions: Some(instructions),
+            trace: None,
+            sequence: Sequence::default(),
+            vector_clocks: false,
+            algorithm: Algorithm::default(),
+            holds_token: false,
+            time: Time::default(),
+            quiet: false,
+        }
+    }
+    pub fn with_trace(mut self, trace: Sender<TraceRecord>, sequence: Sequence) -> Self {
+        self.trace = Some(trace);
+        self.sequence = sequence;
+        self
+    }
+    pub fn with_vector_clocks(mut self) -> Self {
+        self.vector_clocks = true;
+        self
+    }
+    pub fn with_algorithm(mut self, algorithm: Algorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+    /// Makes the node the initial holder of the token
+    pub fn with_token(mut self) -> Self {
+        self.holds_token = true;
+        self
+    }
+    pub fn with_time(mut self, time: Time) -> Self {
+        self.time = time;
+        self
+    }
+    /// Stops the node from printing its progress
+    pub fn quiet(mut self) -> Self {
+        self.quiet = true;
+        self
+    }
+
+    pub(crate) fn log(&self, message: std::fmt::Arguments) {
+        if !self.quiet {
+            println!("{message}");
+        }
+    }
+
+    /// The name of the node for the logs, if it has one
+    pub(crate) fn name_suffix(&self) -> String {
+        if let Some(name) = &self.given_name {
+            format!(" (named: {name}) ")
+        } else {
+            String::new()
+        }
+    }
+
+    pub(crate) fn record(&self, current_node: &NodeLocalData, event: TraceEvent) {
+        if let Some(trace) = &self.trace {
+            // nobody might be listening anymore, which is not a reason to stop
+            let _ = trace.send(TraceRecord::new(
+                self.sequence.next(),
+                current_node.node_id,
+                self.given_name.clone(),
+                self.time.now().as_micros() as u64,
+                current_node.clock,
+                current_node.vector_clock.clone(),
+                event,
+            ));
         }
     }
 
@@ -31,16 +151,12 @@ impl Node {
                         connection: node_tx,
                     });
 
-                    println!(
+                    self.log(format_args!(
                         "Node {:?}{}is now aware of node {:?} being in the network",
                         current_node.node_id,
-                        if let Some(name) = &self.given_name {
-                            format!(" (named: {name}) ")
-                        } else {
-                            String::new()
-                        },
+                        self.name_suffix(),
                         node_id
-                    );
+                    ));
                 }
                 SystemMsg::Start => break, //starting
                 _ => {
@@ -48,47 +164,48 @@ impl Node {
                 }
             };
         }
-        println!(
+        self.log(format_args!(
             "Node {:?}{} finished initialization",
             current_node.node_id,
-            if let Some(name) = &self.given_name {
-                format!(" (named: {name}) ")
-            } else {
-                String::new()
-            }
-        );
+            self.name_suffix()
+        ));
     }
-    fn is_done(&self) -> bool {
+    pub(crate) fn is_done(&self) -> bool {
         if let Some(list) = &self.instructions {
             list.is_empty()
         } else {
             false
         }
     }
-    fn execute_idle_task(&mut self) {
+    /// Takes the next idle task off the list and tells how long it takes
+    pub(crate) fn take_idle_task(&mut self) -> std::time::Duration {
         if let Some(list) = &mut self.instructions {
             if let Some((idx, duration)) = list
                 .iter()
                 .enumerate()
                 .filter_map(|(idx, instruction)| match instruction {
-                    crate::NodeTaskInstruction::Idle { duration } => Some((idx, duration)),
+                    crate::NodeTaskInstruction::Idle { duration } => Some((idx, *duration)),
                     _ => None,
                 })
                 .nth(0)
             {
-                std::thread::sleep(std::time::Duration::from_millis(*duration));
                 list.remove(idx);
+                std::time::Duration::from_millis(duration)
+            } else {
+                std::time::Duration::ZERO
             }
         } else {
             use rand::Rng;
 
             let mut rng = rand::thread_rng();
-            std::thread::sleep(std::time::Duration::from_millis(
-                1000 + rng.gen_range(0..30000),
-            ));
+            std::time::Duration::from_millis(1000 + rng.gen_range(0..30000))
         }
     }
-    fn execute_in_critical_section(&mut self) {
+    fn execute_idle_task(&mut self) {
+        let duration = self.take_idle_task();
+        self.time.sleep(duration);
+    }
+    pub(crate) fn execute_in_critical_section(&mut self) {
         if let Some(list) = &mut self.instructions {
             if let Some((idx, duration)) = list
                 .iter()
@@ -101,14 +218,14 @@ impl Node {
                 })
                 .nth(0)
             {
-                std::thread::sleep(std::time::Duration::from_millis(*duration));
+                self.time.sleep(std::time::Duration::from_millis(*duration));
                 list.remove(idx);
             }
         } else {
             use rand::Rng;
 
             let mut rng = rand::thread_rng();
-            std::thread::sleep(std::time::Duration::from_millis(
+            self.time.sleep(std::time::Duration::from_millis(
                 1000 + rng.gen_range(0..30000),
             ));
         }
@@ -116,11 +233,28 @@ impl Node {
 
     // ------ FOR THE ALGORITHM IMPLEMENTATION LOOK HERE BELOW -------
 
-    /// The "main" of every node where the implementation of the Ricart-Agrawal algorithm is implemented
-    pub fn execute(mut self, mut current_node: NodeLocalData) -> () {
+    /// The "main" of every node, runs the selected algorithm once the node is
+    /// ready
+    pub fn execute(self, mut current_node: NodeLocalData) {
         // Initialize the node and wait for the main thread to signal the start
         self.initialize(&mut current_node);
+        self.time.start();
+        if self.vector_clocks {
+            current_node.vector_clock = Some(VectorClock::default());
+        }
+        if self.holds_token {
+            current_node.token = Some(Token::default());
+        }
+        self.record(&current_node, TraceEvent::Initialized);
+
+        match self.algorithm {
+            Algorithm::RicartAgrawala => self.execute_ricart_agrawala(current_node),
+            Algorithm::SuzukiKasami => self.execute_suzuki_kasami(current_node),
+        }
+    }
 
+    /// The implementation of the Ricart-Agrawal algorithm
+    fn execute_ricart_agrawala(mut self, mut current_node: NodeLocalData) {
         while !self.is_done() {
             // Start with whatever we have to do that we can do alone
             self.execute_idle_task();
@@ -130,37 +264,58 @@ impl Node {
             // checking if we can untill we get the access.
 
             let request_timestamp = current_node.clock;
+            current_node.tick_vector();
+            self.record(&current_node, TraceEvent::CsRequested);
 
             // Broadcast the request:
+            current_node.tick_vector();
             current_node.connected_to.iter().for_each(|other| {
-                other
-                    .connection
-                    .send(SystemMsg::CriticalSectionReq {
+                self.record(
+                    &current_node,
+                    TraceEvent::RequestSent {
+                        to: node_label(other.node_id),
+                        timestamp: request_timestamp,
+                    },
+                );
+                self.time.send(
+                    other,
+                    SystemMsg::CriticalSectionReq {
                         node_id: current_node.node_id,
                         timestamp: request_timestamp,
-                    })
-                    .unwrap();
+                        vector: current_node.vector_clock.clone(),
+                    },
+                );
             });
             current_node.clock += 1;
 
             // Wait for approvals:
             'cs: loop {
-                let msg = current_node.network_connection.recv().unwrap();
+                let Some(msg) = self.time.recv(&current_node.network_connection) else {
+                    return;
+                };
                 current_node.clock += 1;
 
                 match msg {
-                    SystemMsg::CriticalSectionReq { node_id, timestamp } => {
+                    SystemMsg::CriticalSectionReq {
+                        node_id,
+                        timestamp,
+                        vector,
+                    } => {
+                        current_node.receive_vector(&vector);
+                        self.record(
+                            &current_node,
+                            TraceEvent::RequestReceived {
+                                from: node_label(node_id),
+                                timestamp,
+                            },
+                        );
                         //log msg:
-                        println!(
+                        self.log(format_args!(
                             "Node {:?} asked the node {:?}{} for an access to the critical section",
                             node_id,
                             current_node.node_id,
-                            if let Some(name) = &self.given_name {
-                                format!(" (named: {name}) ")
-                            } else {
-                                String::new()
-                            }
-                        );
+                            self.name_suffix()
+                        ));
 
                         // NOTE:
                         // a very hacky way of checking if one id is somehow
@@ -176,32 +331,47 @@ impl Node {
                             }
 
                             //grant the access
+                            current_node.tick_vector();
                             let receiving_node = current_node
                                 .connected_to
                                 .iter()
                                 .find(|other| other.node_id == node_id).expect("got a message from an unkown source, no channel to the source registered at the node");
 
-                            receiving_node
-                                .connection
-                                .send(SystemMsg::AccessApproved {
+                            self.record(
+                                &current_node,
+                                TraceEvent::ApprovalSent {
+                                    to: node_label(node_id),
+                                },
+                            );
+                            self.time.send(
+                                receiving_node,
+                                SystemMsg::AccessApproved {
                                     node_id: current_node.node_id,
                                     timestamp: current_node.clock,
-                                })
-                                .unwrap();
+                                    vector: current_node.vector_clock.clone(),
+                                },
+                            );
 
-                            println!( //log msg
+                            self.log(format_args!( //log msg
                                 "Node {:?}{} granted to node {:?} the access to the critical section",
-                                current_node.node_id, if let Some(name) = &self.given_name {
-                                    format!(" (named: {name}) ")
-                                } else {
-                                    String::new()
-                                }, node_id
-                            );
+                                current_node.node_id, self.name_suffix(), node_id
+                            ));
                         } else {
                             current_node.deffered_reqs.push(node_id);
+                            self.record(
+                                &current_node,
+                                TraceEvent::Deferred {
+                                    from: node_label(node_id),
+                                },
+                            );
                         }
                     }
-                    SystemMsg::AccessApproved { node_id, timestamp } => {
+                    SystemMsg::AccessApproved {
+                        node_id,
+                        timestamp,
+                        vector,
+                    } => {
+                        current_node.receive_vector(&vector);
                         // let's synchronize clock first
                         if current_node.clock < timestamp {
                             current_node.clock = timestamp + 1;
@@ -215,6 +385,12 @@ impl Node {
                             .expect("got an approval from a node unkown to the current node");
 
                         approving_node.is_request_accepted = true;
+                        self.record(
+                            &current_node,
+                            TraceEvent::ApprovalReceived {
+                                from: node_label(node_id),
+                            },
+                        );
                     }
                     _ => {
                         // ignore other types of the messages after the initialization
@@ -227,28 +403,24 @@ impl Node {
                     .iter()
                     .all(|other| other.is_request_accepted)
                 {
-                    println!(
+                    self.log(format_args!(
                         "\x1b[93mNode {:?}{} proceeds into the critical section\x1b[0m",
                         current_node.node_id,
-                        if let Some(name) = &self.given_name {
-                            format!(" (named: {name}) ")
-                        } else {
-                            String::new()
-                        }
-                    );
+                        self.name_suffix()
+                    ));
+                    current_node.tick_vector();
+                    self.record(&current_node, TraceEvent::CsEnter);
                     self.execute_in_critical_section();
                     current_node.clock += 1;
+                    current_node.tick_vector();
+                    self.record(&current_node, TraceEvent::CsExit);
 
-                    println!(
+                    self.log(format_args!(
                         //log msg
                         "\x1b[93mNode {:?}{} exits the critical section\x1b[0m",
                         current_node.node_id,
-                        if let Some(name) = &self.given_name {
-                            format!(" (named: {name}) ")
-                        } else {
-                            String::new()
-                        }
-                    );
+                        self.name_suffix()
+                    ));
 
                     // Clean-up the flags in info on connected nodes
                     current_node
@@ -257,18 +429,26 @@ impl Node {
                         .for_each(|other| other.is_request_accepted = false);
 
                     // Let others know that the CS is now free
+                    current_node.tick_vector();
                     current_node
                         .connected_to
                         .iter()
                         .filter(|other| current_node.deffered_reqs.contains(&other.node_id))
                         .for_each(|other| {
-                            other
-                                .connection
-                                .send(SystemMsg::AccessApproved {
+                            self.record(
+                                &current_node,
+                                TraceEvent::ApprovalSent {
+                                    to: node_label(other.node_id),
+                                },
+                            );
+                            self.time.send(
+                                other,
+                                SystemMsg::AccessApproved {
                                     node_id: current_node.node_id,
                                     timestamp: current_node.clock,
-                                })
-                                .unwrap();
+                                    vector: current_node.vector_clock.clone(),
+                                },
+                            );
                         });
                     current_node.deffered_reqs.clear();
 
@@ -277,21 +457,64 @@ impl Node {
             }
         }
 
-        println!(
+        self.log(format_args!(
             //log msg
             "\x1b[93mNode {:?}{} finished all its tasks\x1b[0m",
             current_node.node_id,
-            if let Some(name) = &self.given_name {
-                format!(" (named: {name}) ")
-            } else {
-                String::new()
-            }
-        );
+            self.name_suffix()
+        ));
+        current_node.tick_vector();
+        self.record(&current_node, TraceEvent::Finished);
 
         // All the tasks are finished but other nodes may be still holding the channel senders to this node.
         // If the receiver is dropped with the node the application will crash as its error handling is simplified.
-        // A very hacky way to solve that is to let the thread keep running until we kill the whole application.
-        loop {}
+        // The thread keeps running until we kill the whole application and as we will never want the critical
+        // section again, every request can be approved right away. Otherwise others would wait for us forever.
+        loop {
+            let Some(msg) = self.time.recv(&current_node.network_connection) else {
+                return;
+            };
+            current_node.clock += 1;
+
+            if let SystemMsg::CriticalSectionReq {
+                node_id,
+                timestamp,
+                vector,
+            } = msg
+            {
+                if current_node.clock <= timestamp {
+                    current_node.clock = timestamp + 1;
+                }
+                current_node.receive_vector(&vector);
+                self.record(
+                    &current_node,
+                    TraceEvent::RequestReceived {
+                        from: node_label(node_id),
+                        timestamp,
+                    },
+                );
+
+                current_node.tick_vector();
+                self.record(
+                    &current_node,
+                    TraceEvent::ApprovalSent {
+                        to: node_label(node_id),
+                    },
+                );
+                self.time.send(
+current_node
+                    .connected_to
+                    .iter()
+                    .find(|other| other.node_id == node_id)
+                    .expect("got a message from an unkown source, no channel to the source registered at the node"),
+SystemMsg::AccessApproved {
+                        node_id: current_node.node_id,
+                        timestamp: current_node.clock,
+                        vector: current_node.vector_clock.clone(),
+                    },
+);
+            }
+        }
     }
 
     // ------ FOR THE ALGORITHM IMPLEMENTATION LOOK HERE ABOVE -------
@@ -306,6 +529,13 @@ pub struct NodeLocalData {
     pub network_connection: Receiver<SystemMsg>,
     pub deffered_reqs: Vec<ThreadId>,
     pub clock: u128,
+    /// Kept only if the [`Node`] was asked to use vector clocks
+    pub vector_clock: Option<VectorClock>,
+    /// The highest request numbers received from every node (RN in the
+    /// Suzuki-Kasami algorithm)
+    pub request_numbers: HashMap<ThreadId, u128>,
+    /// Present at the node that holds the token in the token-based algorithms
+    pub token: Option<Token>,
 }
 
 impl NodeLocalData {
@@ -316,8 +546,26 @@ impl NodeLocalData {
             network_connection,
             deffered_reqs: Vec::new(),
             clock: 0,
+            vector_clock: None,
+            request_numbers: HashMap::new(),
+            token: None,
+        }
+    }
+    /// Marks a new event in the vector clock, if the node keeps one
+    pub fn tick_vector(&mut self) {
+        let own_label = node_label(self.node_id);
+        if let Some(vector) = &mut self.vector_clock {
+            vector.tick(&own_label);
         }
     }
+    /// Takes in the vector clock attached to a received message and marks the
+    /// receive event
+    pub fn receive_vector(&mut self, received: &Option<VectorClock>) {
+        if let (Some(vector), Some(received)) = (&mut self.vector_clock, received) {
+            vector.merge(received);
+        }
+        self.tick_vector();
+    }
 }
 
 /// Information about other nodes as it is tracked locally by a node.
@@ -342,9 +590,37 @@ pub enum SystemMsg {
     /// in the network.
     Start,
     /// Request for an access to the critical section
-    CriticalSectionReq { node_id: ThreadId, timestamp: u128 },
+    CriticalSectionReq {
+        node_id: ThreadId,
+        timestamp: u128,
+        /// Present only if the nodes keep vector clocks
+        vector: Option<VectorClock>,
+    },
     /// Access allowed by the node
-    AccessApproved { node_id: ThreadId, timestamp: u128 },
+    AccessApproved {
+        node_id: ThreadId,
+        timestamp: u128,
+        /// Present only if the nodes keep vector clocks
+        vector: Option<VectorClock>,
+    },
+    /// Request for the token, broadcast in the token-based algorithms
+    TokenReq {
+        node_id: ThreadId,
+        request_number: u128,
+        /// The Lamport clock of the requesting node
+        timestamp: u128,
+        /// Present only if the nodes keep vector clocks
+        vector: Option<VectorClock>,
+    },
+    /// The token handed over to the next node waiting for it
+    TokenPassed {
+        node_id: ThreadId,
+        token: Token,
+        /// The Lamport clock of the node passing the token
+        timestamp: u128,
+        /// Present only if the nodes keep vector clocks
+        vector: Option<VectorClock>,
+    },
 }
 
 /// Owns a thread and uses the thread to run the [`Node`] on it. Basically
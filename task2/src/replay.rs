@@ -0,0 +1,593 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use crate::trace::{MessageKind, TraceEvent, TraceRecord};
+
+/// Reconstructs the course of a simulation from its trace so that it can be
+/// inspected and checked after the nodes are long gone.
+pub struct Replay {
+    /// Records ordered the way they happened
+    records: Vec<TraceRecord>,
+    /// Names given to the nodes, by the identifiers used in the trace
+    names: HashMap<String, String>,
+}
+
+/// Two nodes were found in the critical section at the same time
+#[derive(Debug)]
+pub struct SafetyViolation {
+    /// The node that was already in the critical section
+    pub occupant: String,
+    /// The node that entered the critical section anyway
+    pub intruder: String,
+    /// The position of the offending [`TraceEvent::CsEnter`] in the trace
+    pub seq: u64,
+}
+
+impl std::fmt::Display for SafetyViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "node {} entered the critical section while node {} was still in it (event #{})",
+            self.intruder, self.occupant, self.seq
+        )
+    }
+}
+
+/// Checks mutual exclusion on the records as they come from a running
+/// network, for the runs which never end. Only the nodes in the critical
+/// section are kept, and the records that came ahead of one with a smaller
+/// number until it comes too.
+#[derive(Default)]
+pub struct SafetyMonitor {
+    next_seq: u64,
+    early: BTreeMap<u64, TraceRecord>,
+    occupants: Vec<String>,
+    names: HashMap<String, String>,
+}
+
+impl SafetyMonitor {
+    /// Takes the next record that came, returns the violations it revealed
+    pub fn observe(&mut self, record: TraceRecord) -> Vec<SafetyViolation> {
+        self.early.insert(record.seq, record);
+        let mut violations = Vec::new();
+        while let Some(record) = self.early.remove(&self.next_seq) {
+            self.next_seq += 1;
+            if let Some(name) = &record.name {
+                self.names.insert(record.node.clone(), name.clone());
+            }
+            let name = |node: &str| self.names.get(node).cloned().unwrap_or(node.to_string());
+            match record.event {
+                TraceEvent::CsEnter => {
+                    if let Some(occupant) = self.occupants.first() {
+                        violations.push(SafetyViolation {
+                            occupant: name(occupant),
+                            intruder: name(&record.node),
+                            seq: record.seq,
+                        });
+                    }
+                    self.occupants.push(record.node);
+                }
+                TraceEvent::CsExit => self.occupants.retain(|n| *n != record.node),
+                _ => {}
+            }
+        }
+        violations
+    }
+}
+
+/// Two stays in the critical section which are not ordered by the
+/// happens-before relation, i.e. nothing but luck kept them from overlapping
+#[derive(Debug)]
+pub struct CausalityViolation {
+    pub first: String,
+    pub second: String,
+    /// The position of the [`TraceEvent::CsEnter`] of the second node
+    pub seq: u64,
+}
+
+impl std::fmt::Display for CausalityViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "node {} left the critical section concurrently with node {} entering it (event #{})",
+            self.first, self.second, self.seq
+        )
+    }
+}
+
+/// Numbers gathered for a single node
+#[derive(Default, Debug, Clone)]
+pub struct NodeStatistics {
+    pub cs_entries: usize,
+    pub messages_sent: usize,
+    /// Time between wanting to enter the critical section and entering it, one
+    /// value per entry, in microseconds
+    pub waits_us: Vec<u64>,
+}
+
+impl NodeStatistics {
+    pub fn mean_wait_ms(&self) -> f64 {
+        if self.waits_us.is_empty() {
+            0.0
+        } else {
+            self.waits_us.iter().sum::<u64>() as f64 / self.waits_us.len() as f64 / 1000.0
+        }
+    }
+    pub fn max_wait_ms(&self) -> f64 {
+        self.waits_us.iter().copied().max().unwrap_or(0) as f64 / 1000.0
+    }
+}
+
+/// Summary of a whole run, by the names of the nodes
+pub struct Statistics(pub BTreeMap<String, NodeStatistics>);
+
+impl Statistics {
+    pub fn cs_entries(&self) -> usize {
+        self.0.values().map(|n| n.cs_entries).sum()
+    }
+    pub fn messages_sent(&self) -> usize {
+        self.0.values().map(|n| n.messages_sent).sum()
+    }
+    pub fn messages_per_cs_entry(&self) -> f64 {
+        match self.cs_entries() {
+            0 => 0.0,
+            entries => self.messages_sent() as f64 / entries as f64,
+        }
+    }
+}
+
+impl std::fmt::Display for Statistics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{:<16} {:>10} {:>10} {:>14} {:>14}",
+            "node", "cs entries", "messages", "mean wait [ms]", "max wait [ms]"
+        )?;
+        for (name, node) in &self.0 {
+            writeln!(
+                f,
+                "{:<16} {:>10} {:>10} {:>14.1} {:>14.1}",
+                name,
+                node.cs_entries,
+                node.messages_sent,
+                node.mean_wait_ms(),
+                node.max_wait_ms()
+            )?;
+        }
+        write!(
+            f,
+            "Total: {} entries to the critical section, {} messages, {:.2} messages per entry",
+            self.cs_entries(),
+            self.messages_sent(),
+            self.messages_per_cs_entry()
+        )
+    }
+}
+
+impl Replay {
+    pub fn new(mut records: Vec<TraceRecord>) -> Self {
+        records.sort_by_key(|r| r.seq);
+        let names = records
+            .iter()
+            .filter_map(|r| r.name.as_ref().map(|name| (r.node.clone(), name.clone())))
+            .collect();
+        Self { records, names }
+    }
+
+    pub fn records(&self) -> &[TraceRecord] {
+        &self.records
+    }
+
+    /// The name given to the node or its identifier if it has no name
+    pub fn display_name<'a>(&'a self, node: &'a str) -> &'a str {
+        self.names.get(node).map(String::as_str).unwrap_or(node)
+    }
+
+    /// Identifiers of all the nodes seen in the trace, ordered by their names
+    pub fn nodes(&self) -> Vec<&str> {
+        let mut nodes = self
+            .records
+            .iter()
+            .map(|r| r.node.as_str())
+            .collect::<Vec<_>>();
+        nodes.sort_by_key(|node| (self.display_name(node), *node));
+        nodes.dedup();
+        nodes
+    }
+
+    /// Pairs up the events of sending and receiving the same message, by the
+    /// positions of the events in [`Replay::records`]. The channels between
+    /// the nodes are FIFO so the n-th message sent is the n-th one received.
+    /// Messages that were never received are left out.
+    pub fn messages(&self) -> Vec<(usize, usize)> {
+        let mut in_flight = HashMap::<(&str, &str, MessageKind), VecDeque<usize>>::new();
+        let mut pairs = Vec::new();
+        for (idx, record) in self.records.iter().enumerate() {
+            if let Some((to, kind)) = record.event.sent_message() {
+                in_flight
+                    .entry((record.node.as_str(), to, kind))
+                    .or_default()
+                    .push_back(idx);
+            }
+            if let Some((from, kind)) = record.event.received_message() {
+                if let Some(sent) = in_flight
+                    .get_mut(&(from, record.node.as_str(), kind))
+                    .and_then(|queue| queue.pop_front())
+                {
+                    pairs.push((sent, idx));
+                }
+            }
+        }
+        pairs
+    }
+
+    /// Stays in the critical section as positions of the entering and leaving
+    /// events in [`Replay::records`], ordered by the time of entering.
+    pub fn critical_sections(&self) -> Vec<(usize, Option<usize>)> {
+        let mut sections = Vec::<(usize, Option<usize>)>::new();
+        let mut open = HashMap::<&str, usize>::new();
+        for (idx, record) in self.records.iter().enumerate() {
+            match record.event {
+                TraceEvent::CsEnter => {
+                    open.insert(&record.node, sections.len());
+                    sections.push((idx, None));
+                }
+                TraceEvent::CsExit => {
+                    if let Some(section) = open.remove(record.node.as_str()) {
+                        sections[section].1 = Some(idx);
+                    }
+                }
+                _ => {}
+            }
+        }
+        sections
+    }
+
+    pub fn describe(&self, event: &TraceEvent) -> String {
+        match event {
+            TraceEvent::Initialized => "finished initialization".to_string(),
+            TraceEvent::CsRequested => "wants to enter the critical section".to_string(),
+            TraceEvent::RequestSent { to, timestamp } => format!(
+                "sent a request to {} (timestamp {timestamp})",
+                self.display_name(to)
+            ),
+            TraceEvent::RequestReceived { from, timestamp } => format!(
+                "received a request from {} (timestamp {timestamp})",
+                self.display_name(from)
+            ),
+            TraceEvent::ApprovalSent { to } => {
+                format!("granted the access to {}", self.display_name(to))
+            }
+            TraceEvent::ApprovalReceived { from } => {
+                format!("got the approval from {}", self.display_name(from))
+            }
+            TraceEvent::Deferred { from } => {
+                format!("deferred the request of {}", self.display_name(from))
+            }
+            TraceEvent::TokenRequestSent { to, number } => format!(
+                "asked {} for the token (request number {number})",
+                self.display_name(to)
+            ),
+            TraceEvent::TokenRequestReceived { from, number } => format!(
+                "was asked by {} for the token (request number {number})",
+                self.display_name(from)
+            ),
+            TraceEvent::TokenSent { to } => {
+                format!("passed the token to {}", self.display_name(to))
+            }
+            TraceEvent::TokenReceived { from } => {
+                format!("received the token from {}", self.display_name(from))
+            }
+            TraceEvent::CsEnter => "enters the critical section".to_string(),
+            TraceEvent::CsExit => "exits the critical section".to_string(),
+            TraceEvent::Finished => "finished all its tasks".to_string(),
+        }
+    }
+
+    /// Prints all the events in the order they happened with the time elapsed
+    /// since the first one.
+    pub fn print_timeline(&self) {
+        let start = self.records.first().map(|r| r.at_us).unwrap_or(0);
+        for record in &self.records {
+            println!(
+                "#{:<6} {:>10.1} ms  {:<16} clock {:<6} {}{}",
+                record.seq,
+                record.at_us.saturating_sub(start) as f64 / 1000.0,
+                self.display_name(&record.node),
+                record.clock,
+                self.describe(&record.event),
+                match &record.vector {
+                    Some(vector) => format!(" {vector}"),
+                    None => String::new(),
+                }
+            );
+        }
+    }
+
+    /// Goes through the events and finds every moment at which more than one
+    /// node was in the critical section.
+    pub fn check_safety(&self) -> Vec<SafetyViolation> {
+        let mut violations = Vec::new();
+        let mut occupants = Vec::<&str>::new();
+        for record in &self.records {
+            match record.event {
+                TraceEvent::CsEnter => {
+                    if let Some(occupant) = occupants.first() {
+                        violations.push(SafetyViolation {
+                            occupant: self.display_name(occupant).to_string(),
+                            intruder: self.display_name(&record.node).to_string(),
+                            seq: record.seq,
+                        });
+                    }
+                    occupants.push(&record.node);
+                }
+                TraceEvent::CsExit => occupants.retain(|n| *n != record.node),
+                _ => {}
+            }
+        }
+        violations
+    }
+
+    /// Checks with the vector clocks (if the trace has them) that every node
+    /// leaving the critical section happened before the next node entering it.
+    pub fn check_causality(&self) -> Vec<CausalityViolation> {
+        let sections = self.critical_sections();
+        sections
+            .windows(2)
+            .filter_map(|pair| {
+                let exit = &self.records[pair[0].1?];
+                let enter = &self.records[pair[1].0];
+                match (&exit.vector, &enter.vector) {
+                    (Some(exit_vector), Some(enter_vector))
+                        if !exit_vector.happened_before(enter_vector) =>
+                    {
+                        Some(CausalityViolation {
+                            first: self.display_name(&exit.node).to_string(),
+                            second: self.display_name(&enter.node).to_string(),
+                            seq: enter.seq,
+                        })
+                    }
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
+    /// Nodes which wanted to enter the critical section but never did before
+    /// the trace ended.
+    pub fn unserved_nodes(&self) -> Vec<&str> {
+        let mut waiting = BTreeMap::<&str, bool>::new();
+        for record in &self.records {
+            match record.event {
+                TraceEvent::CsRequested => {
+                    waiting.insert(self.display_name(&record.node), true);
+                }
+                TraceEvent::CsEnter => {
+                    waiting.insert(self.display_name(&record.node), false);
+                }
+                _ => {}
+            }
+        }
+        waiting
+            .into_iter()
+            .filter_map(|(node, is_waiting)| is_waiting.then_some(node))
+            .collect()
+    }
+
+    pub fn statistics(&self) -> Statistics {
+        let mut stats = BTreeMap::<String, NodeStatistics>::new();
+        let mut wanted_since = HashMap::<&str, u64>::new();
+        for record in &self.records {
+            let node = stats
+                .entry(self.display_name(&record.node).to_string())
+                .or_default();
+            if record.event.is_message_sent() {
+                node.messages_sent += 1;
+            }
+            match record.event {
+                TraceEvent::CsRequested => {
+                    wanted_since.insert(&record.node, record.at_us);
+                }
+                TraceEvent::CsEnter => {
+                    node.cs_entries += 1;
+                    if let Some(since) = wanted_since.remove(record.node.as_str()) {
+                        node.waits_us.push(record.at_us.saturating_sub(since));
+                    }
+                }
+                _ => {}
+            }
+        }
+        Statistics(stats)
+    }
+
+    /// Prints the results of the safety checks and the statistics, returns
+    /// whether the run was safe.
+    pub fn report(&self) -> bool {
+        let violations = self.check_safety();
+        if violations.is_empty() {
+            println!("Safety check passed: no two nodes were in the critical section at once");
+        } else {
+            println!("Safety check FAILED:");
+            for v in &violations {
+                println!("--- {v}");
+            }
+        }
+        let has_vectors = self.records.iter().any(|r| r.vector.is_some());
+        let causality_violations = self.check_causality();
+        if has_vectors && causality_violations.is_empty() {
+            println!("Causality check passed: every stay in the critical section happened before the next one");
+        } else if has_vectors {
+            println!("Causality check FAILED:");
+            for v in &causality_violations {
+                println!("--- {v}");
+            }
+        }
+        let unserved = self.unserved_nodes();
+        if !unserved.is_empty() {
+            println!(
+                "Nodes still waiting for the critical section when the trace ended: {unserved:?}"
+            );
+        }
+        println!("Statistics:");
+        println!("{}", self.statistics());
+
+        violations.is_empty() && causality_violations.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::VectorClock;
+
+    /// Records of the nodes `A` and `B`, 1 ms apart
+    fn records(events: &[(&str, TraceEvent)]) -> Vec<TraceRecord> {
+        events
+            .iter()
+            .enumerate()
+            .map(|(seq, (node, event))| TraceRecord {
+                seq: seq as u64,
+                at_us: 1000 * seq as u64,
+                node: format!("ThreadId({node})"),
+                name: Some(node.to_string()),
+                clock: seq as u128,
+                vector: None,
+                event: event.clone(),
+            })
+            .collect()
+    }
+
+    fn sent(to: &str) -> TraceEvent {
+        TraceEvent::RequestSent {
+            to: format!("ThreadId({to})"),
+            timestamp: 1,
+        }
+    }
+
+    #[test]
+    fn one_after_the_other_is_safe() {
+        let replay = Replay::new(records(&[
+            ("A", TraceEvent::CsRequested),
+            ("A", sent("B")),
+            ("A", TraceEvent::CsEnter),
+            ("B", TraceEvent::CsRequested),
+            ("A", TraceEvent::CsExit),
+            ("B", TraceEvent::CsEnter),
+            ("B", TraceEvent::CsExit),
+        ]));
+        assert!(replay.check_safety().is_empty());
+        assert!(replay.unserved_nodes().is_empty());
+
+        let stats = replay.statistics();
+        assert_eq!(stats.cs_entries(), 2);
+        assert_eq!(stats.messages_sent(), 1);
+        assert_eq!(stats.0["A"].waits_us, vec![2000]);
+        assert_eq!(stats.0["B"].max_wait_ms(), 2.0);
+    }
+
+    #[test]
+    fn overlap_is_found() {
+        let replay = Replay::new(records(&[
+            ("A", TraceEvent::CsEnter),
+            ("B", TraceEvent::CsRequested),
+            ("B", TraceEvent::CsEnter),
+            ("A", TraceEvent::CsExit),
+            ("B", TraceEvent::CsExit),
+            ("A", TraceEvent::CsRequested),
+        ]));
+        let violations = replay.check_safety();
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].occupant, "A");
+        assert_eq!(violations[0].intruder, "B");
+        assert_eq!(violations[0].seq, 2);
+        assert_eq!(replay.unserved_nodes(), vec!["A"]);
+    }
+
+    #[test]
+    fn monitor_waits_for_the_records_that_are_late() {
+        let mut trace = records(&[
+            ("A", TraceEvent::CsEnter),
+            ("A", TraceEvent::CsExit),
+            ("B", TraceEvent::CsEnter),
+            ("A", TraceEvent::CsEnter),
+        ]);
+        let mut monitor = SafetyMonitor::default();
+        // the exit of A comes last but it happened before B entered
+        let exit = trace.remove(1);
+        assert!(monitor.observe(trace.remove(0)).is_empty());
+        assert!(monitor.observe(trace.remove(0)).is_empty());
+        assert!(monitor.observe(trace.remove(0)).is_empty());
+        let violations = monitor.observe(exit);
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].occupant, "B");
+        assert_eq!(violations[0].intruder, "A");
+        assert_eq!(violations[0].seq, 3);
+    }
+
+    /// `A` leaves the critical section and `B` enters it, with the vector
+    /// clocks of the exit and the entry
+    fn handover(exit: &[(&str, u128)], enter: &[(&str, u128)]) -> Replay {
+        let vector = |entries: &[(&str, u128)]| {
+            let mut vector = VectorClock::default();
+            for (node, value) in entries {
+                vector.0.insert(format!("ThreadId({node})"), *value);
+            }
+            Some(vector)
+        };
+        let mut trace = records(&[
+            ("A", TraceEvent::CsEnter),
+            ("A", TraceEvent::CsExit),
+            ("B", TraceEvent::CsEnter),
+        ]);
+        trace[0].vector = vector(&[("A", 1)]);
+        trace[1].vector = vector(exit);
+        trace[2].vector = vector(enter);
+        Replay::new(trace)
+    }
+
+    #[test]
+    fn exit_known_to_the_next_one_in() {
+        let replay = handover(&[("A", 2)], &[("A", 3), ("B", 2)]);
+        assert_eq!(replay.critical_sections(), vec![(0, Some(1)), (2, None)]);
+        assert!(replay.check_causality().is_empty());
+    }
+
+    #[test]
+    fn exit_unknown_to_the_next_one_in() {
+        let replay = handover(&[("A", 2)], &[("A", 1), ("B", 2)]);
+        let violations = replay.check_causality();
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].first, "A");
+        assert_eq!(violations[0].second, "B");
+        assert_eq!(violations[0].seq, 2);
+    }
+
+    #[test]
+    fn sends_are_paired_with_receives() {
+        let replay = Replay::new(records(&[
+            ("A", sent("B")),
+            ("A", sent("B")),
+            (
+                "B",
+                TraceEvent::RequestReceived {
+                    from: "ThreadId(A)".to_string(),
+                    timestamp: 1,
+                },
+            ),
+            (
+                "B",
+                TraceEvent::ApprovalSent {
+                    to: "ThreadId(A)".to_string(),
+                },
+            ),
+            (
+                "A",
+                TraceEvent::ApprovalReceived {
+                    from: "ThreadId(B)".to_string(),
+                },
+            ),
+        ]));
+        // the second request is still on its way
+        assert_eq!(replay.messages(), vec![(0, 2), (3, 4)]);
+        assert_eq!(replay.nodes(), vec!["ThreadId(A)", "ThreadId(B)"]);
+    }
+}
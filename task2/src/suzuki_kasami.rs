@@ -0,0 +1,374 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::mpsc::RecvTimeoutError,
+    thread::ThreadId,
+};
+
+use crate::{
+    node::{Node, NodeLocalData, SystemMsg},
+    trace::{node_label, TraceEvent},
+};
+
+/// The single token of the Suzuki-Kasami algorithm. Only the node holding it
+/// may enter the critical section.
+#[derive(Default, Debug, Clone)]
+pub struct Token {
+    /// The request number of the most recently served request of every node
+    /// (LN in the algorithm's description)
+    pub last_served: HashMap<ThreadId, u128>,
+    /// Nodes waiting for the token
+    pub queue: VecDeque<ThreadId>,
+}
+
+impl Node {
+    /// The implementation of the Suzuki-Kasami algorithm
+    pub(crate) fn execute_suzuki_kasami(mut self, mut current_node: NodeLocalData) {
+        while !self.is_done() {
+            // Start with whatever we have to do that we can do alone, the
+            // requests for the token have to be answered in the meantime
+            let idle_until = self.time.now() + self.take_idle_task();
+            while let Some(remaining) = idle_until.checked_sub(self.time.now()) {
+                match self
+                    .time
+                    .recv_timeout(&current_node.network_connection, remaining)
+                {
+                    Ok(msg) => self.handle_token_msg(&mut current_node, msg, false),
+                    Err(RecvTimeoutError::Timeout) => break,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            }
+            current_node.clock += 1;
+            current_node.tick_vector();
+            self.record(&current_node, TraceEvent::CsRequested);
+
+            if current_node.token.is_none() {
+                let request_number = current_node
+                    .request_numbers
+                    .entry(current_node.node_id)
+                    .or_insert(0);
+                *request_number += 1;
+                let request_number = *request_number;
+
+                // Broadcast the request:
+                current_node.clock += 1;
+                current_node.tick_vector();
+                current_node.connected_to.iter().for_each(|other| {
+                    self.record(
+                        &current_node,
+                        TraceEvent::TokenRequestSent {
+                            to: node_label(other.node_id),
+                            number: request_number,
+                        },
+                    );
+                    self.time.send(
+                        other,
+                        SystemMsg::TokenReq {
+                            node_id: current_node.node_id,
+                            request_number,
+                            timestamp: current_node.clock,
+                            vector: current_node.vector_clock.clone(),
+                        },
+                    );
+                });
+
+                // Wait for the token:
+                while current_node.token.is_none() {
+                    let Some(msg) = self.time.recv(&current_node.network_connection) else {
+                        return;
+                    };
+                    self.handle_token_msg(&mut current_node, msg, true);
+                }
+            }
+
+            self.log(format_args!(
+                "\x1b[93mNode {:?}{} proceeds into the critical section\x1b[0m",
+                current_node.node_id,
+                self.name_suffix()
+            ));
+            current_node.tick_vector();
+            self.record(&current_node, TraceEvent::CsEnter);
+            self.execute_in_critical_section();
+            current_node.clock += 1;
+            current_node.tick_vector();
+            self.record(&current_node, TraceEvent::CsExit);
+            self.log(format_args!(
+                //log msg
+                "\x1b[93mNode {:?}{} exits the critical section\x1b[0m",
+                current_node.node_id,
+                self.name_suffix()
+            ));
+
+            // Our request has been served
+            let own_number = current_node
+                .request_numbers
+                .get(&current_node.node_id)
+                .copied()
+                .unwrap_or(0);
+            if let Some(token) = &mut current_node.token {
+                token.last_served.insert(current_node.node_id, own_number);
+            }
+
+            // Catch up with the requests which came while we were busy and
+            // pass the token on if anyone is waiting for it
+            while let Ok(msg) = current_node.network_connection.try_recv() {
+                self.handle_token_msg(&mut current_node, msg, true);
+            }
+            self.pass_token(&mut current_node);
+        }
+
+        self.log(format_args!(
+            //log msg
+            "\x1b[93mNode {:?}{} finished all its tasks\x1b[0m",
+            current_node.node_id,
+            self.name_suffix()
+        ));
+        current_node.tick_vector();
+        self.record(&current_node, TraceEvent::Finished);
+
+        // We will never want the critical section again but we might still be
+        // holding the token or get asked for it, the thread keeps running
+        // until we kill the whole application.
+        loop {
+            let Some(msg) = self.time.recv(&current_node.network_connection) else {
+                return;
+            };
+            self.handle_token_msg(&mut current_node, msg, false);
+        }
+    }
+
+    /// Reacts to a message of the Suzuki-Kasami algorithm. A node which is
+    /// `busy` wants the critical section itself or is in it and so it keeps
+    /// the token if it has it.
+    fn handle_token_msg(&self, current_node: &mut NodeLocalData, msg: SystemMsg, busy: bool) {
+        match msg {
+            SystemMsg::TokenReq {
+                node_id,
+                request_number,
+                timestamp,
+                vector,
+            } => {
+                current_node.clock = current_node.clock.max(timestamp) + 1;
+                current_node.receive_vector(&vector);
+                self.record(
+                    current_node,
+                    TraceEvent::TokenRequestReceived {
+                        from: node_label(node_id),
+                        number: request_number,
+                    },
+                );
+                //log msg:
+                self.log(format_args!(
+                    "Node {:?} asked the node {:?}{} for the token",
+                    node_id,
+                    current_node.node_id,
+                    self.name_suffix()
+                ));
+
+                let known = current_node.request_numbers.entry(node_id).or_insert(0);
+                *known = (*known).max(request_number);
+
+                if !busy {
+                    self.pass_token(current_node);
+                }
+            }
+            SystemMsg::TokenPassed {
+                node_id,
+                token,
+                timestamp,
+                vector,
+            } => {
+                current_node.clock = current_node.clock.max(timestamp) + 1;
+                current_node.receive_vector(&vector);
+                self.record(
+                    current_node,
+                    TraceEvent::TokenReceived {
+                        from: node_label(node_id),
+                    },
+                );
+                self.log(format_args!(
+                    //log msg
+                    "Node {:?}{} received the token from node {:?}",
+                    current_node.node_id,
+                    self.name_suffix(),
+                    node_id
+                ));
+                current_node.token = Some(token);
+            }
+            _ => {
+                // ignore other types of the messages after the initialization
+            }
+        }
+    }
+
+    /// Puts every node with an outstanding request into the queue of the token
+    /// (if we hold it) and hands the token over to the first one waiting.
+    fn pass_token(&self, current_node: &mut NodeLocalData) {
+        let Some(token) = &mut current_node.token else {
+            return;
+        };
+        for other in &current_node.connected_to {
+            let requested = current_node
+                .request_numbers
+                .get(&other.node_id)
+                .copied()
+                .unwrap_or(0);
+            let served = token.last_served.get(&other.node_id).copied().unwrap_or(0);
+            if requested == served + 1 && !token.queue.contains(&other.node_id) {
+                token.queue.push_back(other.node_id);
+            }
+        }
+
+        if let Some(next) = token.queue.pop_front() {
+            let token = current_node.token.take().unwrap();
+            current_node.clock += 1;
+            current_node.tick_vector();
+            self.record(
+                current_node,
+                TraceEvent::TokenSent {
+                    to: node_label(next),
+                },
+            );
+            self.time.send(
+                current_node
+                    .connected_to
+                    .iter()
+                    .find(|other| other.node_id == next)
+                    .expect("the token is to be passed to a node unknown to the current node"),
+                SystemMsg::TokenPassed {
+                    node_id: current_node.node_id,
+                    token,
+                    timestamp: current_node.clock,
+                    vector: current_node.vector_clock.clone(),
+                },
+            );
+            self.log(format_args!(
+                //log msg
+                "Node {:?}{} passed the token to node {:?}",
+                current_node.node_id,
+                self.name_suffix(),
+                next
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::{channel, Receiver};
+
+    use super::*;
+    use crate::node::NodeInfo;
+
+    /// A node connected to `peers` others, with the receiving ends of the
+    /// channels to them
+    fn connected(peers: usize) -> (NodeLocalData, Vec<(ThreadId, Receiver<SystemMsg>)>) {
+        let (_, own_rx) = channel();
+        let mut local = NodeLocalData::new(std::thread::current().id(), own_rx);
+        let mut others = Vec::new();
+        for _ in 0..peers {
+            let node_id = std::thread::spawn(|| {}).thread().id();
+            let (tx, rx) = channel();
+            local.connected_to.push(NodeInfo {
+                is_request_accepted: false,
+                node_id,
+                connection: tx,
+            });
+            others.push((node_id, rx));
+        }
+        (local, others)
+    }
+
+    fn request(node_id: ThreadId, request_number: u128, timestamp: u128) -> SystemMsg {
+        SystemMsg::TokenReq {
+            node_id,
+            request_number,
+            timestamp,
+            vector: None,
+        }
+    }
+
+    #[test]
+    fn token_goes_to_the_first_outstanding_request() {
+        let (mut local, others) = connected(3);
+        local.token = Some(Token::default());
+        local.request_numbers.insert(others[1].0, 1);
+        local.request_numbers.insert(others[2].0, 1);
+        local.clock = 4;
+
+        Node::default().pass_token(&mut local);
+
+        assert!(local.token.is_none());
+        assert!(others[0].1.try_recv().is_err());
+        assert!(others[2].1.try_recv().is_err());
+        match others[1].1.try_recv().unwrap() {
+            SystemMsg::TokenPassed {
+                node_id,
+                token,
+                timestamp,
+                ..
+            } => {
+                assert_eq!(node_id, local.node_id);
+                assert_eq!(timestamp, 5);
+                assert_eq!(token.queue, [others[2].0]);
+            }
+            _ => panic!("expected the token"),
+        }
+    }
+
+    #[test]
+    fn served_requests_are_not_queued_again() {
+        let (mut local, others) = connected(1);
+        let mut token = Token::default();
+        token.last_served.insert(others[0].0, 2);
+        local.token = Some(token);
+        local.request_numbers.insert(others[0].0, 2);
+
+        Node::default().pass_token(&mut local);
+
+        assert!(local.token.is_some());
+        assert!(others[0].1.try_recv().is_err());
+    }
+
+    #[test]
+    fn busy_node_keeps_the_token() {
+        let (mut local, others) = connected(1);
+        let node = Node::default();
+        local.token = Some(Token::default());
+
+        node.handle_token_msg(&mut local, request(others[0].0, 1, 7), true);
+        // an old request that came late does not lower the number
+        node.handle_token_msg(&mut local, request(others[0].0, 0, 2), true);
+        assert!(local.token.is_some());
+        assert_eq!(local.request_numbers[&others[0].0], 1);
+        assert_eq!(local.clock, 9);
+
+        // once it is done the request is served
+        node.handle_token_msg(&mut local, request(others[0].0, 1, 7), false);
+        assert!(local.token.is_none());
+        assert!(matches!(
+            others[0].1.try_recv(),
+            Ok(SystemMsg::TokenPassed { .. })
+        ));
+    }
+
+    #[test]
+    fn received_token_merges_the_clock() {
+        let (mut local, others) = connected(1);
+        local.clock = 3;
+        let token = Token {
+            last_served: HashMap::from([(others[0].0, 1)]),
+            queue: VecDeque::new(),
+        };
+        let msg = SystemMsg::TokenPassed {
+            node_id: others[0].0,
+            token,
+            timestamp: 10,
+            vector: None,
+        };
+
+        Node::default().handle_token_msg(&mut local, msg, true);
+
+        assert_eq!(local.clock, 11);
+        assert_eq!(local.token.unwrap().last_served[&others[0].0], 1);
+    }
+}
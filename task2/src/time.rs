@@ -0,0 +1,408 @@
+//! Where the nodes take their time from. Normally they really sleep and wait
+//! for the messages. In the virtual time (used by the [`crate::bench`]) they
+//! take turns instead: only one node runs at a time and once it waits the clock
+//! jumps straight to the next thing that happens, so a run takes as long as
+//! the computation and not as long as the tasks.
+
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    sync::{
+        mpsc::{Receiver, RecvTimeoutError, Sender},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
+    thread::ThreadId,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::node::{NodeInfo, SystemMsg};
+
+/// The time as seen by a single node
+#[derive(Default)]
+pub enum Time {
+    /// The clock of the system, the tasks take as long as they say
+    #[default]
+    Real,
+    /// The node takes turns with the others in a shared [`VirtualTime`]
+    Virtual {
+        shared: Arc<VirtualTime>,
+        node: usize,
+    },
+}
+
+impl Time {
+    /// Called by the node once it is ready to run, in the virtual time it
+    /// waits for its first turn
+    pub fn start(&self) {
+        if let Time::Virtual { shared, node } = self {
+            shared.start(*node);
+        }
+    }
+
+    /// Time since the UNIX epoch, or since the start of the run in the virtual
+    /// time
+    pub fn now(&self) -> Duration {
+        match self {
+            Time::Real => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("the system clock is set before the UNIX epoch"),
+            Time::Virtual { shared, .. } => shared.lock().now,
+        }
+    }
+
+    pub fn sleep(&self, duration: Duration) {
+        match self {
+            Time::Real => std::thread::sleep(duration),
+            Time::Virtual { shared, node } => {
+                shared.wait(*node, false, Some(duration));
+            }
+        }
+    }
+
+    /// Waits for the next message. Gives nothing once the messages cannot
+    /// come anymore.
+    pub fn recv(&self, rx: &Receiver<SystemMsg>) -> Option<SystemMsg> {
+        match self {
+            Time::Real => rx.recv().ok(),
+            Time::Virtual { shared, node } => loop {
+                if let Ok(msg) = rx.try_recv() {
+                    return Some(msg);
+                }
+                if !shared.wait(*node, true, None) {
+                    return None;
+                }
+            },
+        }
+    }
+
+    pub fn recv_timeout(
+        &self,
+        rx: &Receiver<SystemMsg>,
+        timeout: Duration,
+    ) -> Result<SystemMsg, RecvTimeoutError> {
+        match self {
+            Time::Real => rx.recv_timeout(timeout),
+            Time::Virtual { shared, node } => {
+                if let Ok(msg) = rx.try_recv() {
+                    return Ok(msg);
+                }
+                let running = shared.wait(*node, true, Some(timeout));
+                match rx.try_recv() {
+                    Ok(msg) => Ok(msg),
+                    Err(_) if running => Err(RecvTimeoutError::Timeout),
+                    Err(_) => Err(RecvTimeoutError::Disconnected),
+                }
+            }
+        }
+    }
+
+    /// Sends the message to the node, in the virtual time it gets delivered
+    /// after a random delay
+    pub fn send(&self, to: &NodeInfo, msg: SystemMsg) {
+        match self {
+            Time::Real => to.connection.send(msg).unwrap(),
+            Time::Virtual { shared, node } => shared.send(*node, to, msg),
+        }
+    }
+}
+
+impl Drop for Time {
+    fn drop(&mut self) {
+        if let Time::Virtual { shared, node } = self {
+            shared.leave(*node);
+        }
+    }
+}
+
+/// The clock and the schedule shared by all the nodes running in the virtual
+/// time. Given the same seed, the nodes take the same turns every time.
+pub struct VirtualTime {
+    schedule: Mutex<Schedule>,
+}
+
+struct Schedule {
+    now: Duration,
+    /// Delivering a message takes a random time from this range
+    delay_ms: (u64, u64),
+    rng: StdRng,
+    events: BinaryHeap<Reverse<Scheduled>>,
+    next_seq: u64,
+    /// The node whose turn it is, none while the next one is being picked
+    current: Option<usize>,
+    /// Nodes which have not come to their first turn yet, nothing happens
+    /// until all of them do
+    arriving: usize,
+    /// The nodes by their threads, known once they come to their first turn
+    threads: HashMap<ThreadId, usize>,
+    /// What every node is waiting for, none while it is running
+    waiting: Vec<Option<Waiting>>,
+    /// Every node waits on its own for its turn
+    turns: Vec<Arc<Condvar>>,
+    /// When the last message sent through every link gets delivered, so that
+    /// the messages do not overtake each other
+    links: HashMap<(usize, usize), Duration>,
+    /// Nothing can happen anymore
+    over: bool,
+}
+
+#[derive(Clone, Copy)]
+struct Waiting {
+    for_message: bool,
+    /// The sequence number of the wake-up scheduled for the node
+    timer: Option<u64>,
+}
+
+enum Event {
+    FirstTurn(usize),
+    WakeUp(usize),
+    Deliver {
+        to: usize,
+        tx: Sender<SystemMsg>,
+        msg: SystemMsg,
+    },
+}
+
+/// An event waiting in the schedule, ordered by the time and then by the
+/// order of scheduling
+struct Scheduled {
+    at: Duration,
+    seq: u64,
+    event: Event,
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Self) -> bool {
+        (self.at, self.seq) == (other.at, other.seq)
+    }
+}
+impl Eq for Scheduled {}
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.at, self.seq).cmp(&(other.at, other.seq))
+    }
+}
+
+impl VirtualTime {
+    pub fn new(message_delay_ms: (u64, u64), seed: u64) -> Arc<Self> {
+        Arc::new(Self {
+            schedule: Mutex::new(Schedule {
+                now: Duration::ZERO,
+                delay_ms: message_delay_ms,
+                rng: StdRng::seed_from_u64(seed),
+                events: BinaryHeap::new(),
+                next_seq: 0,
+                current: None,
+                arriving: 0,
+                threads: HashMap::new(),
+                waiting: Vec::new(),
+                turns: Vec::new(),
+                links: HashMap::new(),
+                over: false,
+            }),
+        })
+    }
+
+    /// Adds a node to the run, the nodes get their first turns in the order
+    /// they joined
+    pub fn join(self: &Arc<Self>) -> Time {
+        let mut schedule = self.lock();
+        let node = schedule.turns.len();
+        schedule.turns.push(Arc::new(Condvar::new()));
+        schedule.waiting.push(None);
+        schedule.arriving += 1;
+        schedule.push(Duration::ZERO, Event::FirstTurn(node));
+        Time::Virtual {
+            shared: Arc::clone(self),
+            node,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Schedule> {
+        self.schedule
+            .lock()
+            .expect("a node panicked in the virtual time")
+    }
+
+    fn start(&self, node: usize) {
+        let mut schedule = self.lock();
+        schedule.threads.insert(std::thread::current().id(), node);
+        schedule.arriving -= 1;
+        schedule.waiting[node] = Some(Waiting {
+            for_message: false,
+            timer: None,
+        });
+        self.take_turn(schedule, node);
+    }
+
+    /// Gives up the turn until a message comes (if the node waits for one) or
+    /// the time runs out. Returns false once the run is over.
+    fn wait(&self, node: usize, for_message: bool, timeout: Option<Duration>) -> bool {
+        let mut schedule = self.lock();
+        let timer = timeout.map(|timeout| {
+            let at = schedule.now + timeout;
+            schedule.push(at, Event::WakeUp(node))
+        });
+        schedule.waiting[node] = Some(Waiting { for_message, timer });
+        schedule.current = None;
+        self.take_turn(schedule, node)
+    }
+
+    fn take_turn(&self, mut schedule: MutexGuard<'_, Schedule>, node: usize) -> bool {
+        if schedule.current.is_none() && schedule.arriving == 0 {
+            schedule.advance();
+        }
+        let turn = Arc::clone(&schedule.turns[node]);
+        while schedule.current != Some(node) && !schedule.over {
+            schedule = turn
+                .wait(schedule)
+                .expect("a node panicked in the virtual time");
+        }
+        schedule.waiting[node] = None;
+        !schedule.over
+    }
+
+    /// The node is gone (done or panicked), the others must not wait for it
+    fn leave(&self, node: usize) {
+        let mut schedule = self.lock();
+        if !schedule.threads.values().any(|&other| other == node) {
+            // it never came to its first turn
+            schedule.arriving -= 1;
+        } else if schedule.current == Some(node) {
+            schedule.current = None;
+        } else {
+            return;
+        }
+        if schedule.current.is_none() && schedule.arriving == 0 && !schedule.over {
+            schedule.advance();
+        }
+    }
+
+    fn send(&self, from: usize, to: &NodeInfo, msg: SystemMsg) {
+        let mut schedule = self.lock();
+        let to_node = *schedule
+            .threads
+            .get(&to.node_id)
+            .expect("a message for a node which does not run in the virtual time");
+        let (min, max) = schedule.delay_ms;
+        let delay = Duration::from_millis(schedule.rng.gen_range(min..=max.max(min)));
+        let at = (schedule.now + delay).max(
+            schedule
+                .links
+                .get(&(from, to_node))
+                .copied()
+                .unwrap_or_default(),
+        );
+        schedule.links.insert((from, to_node), at);
+        schedule.push(
+            at,
+            Event::Deliver {
+                to: to_node,
+                tx: to.connection.clone(),
+                msg,
+            },
+        );
+    }
+}
+
+impl Schedule {
+    fn push(&mut self, at: Duration, event: Event) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.events.push(Reverse(Scheduled { at, seq, event }));
+        seq
+    }
+
+    /// Goes through the schedule until some node can run and hands it the
+    /// turn. If there is nothing left, the run is over.
+    fn advance(&mut self) {
+        while let Some(Reverse(Scheduled { at, seq, event })) = self.events.pop() {
+            self.now = at;
+            let next = match event {
+                Event::FirstTurn(node) => Some(node),
+                Event::WakeUp(node) => self.waiting[node]
+                    .filter(|waiting| waiting.timer == Some(seq))
+                    .map(|_| node),
+                Event::Deliver { to, tx, msg } => {
+                    // the node might be gone already
+                    let _ = tx.send(msg);
+                    self.waiting[to]
+                        .filter(|waiting| waiting.for_message)
+                        .map(|_| to)
+                }
+            };
+            if let Some(node) = next {
+                self.current = Some(node);
+                self.turns[node].notify_one();
+                return;
+            }
+        }
+        self.over = true;
+        for turn in &self.turns {
+            turn.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nodes_wake_up_in_the_order_of_their_sleeps() {
+        let time = VirtualTime::new((1, 1), 0);
+        let woken = Arc::new(Mutex::new(Vec::new()));
+        // all of them have to join before any of them starts
+        let joined = [30, 10, 20].map(|sleep_ms| (sleep_ms, time.join()));
+        let threads = joined.map(|(sleep_ms, own)| {
+            let woken = Arc::clone(&woken);
+            std::thread::spawn(move || {
+                own.start();
+                own.sleep(Duration::from_millis(sleep_ms));
+                woken.lock().unwrap().push(own.now().as_millis());
+            })
+        });
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(*woken.lock().unwrap(), vec![10, 20, 30]);
+    }
+
+    #[test]
+    fn messages_take_the_delay_and_keep_their_order() {
+        let time = VirtualTime::new((5, 5), 0);
+        let (tx, rx) = std::sync::mpsc::channel();
+        // both have to join before either of them starts
+        let (own, other) = (time.join(), time.join());
+
+        let receiver = std::thread::spawn(move || {
+            own.start();
+            let mut received = Vec::new();
+            while let Some(SystemMsg::Start) = own.recv(&rx) {
+                received.push(own.now().as_millis());
+            }
+            received
+        });
+        let to = NodeInfo {
+            is_request_accepted: false,
+            node_id: receiver.thread().id(),
+            connection: tx,
+        };
+        let sender = std::thread::spawn(move || {
+            other.start();
+            other.send(&to, SystemMsg::Start);
+            other.sleep(Duration::from_millis(2));
+            other.send(&to, SystemMsg::Start);
+        });
+
+        sender.join().unwrap();
+        // the receiver gets nothing once nothing can happen anymore
+        assert_eq!(receiver.join().unwrap(), vec![5, 7]);
+    }
+}
@@ -0,0 +1,279 @@
+use std::{
+    io::{BufRead, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread::ThreadId,
+};
+
+use crate::clock::VectorClock;
+
+/// Counter shared by the nodes of a network used to put all their records into
+/// a single order consistent with what happened in real time. Every node takes
+/// a number from it at the moment of recording the event so if one event could
+/// have caused the other it is guaranteed to have a smaller number.
+#[derive(Default, Debug, Clone)]
+pub struct Sequence(Arc<AtomicU64>);
+
+impl Sequence {
+    pub fn next(&self) -> u64 {
+        self.0.fetch_add(1, Ordering::SeqCst)
+    }
+}
+
+/// A single event observed at a node, as it gets written into a JSON-lines
+/// trace file (one record per line).
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+pub struct TraceRecord {
+    /// Position of the record in the global order of events
+    pub seq: u64,
+    /// Wall-clock time of the event in microseconds since the UNIX epoch (since
+    /// the start of the run in the virtual time)
+    pub at_us: u64,
+    /// Identifier of the node which recorded the event
+    pub node: String,
+    /// Optional, improves readability of the replay
+    pub name: Option<String>,
+    /// The value of the Lamport clock of the node when the event was recorded
+    pub clock: u128,
+    /// The vector clock of the node, if the nodes keep them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vector: Option<VectorClock>,
+    pub event: TraceEvent,
+}
+
+impl TraceRecord {
+    pub fn new(
+        seq: u64,
+        node_id: ThreadId,
+        name: Option<String>,
+        at_us: u64,
+        clock: u128,
+        vector: Option<VectorClock>,
+        event: TraceEvent,
+    ) -> Self {
+        Self {
+            seq,
+            at_us,
+            node: node_label(node_id),
+            name,
+            clock,
+            vector,
+            event,
+        }
+    }
+}
+
+/// Kinds of events the nodes report. Peers are referred to with the same
+/// identifiers that are used in the [`TraceRecord::node`] field.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TraceEvent {
+    /// The node got to know all the other nodes and started running its tasks
+    Initialized,
+    /// The node finished its idle task and wants to enter the critical section
+    CsRequested,
+    RequestSent {
+        to: String,
+        timestamp: u128,
+    },
+    RequestReceived {
+        from: String,
+        timestamp: u128,
+    },
+    ApprovalSent {
+        to: String,
+    },
+    ApprovalReceived {
+        from: String,
+    },
+    /// The reply to the request has been postponed until the node leaves the
+    /// critical section
+    Deferred {
+        from: String,
+    },
+    TokenRequestSent {
+        to: String,
+        number: u128,
+    },
+    TokenRequestReceived {
+        from: String,
+        number: u128,
+    },
+    TokenSent {
+        to: String,
+    },
+    TokenReceived {
+        from: String,
+    },
+    CsEnter,
+    CsExit,
+    /// The node has no more tasks to execute
+    Finished,
+}
+
+/// Kinds of messages exchanged by the nodes, used to pair up the events of
+/// sending and receiving the same message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MessageKind {
+    Request,
+    Approval,
+    TokenRequest,
+    Token,
+}
+
+impl MessageKind {
+    pub const ALL: [MessageKind; 4] = [
+        MessageKind::Request,
+        MessageKind::Approval,
+        MessageKind::TokenRequest,
+        MessageKind::Token,
+    ];
+}
+
+impl TraceEvent {
+    /// The receiver and the kind of the message if the event stands for a
+    /// message put into the network
+    pub fn sent_message(&self) -> Option<(&str, MessageKind)> {
+        match self {
+            TraceEvent::RequestSent { to, .. } => Some((to, MessageKind::Request)),
+            TraceEvent::ApprovalSent { to } => Some((to, MessageKind::Approval)),
+            TraceEvent::TokenRequestSent { to, .. } => Some((to, MessageKind::TokenRequest)),
+            TraceEvent::TokenSent { to } => Some((to, MessageKind::Token)),
+            _ => None,
+        }
+    }
+    /// The sender and the kind of the message if the event stands for a
+    /// message taken out of the network
+    pub fn received_message(&self) -> Option<(&str, MessageKind)> {
+        match self {
+            TraceEvent::RequestReceived { from, .. } => Some((from, MessageKind::Request)),
+            TraceEvent::ApprovalReceived { from } => Some((from, MessageKind::Approval)),
+            TraceEvent::TokenRequestReceived { from, .. } => {
+                Some((from, MessageKind::TokenRequest))
+            }
+            TraceEvent::TokenReceived { from } => Some((from, MessageKind::Token)),
+            _ => None,
+        }
+    }
+    /// Tells whether the event stands for a message put into the network
+    pub fn is_message_sent(&self) -> bool {
+        self.sent_message().is_some()
+    }
+}
+
+/// The identifier of a node as it appears in the traces
+pub fn node_label(node_id: ThreadId) -> String {
+    format!("{node_id:?}")
+}
+
+/// Writes the records into a file, one JSON object per line. Every line is
+/// flushed immediately so that the trace stays useful even if the simulation
+/// never finishes and has to be killed.
+pub struct TraceWriter {
+    file: std::io::BufWriter<std::fs::File>,
+}
+
+impl TraceWriter {
+    pub fn create(path: &str) -> std::io::Result<Self> {
+        Ok(Self {
+            file: std::io::BufWriter::new(std::fs::File::create(path)?),
+        })
+    }
+    pub fn write(&mut self, record: &TraceRecord) -> std::io::Result<()> {
+        serde_json::to_writer(&mut self.file, record)?;
+        self.file.write_all(b"\n")?;
+        self.file.flush()
+    }
+}
+
+/// Reads a trace produced by the [`TraceWriter`] and orders the records the
+/// way they happened.
+pub fn read_trace(path: &str) -> Result<Vec<TraceRecord>, String> {
+    let file = std::fs::File::open(path).map_err(|e| format!("failed to open {path}: {e}"))?;
+    let mut records = Vec::new();
+    for (line_no, line) in std::io::BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| format!("failed to read {path}: {e}"))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = serde_json::from_str(&line)
+            .map_err(|e| format!("malformed record in line {}: {e}", line_no + 1))?;
+        records.push(record);
+    }
+    records.sort_by_key(|r: &TraceRecord| r.seq);
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(seq: u64, event: TraceEvent) -> TraceRecord {
+        TraceRecord {
+            seq,
+            at_us: 1000 * seq,
+            node: "ThreadId(2)".to_string(),
+            name: Some("A".to_string()),
+            clock: seq as u128,
+            vector: None,
+            event,
+        }
+    }
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("task2-{}-{name}", std::process::id()));
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn every_sequence_counts_from_zero() {
+        let (first, second) = (Sequence::default(), Sequence::default());
+        assert_eq!([first.next(), first.next()], [0, 1]);
+        // the clones share the counter, other sequences do not
+        assert_eq!(first.clone().next(), 2);
+        assert_eq!(second.next(), 0);
+
+        let id = std::thread::current().id();
+        let record = TraceRecord::new(first.next(), id, None, 10, 0, None, TraceEvent::Finished);
+        assert_eq!((record.seq, record.node), (3, node_label(id)));
+    }
+
+    #[test]
+    fn written_trace_is_read_in_order() {
+        let path = temp_path("written.jsonl");
+        let mut writer = TraceWriter::create(&path).unwrap();
+        // the records can reach the writer out of order
+        writer.write(&record(1, TraceEvent::CsRequested)).unwrap();
+        writer
+            .write(&record(
+                2,
+                TraceEvent::RequestSent {
+                    to: "ThreadId(3)".to_string(),
+                    timestamp: 1,
+                },
+            ))
+            .unwrap();
+        writer.write(&record(0, TraceEvent::Initialized)).unwrap();
+        drop(writer);
+
+        let records = read_trace(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let seqs = records.iter().map(|r| r.seq).collect::<Vec<_>>();
+        assert_eq!(seqs, vec![0, 1, 2]);
+        assert_eq!(records[0].event, TraceEvent::Initialized);
+        assert!(records[2].event.is_message_sent());
+        assert_eq!(records[2].name.as_deref(), Some("A"));
+    }
+
+    #[test]
+    fn malformed_line_is_reported() {
+        let path = temp_path("malformed.jsonl");
+        std::fs::write(&path, "\n{\"seq\": 0}\n").unwrap();
+        let error = read_trace(&path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(error.starts_with("malformed record in line 2"), "{error}");
+        assert!(read_trace(&temp_path("missing.jsonl")).is_err());
+    }
+}
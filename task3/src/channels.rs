@@ -0,0 +1,183 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::{system::SystemMsg, tree::NodeId};
+
+/// A message on its way to a node
+#[derive(Debug)]
+pub struct InFlight {
+    pub from: NodeId,
+    /// The first iteration in which the receiver can see the message
+    pub deliver_at: usize,
+    pub msg: SystemMsg,
+}
+
+/// The links between the nodes. Every node has an inbox in which the
+/// messages wait until they get delivered, how long it takes depends on the
+/// link the message travels through. A link is FIFO, a message never arrives
+/// before the ones sent through the same link earlier.
+#[derive(Debug, Default)]
+pub struct Channels {
+    inboxes: Vec<VecDeque<InFlight>>,
+    /// Delays of the links which do not use the default one, the smaller id
+    /// of the two nodes goes first
+    delays: HashMap<(NodeId, NodeId), usize>,
+    default_delay: usize,
+    /// The delay of all the requests, whichever link they take
+    request_delay: Option<usize>,
+}
+
+impl Channels {
+    pub fn new(default_delay: usize) -> Self {
+        Self {
+            default_delay,
+            ..Default::default()
+        }
+    }
+    pub fn add_node(&mut self) {
+        self.inboxes.push(VecDeque::new());
+    }
+    /// Sets the delay, in iterations, of the link between the nodes (in both
+    /// directions)
+    pub fn set_delay(&mut self, a: NodeId, b: NodeId, delay: usize) {
+        self.delays.insert((a.min(b), a.max(b)), delay);
+    }
+    pub fn delay(&self, a: NodeId, b: NodeId) -> usize {
+        self.delays
+            .get(&(a.min(b), a.max(b)))
+            .copied()
+            .unwrap_or(self.default_delay)
+    }
+    /// Makes the requests take the given delay instead of the one of the link
+    pub fn set_request_delay(&mut self, delay: usize) {
+        self.request_delay = Some(delay);
+    }
+    pub fn request_delay(&self) -> Option<usize> {
+        self.request_delay
+    }
+    pub fn send(&mut self, from: NodeId, to: NodeId, msg: SystemMsg, now: usize) {
+        let delay = match (&msg, self.request_delay) {
+            (SystemMsg::Request { .. }, Some(delay)) => delay,
+            _ => self.delay(from, to),
+        };
+        // a request can be faster than the link, it waits for whatever is
+        // ahead of it then
+        let ahead = self.inboxes[to]
+            .iter()
+            .rev()
+            .find(|msg| msg.from == from)
+            .map_or(0, |msg| msg.deliver_at);
+        let deliver_at = (now + delay).max(ahead);
+        self.inboxes[to].push_back(InFlight {
+            from,
+            deliver_at,
+            msg,
+        });
+    }
+    /// Takes out of the inbox of the node all the messages which have already
+    /// arrived, in the order they were sent
+    pub fn deliver(&mut self, node: NodeId, now: usize) -> VecDeque<InFlight> {
+        let (arrived, travelling) = self.inboxes[node]
+            .drain(..)
+            .partition(|msg| msg.deliver_at <= now);
+        self.inboxes[node] = travelling;
+        arrived
+    }
+    /// Throws away everything waiting in the inbox of the node
+    pub fn clear(&mut self, node: NodeId) {
+        self.inboxes[node].clear();
+    }
+    /// Throws away all the messages the node has sent which have not been
+    /// delivered yet
+    pub fn drop_from(&mut self, from: NodeId) {
+        for inbox in &mut self.inboxes {
+            inbox.retain(|msg| msg.from != from);
+        }
+    }
+    /// All the messages which have not been delivered yet with their receivers
+    pub fn in_flight(&self) -> impl Iterator<Item = (NodeId, &InFlight)> {
+        self.inboxes
+            .iter()
+            .enumerate()
+            .flat_map(|(to, inbox)| inbox.iter().map(move |msg| (to, msg)))
+    }
+    pub fn is_empty(&self) -> bool {
+        self.inboxes.iter().all(VecDeque::is_empty)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system::Token;
+
+    fn channels(nodes: usize, default_delay: usize) -> Channels {
+        let mut channels = Channels::new(default_delay);
+        for _ in 0..nodes {
+            channels.add_node();
+        }
+        channels
+    }
+
+    fn senders(arrived: VecDeque<InFlight>) -> Vec<NodeId> {
+        arrived.into_iter().map(|msg| msg.from).collect()
+    }
+
+    #[test]
+    fn links_are_symmetric() {
+        let mut channels = channels(3, 1);
+        channels.set_delay(2, 0, 4);
+        assert_eq!(channels.delay(0, 2), 4);
+        assert_eq!(channels.delay(2, 0), 4);
+        assert_eq!(channels.delay(0, 1), 1);
+    }
+
+    #[test]
+    fn messages_wait_for_their_delay() {
+        let mut channels = channels(3, 1);
+        channels.set_delay(0, 2, 3);
+        channels.send(0, 2, SystemMsg::Probe { epoch: 0 }, 10);
+        channels.send(1, 2, SystemMsg::Probe { epoch: 0 }, 10);
+
+        assert!(channels.deliver(2, 10).is_empty());
+        assert_eq!(senders(channels.deliver(2, 11)), vec![1]);
+        assert!(!channels.is_empty());
+        assert!(channels.deliver(2, 12).is_empty());
+        assert_eq!(senders(channels.deliver(2, 13)), vec![0]);
+        assert!(channels.is_empty());
+    }
+
+    #[test]
+    fn arrived_messages_keep_the_order_they_were_sent_in() {
+        let mut channels = channels(2, 0);
+        for now in 0..3 {
+            channels.send(1, 0, SystemMsg::Probe { epoch: 0 }, now);
+        }
+        let arrived = channels.deliver(0, 5);
+        assert_eq!(
+            arrived.iter().map(|msg| msg.deliver_at).collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+    }
+
+    #[test]
+    fn requests_take_their_own_delay_but_do_not_overtake() {
+        let mut channels = channels(3, 3);
+        channels.set_request_delay(1);
+        let request = || SystemMsg::Request {
+            origin: 0,
+            epoch: 0,
+            priority: 0,
+            raise: false,
+        };
+        channels.send(0, 1, SystemMsg::Token(Token::default()), 0);
+        channels.send(0, 1, request(), 0);
+        channels.send(2, 1, request(), 0);
+
+        assert_eq!(senders(channels.deliver(1, 1)), vec![2]);
+        let arrived = channels.deliver(1, 3);
+        assert!(matches!(
+            arrived.iter().map(|msg| &msg.msg).collect::<Vec<_>>()[..],
+            [SystemMsg::Token(_), SystemMsg::Request { .. }]
+        ));
+    }
+}
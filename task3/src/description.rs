@@ -0,0 +1,786 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt::Display,
+};
+
+use crate::{
+    spanning_tree::{InputGraph, SpanningTree},
+    system::{Instruction, System, SystemNodeData, Token},
+};
+
+/// A problem with the structure of the described system which makes it
+/// impossible to run the algorithm on it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TopologyError {
+    Empty,
+    DuplicateName(String),
+    UnknownParent {
+        node: String,
+        parent: String,
+    },
+    NoRoot,
+    MultipleRoots(Vec<String>),
+    /// The names of the nodes on the cycle, the first one repeated at the end
+    Cycle(Vec<String>),
+    Unreachable {
+        root: String,
+        nodes: Vec<String>,
+    },
+    /// A crash of a node which is not in the system
+    UnknownCrash(String),
+    UnknownRoot(String),
+    /// A described node missing in the graph
+    NotInGraph(String),
+    /// A node with a parent while the tree is built from the graph
+    ParentInGraph(String),
+    /// The root can only be chosen if the tree is built from a graph
+    RootWithoutGraph(String),
+    NoTokens,
+    /// The holders have to be given for all the tokens or for none
+    HolderCount {
+        tokens: usize,
+        holders: usize,
+    },
+    UnknownHolder(String),
+}
+
+impl Display for TopologyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TopologyError::Empty => write!(f, "the system has no nodes"),
+            TopologyError::DuplicateName(name) => write!(f, "node {name} is declared twice"),
+            TopologyError::UnknownParent { node, parent } => {
+                write!(f, "node {node} has an unknown parent {parent}")
+            }
+            TopologyError::NoRoot => write!(f, "no node is a root (every node has a parent)"),
+            TopologyError::MultipleRoots(roots) => {
+                write!(f, "multiple roots: {}", roots.join(", "))
+            }
+            TopologyError::Cycle(nodes) => write!(f, "cycle {}", nodes.join("→")),
+            TopologyError::Unreachable { root, nodes } => write!(
+                f,
+                "nodes {} are not connected to the root {root}",
+                nodes.join(", ")
+            ),
+            TopologyError::UnknownCrash(node) => {
+                write!(f, "an unknown node {node} is supposed to crash")
+            }
+            TopologyError::UnknownRoot(node) => write!(f, "the root {node} is not in the graph"),
+            TopologyError::NotInGraph(node) => write!(f, "node {node} is not in the graph"),
+            TopologyError::ParentInGraph(node) => write!(
+                f,
+                "node {node} has a parent but the tree is built from the graph"
+            ),
+            TopologyError::RootWithoutGraph(node) => write!(
+                f,
+                "the root {node} can be chosen only if the tree is built from a graph"
+            ),
+            TopologyError::NoTokens => write!(f, "the system needs at least one token"),
+            TopologyError::HolderCount { tokens, holders } => write!(
+                f,
+                "{holders} holders are given for {tokens} tokens, one per token is needed"
+            ),
+            TopologyError::UnknownHolder(node) => {
+                write!(f, "an unknown node {node} is supposed to hold a token")
+            }
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct SystemNodeDescription {
+    #[serde(default)]
+    pub instructions: VecDeque<Instruction>,
+    pub parent: Option<String>,
+    /// Position of the node in each iteration of the simulation. The nodes
+    /// without it follow the ordered ones in the order they were declared in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub order: Option<usize>,
+    /// Delay, in iterations, of the link to the parent. The default one of
+    /// the system is used if not given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delay: Option<usize>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct SystemDescription {
+    /// In the order of declaration in the file
+    #[serde(default, with = "declaration_order")]
+    nodes: Vec<(String, SystemNodeDescription)>,
+    /// The network the tree is built from instead of the parents of the
+    /// nodes (see [`SystemDescription::span_graph`])
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    graph: Option<InputGraph>,
+    /// The root of the tree built from the graph, the first node of the
+    /// graph if not given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    root: Option<String>,
+    /// How many iterations it takes for a message to get to a neighbour
+    #[serde(default = "default_delay")]
+    delay: usize,
+    /// How many iterations the requests take, on every link, the delays of
+    /// the links are used if not given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    request_delay: Option<usize>,
+    /// Nodes which stop working during the simulation
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    crashes: Vec<CrashDescription>,
+    /// How many iterations it takes the neighbours of a crashed node to notice
+    /// the crash
+    #[serde(default = "default_detection_delay")]
+    detection_delay: usize,
+    /// How many nodes can be in the critical section at once, only in
+    /// Raymond's algorithm
+    #[serde(default = "default_tokens")]
+    tokens: usize,
+    /// The node holding each token initially, a node can hold several. All
+    /// the tokens start at the root if not given.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    holders: Vec<String>,
+}
+
+/// The node stops working at the beginning of the iteration `at`, its
+/// messages in flight are lost
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct CrashDescription {
+    pub node: String,
+    pub at: usize,
+}
+
+fn default_delay() -> usize {
+    1
+}
+
+fn default_detection_delay() -> usize {
+    2
+}
+
+fn default_tokens() -> usize {
+    1
+}
+
+/// (De)serializes a JSON object as a list of its entries keeping the order in
+/// which they appear in the file, which a `HashMap` would lose
+mod declaration_order {
+    use serde::{
+        de::{MapAccess, Visitor},
+        ser::SerializeMap,
+        Deserialize, Deserializer, Serialize, Serializer,
+    };
+
+    pub fn serialize<S, V>(entries: &[(String, V)], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+        V: Serialize,
+    {
+        let mut map = serializer.serialize_map(Some(entries.len()))?;
+        for (key, value) in entries {
+            map.serialize_entry(key, value)?;
+        }
+        map.end()
+    }
+
+    pub fn deserialize<'de, D, V>(deserializer: D) -> Result<Vec<(String, V)>, D::Error>
+    where
+        D: Deserializer<'de>,
+        V: Deserialize<'de>,
+    {
+        struct EntriesVisitor<V>(std::marker::PhantomData<V>);
+
+        impl<'de, V: Deserialize<'de>> Visitor<'de> for EntriesVisitor<V> {
+            type Value = Vec<(String, V)>;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "a map of nodes")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let mut entries = Vec::new();
+                while let Some(entry) = map.next_entry()? {
+                    entries.push(entry);
+                }
+                Ok(entries)
+            }
+        }
+
+        deserializer.deserialize_map(EntriesVisitor(std::marker::PhantomData))
+    }
+}
+
+/// The node at the end of the chain of parents, if the chain ends at all on a
+/// known node
+fn root_of<'a>(
+    nodes: &HashMap<&'a String, &'a SystemNodeDescription>,
+    mut name: &'a String,
+) -> Option<&'a String> {
+    for _ in 0..=nodes.len() {
+        match &nodes.get(name)?.parent {
+            Some(parent) => name = parent,
+            None => return Some(name),
+        }
+    }
+    None
+}
+
+impl SystemDescription {
+    pub fn with_root(mut self, root: String) -> Self {
+        self.root = Some(root);
+        self
+    }
+
+    /// Builds the tree out of the graph, if there is one, with the echo
+    /// algorithm started at the root and fills in the parents of the nodes.
+    /// The nodes of the graph which are not described get no instructions.
+    pub fn span_graph(&mut self) -> Result<Option<SpanningTree>, Vec<TopologyError>> {
+        let Some(graph) = &self.graph else {
+            return match &self.root {
+                Some(root) => Err(vec![TopologyError::RootWithoutGraph(root.clone())]),
+                None => Ok(None),
+            };
+        };
+        let names = graph.names();
+        let mut problems = Vec::new();
+        let mut indices = HashMap::new();
+        for (idx, name) in names.iter().enumerate() {
+            if indices.insert(name, idx).is_some() {
+                problems.push(TopologyError::DuplicateName(name.clone()));
+            }
+        }
+        for (name, node) in &self.nodes {
+            if !indices.contains_key(name) {
+                problems.push(TopologyError::NotInGraph(name.clone()));
+            } else if node.parent.is_some() {
+                problems.push(TopologyError::ParentInGraph(name.clone()));
+            }
+        }
+        let root = match &self.root {
+            Some(root) => indices
+                .get(root)
+                .copied()
+                .ok_or(TopologyError::UnknownRoot(root.clone())),
+            None if names.is_empty() => Err(TopologyError::Empty),
+            None => Ok(0),
+        };
+        let root = match root {
+            Ok(root) if problems.is_empty() => root,
+            Ok(_) => return Err(problems),
+            Err(problem) => {
+                problems.push(problem);
+                return Err(problems);
+            }
+        };
+
+        let tree = SpanningTree::echo(&graph.neighbours(), root);
+        let mut unreached = tree
+            .unreached()
+            .into_iter()
+            .map(|node| names[node].clone())
+            .collect::<Vec<_>>();
+        if !unreached.is_empty() {
+            unreached.sort();
+            return Err(vec![TopologyError::Unreachable {
+                root: names[root].clone(),
+                nodes: unreached,
+            }]);
+        }
+
+        for (idx, name) in names.iter().enumerate() {
+            let parent = tree.parents[idx].map(|parent| names[parent].clone());
+            match self
+                .nodes
+                .iter_mut()
+                .find(|(described, _)| described == name)
+            {
+                Some((_, node)) => node.parent = parent,
+                None => self.nodes.push((
+                    name.clone(),
+                    SystemNodeDescription {
+                        instructions: VecDeque::new(),
+                        parent,
+                        order: None,
+                        delay: None,
+                    },
+                )),
+            }
+        }
+        Ok(Some(tree))
+    }
+
+    /// Checks whether the parent links form a single tree. All the problems
+    /// found are reported at once, sorted by the names of the nodes involved.
+    pub fn validate(&self) -> Result<(), Vec<TopologyError>> {
+        let mut problems = Vec::new();
+        let mut nodes = HashMap::new();
+        for (name, node) in &self.nodes {
+            if nodes.insert(name, node).is_some() {
+                problems.push(TopologyError::DuplicateName(name.clone()));
+            }
+        }
+        let mut names = nodes.keys().copied().collect::<Vec<_>>();
+        names.sort();
+
+        if names.is_empty() {
+            return Err(vec![TopologyError::Empty]);
+        }
+
+        for name in &names {
+            if let Some(parent) = &nodes[*name].parent {
+                if !nodes.contains_key(parent) {
+                    problems.push(TopologyError::UnknownParent {
+                        node: name.to_string(),
+                        parent: parent.clone(),
+                    });
+                }
+            }
+        }
+
+        let roots = names
+            .iter()
+            .filter(|name| nodes[**name].parent.is_none())
+            .map(|name| name.to_string())
+            .collect::<Vec<_>>();
+        match roots.len() {
+            0 => problems.push(TopologyError::NoRoot),
+            1 => {}
+            _ => problems.push(TopologyError::MultipleRoots(roots.clone())),
+        }
+
+        // follow the parent links from every node, a link leading back to a
+        // node visited during the same walk closes a cycle
+        let mut in_cycle = HashSet::new();
+        let mut visited = HashSet::new();
+        for name in &names {
+            let mut path: Vec<&String> = Vec::new();
+            let mut current = Some(*name);
+            while let Some(node) = current {
+                if let Some(pos) = path.iter().position(|visited| *visited == node) {
+                    let mut cycle = path[pos..]
+                        .iter()
+                        .map(|name| name.to_string())
+                        .collect::<Vec<_>>();
+                    in_cycle.extend(cycle.iter().cloned());
+                    cycle.push(node.clone());
+                    problems.push(TopologyError::Cycle(cycle));
+                    break;
+                }
+                if !visited.insert(node) {
+                    break;
+                }
+                path.push(node);
+                current = nodes.get(node).and_then(|n| n.parent.as_ref());
+            }
+        }
+
+        // with exactly one root every other node has to lead to it
+        if let [root] = roots.as_slice() {
+            let unreachable = names
+                .iter()
+                .filter(|name| !in_cycle.contains(**name))
+                .filter(|name| root_of(&nodes, name).map(String::as_str) != Some(root.as_str()))
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>();
+            if !unreachable.is_empty() {
+                problems.push(TopologyError::Unreachable {
+                    root: root.clone(),
+                    nodes: unreachable,
+                });
+            }
+        }
+
+        let mut crashing = self
+            .crashes
+            .iter()
+            .filter(|crash| !nodes.contains_key(&crash.node))
+            .map(|crash| crash.node.clone())
+            .collect::<Vec<_>>();
+        crashing.sort();
+        crashing.dedup();
+        problems.extend(crashing.into_iter().map(TopologyError::UnknownCrash));
+
+        if self.tokens == 0 {
+            problems.push(TopologyError::NoTokens);
+        } else if !self.holders.is_empty() && self.holders.len() != self.tokens {
+            problems.push(TopologyError::HolderCount {
+                tokens: self.tokens,
+                holders: self.holders.len(),
+            });
+        }
+        let mut holders = self
+            .holders
+            .iter()
+            .filter(|holder| !nodes.contains_key(holder))
+            .cloned()
+            .collect::<Vec<_>>();
+        holders.sort();
+        holders.dedup();
+        problems.extend(holders.into_iter().map(TopologyError::UnknownHolder));
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+
+    pub fn has_crashes(&self) -> bool {
+        !self.crashes.is_empty()
+    }
+
+    pub fn tokens(&self) -> usize {
+        self.tokens
+    }
+
+    /// Produces the system of nodes and takes the execution duration out of
+    /// the description. The description has to be valid (see
+    /// [`SystemDescription::validate`]). The nodes are added to the system
+    /// (and later iterated over) sorted by their `order` and then by the order
+    /// of declaration.
+    ///
+    /// With the holders given every node points at the neighbour on the
+    /// shortest path to the closest of them, so the holders are the roots of
+    /// a forest.
+    pub fn build_system(self) -> System {
+        let mut system = System::new(self.delay).with_detection_delay(self.detection_delay);
+        if let Some(delay) = self.request_delay {
+            system.channels.set_request_delay(delay);
+        }
+        let mut parents = Vec::new();
+        let mut ids = HashMap::new();
+
+        let mut nodes = self.nodes;
+        // stable, so the declaration order is kept among equal keys
+        nodes.sort_by_key(|(_, data)| (data.order.is_none(), data.order));
+
+        for (name, data) in nodes {
+            let id = system.add_node(SystemNodeData::new(name.clone(), data.instructions));
+            ids.insert(name, id);
+            parents.push((id, data.parent, data.delay));
+        }
+
+        for (id, maybe_parent, delay) in parents {
+            match maybe_parent {
+                Some(parent) => {
+                    let parent = *ids
+                        .get(&parent)
+                        .expect("the description should be validated before building");
+                    system.tree.make_child_of(id, parent);
+                    system.connect(id, parent);
+                    if let Some(delay) = delay {
+                        system.channels.set_delay(id, parent, delay);
+                    }
+                }
+                None if self.holders.is_empty() => {
+                    let tokens = &mut system.tree.data_mut(id).tokens;
+                    tokens.extend((0..self.tokens).map(|_| Token::default()));
+                }
+                None => {}
+            }
+        }
+
+        if !self.holders.is_empty() {
+            let mut queue = VecDeque::new();
+            for holder in &self.holders {
+                let id = ids[holder];
+                system.tree.data_mut(id).tokens.push(Token::default());
+                if !queue.contains(&id) {
+                    system.tree.make_root(id);
+                    queue.push_back(id);
+                }
+            }
+            let mut reached = queue.iter().copied().collect::<HashSet<_>>();
+            while let Some(node) = queue.pop_front() {
+                for neighbour in system.tree.data(node).neighbours.clone() {
+                    if reached.insert(neighbour) {
+                        system.tree.make_child_of(neighbour, node);
+                        queue.push_back(neighbour);
+                    }
+                }
+            }
+        }
+
+        for crash in self.crashes {
+            system.schedule_crash(ids[&crash.node], crash.at);
+        }
+
+        system
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn description(nodes: &[(&str, Option<&str>)]) -> SystemDescription {
+        SystemDescription {
+            nodes: nodes
+                .iter()
+                .map(|(name, parent)| {
+                    let node = SystemNodeDescription {
+                        instructions: VecDeque::new(),
+                        parent: parent.map(str::to_string),
+                        order: None,
+                        delay: None,
+                    };
+                    (name.to_string(), node)
+                })
+                .collect(),
+            graph: None,
+            root: None,
+            delay: 1,
+            request_delay: None,
+            crashes: Vec::new(),
+            detection_delay: default_detection_delay(),
+            tokens: 1,
+            holders: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn single_tree_is_valid() {
+        let description = description(&[("a", None), ("b", Some("a")), ("c", Some("b"))]);
+        assert_eq!(description.validate(), Ok(()));
+
+        let system = description.build_system();
+        let holders = system
+            .ids()
+            .filter(|&node| system.tree.data(node).tokens.len() == 1)
+            .map(|node| system.id(node))
+            .collect::<Vec<_>>();
+        assert_eq!(holders, vec!["a"]);
+    }
+
+    #[test]
+    fn roots_are_counted() {
+        assert_eq!(description(&[]).validate(), Err(vec![TopologyError::Empty]));
+        assert_eq!(
+            description(&[("a", None), ("b", None)]).validate(),
+            Err(vec![TopologyError::MultipleRoots(vec![
+                "a".to_string(),
+                "b".to_string()
+            ])])
+        );
+    }
+
+    #[test]
+    fn unknown_parent_leaves_the_node_unreachable() {
+        let problems = description(&[("a", None), ("b", Some("x"))])
+            .validate()
+            .unwrap_err();
+        assert_eq!(
+            problems,
+            vec![
+                TopologyError::UnknownParent {
+                    node: "b".to_string(),
+                    parent: "x".to_string()
+                },
+                TopologyError::Unreachable {
+                    root: "a".to_string(),
+                    nodes: vec!["b".to_string()]
+                },
+            ]
+        );
+        assert_eq!(problems[0].to_string(), "node b has an unknown parent x");
+    }
+
+    #[test]
+    fn cycles_are_reported_once() {
+        let problems = description(&[("a", Some("b")), ("b", Some("a"))])
+            .validate()
+            .unwrap_err();
+        assert_eq!(
+            problems,
+            vec![
+                TopologyError::NoRoot,
+                TopologyError::Cycle(vec!["a".to_string(), "b".to_string(), "a".to_string()]),
+            ]
+        );
+
+        // the nodes of the cycle are not reported as unreachable on top of it
+        let problems = description(&[("a", None), ("b", Some("c")), ("c", Some("b"))])
+            .validate()
+            .unwrap_err();
+        assert_eq!(
+            problems,
+            vec![TopologyError::Cycle(vec![
+                "b".to_string(),
+                "c".to_string(),
+                "b".to_string()
+            ])]
+        );
+        assert_eq!(problems[0].to_string(), "cycle b→c→b");
+    }
+
+    #[test]
+    fn nodes_keep_the_order_of_declaration() {
+        let description: SystemDescription = serde_json::from_str(
+            r#"{"nodes": {
+                "c": {"instructions": [], "parent": null},
+                "a": {"instructions": [], "parent": "c"},
+                "d": {"instructions": [], "parent": "c", "order": 1},
+                "b": {"instructions": [], "parent": "c", "order": 0}
+            }}"#,
+        )
+        .unwrap();
+        assert_eq!(description.validate(), Ok(()));
+
+        // the explicitly ordered ones first, then the others as declared
+        let system = description.build_system();
+        let names = system.ids().map(|node| system.id(node)).collect::<Vec<_>>();
+        assert_eq!(names, vec!["b", "d", "c", "a"]);
+    }
+
+    #[test]
+    fn duplicate_names_are_reported() {
+        let problems = description(&[("a", None), ("b", Some("a")), ("b", Some("a"))])
+            .validate()
+            .unwrap_err();
+        assert_eq!(
+            problems,
+            vec![TopologyError::DuplicateName("b".to_string())]
+        );
+    }
+
+    #[test]
+    fn links_take_their_delays_from_the_description() {
+        let description: SystemDescription = serde_json::from_str(
+            r#"{"delay": 2, "nodes": {
+                "a": {"instructions": [], "parent": null},
+                "b": {"instructions": [], "parent": "a", "delay": 5},
+                "c": {"instructions": [], "parent": "a"}
+            }}"#,
+        )
+        .unwrap();
+        let system = description.build_system();
+        assert_eq!(system.channels.delay(1, 0), 5);
+        assert_eq!(system.channels.delay(0, 2), 2);
+    }
+
+    #[test]
+    fn crashes_of_unknown_nodes_are_reported() {
+        let mut description = description(&[("a", None), ("b", Some("a"))]);
+        description.crashes = ["b", "x", "x"]
+            .map(|node| CrashDescription {
+                node: node.to_string(),
+                at: 3,
+            })
+            .into();
+        assert_eq!(
+            description.validate().unwrap_err(),
+            vec![TopologyError::UnknownCrash("x".to_string())]
+        );
+    }
+
+    #[test]
+    fn holders_have_to_match_the_tokens() {
+        let mut description = description(&[("a", None), ("b", Some("a"))]);
+        description.tokens = 0;
+        assert_eq!(description.validate(), Err(vec![TopologyError::NoTokens]));
+        description.tokens = 2;
+        assert_eq!(description.validate(), Ok(()));
+        description.holders = vec!["b".to_string(), "x".to_string(), "x".to_string()];
+        assert_eq!(
+            description.validate().unwrap_err(),
+            vec![
+                TopologyError::HolderCount {
+                    tokens: 2,
+                    holders: 3
+                },
+                TopologyError::UnknownHolder("x".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn tokens_start_at_the_root_or_at_their_holders() {
+        let chain = [
+            ("a", None),
+            ("b", Some("a")),
+            ("c", Some("b")),
+            ("d", Some("c")),
+        ];
+        let mut at_root = description(&chain);
+        at_root.tokens = 2;
+        let system = at_root.build_system();
+        assert_eq!(system.tree.data(0).tokens.len(), 2);
+
+        let mut held = description(&chain);
+        held.tokens = 3;
+        held.holders = ["a", "d", "d"].map(str::to_string).into();
+        let system = held.build_system();
+        let tokens = system
+            .ids()
+            .map(|node| system.tree.data(node).tokens.len())
+            .collect::<Vec<_>>();
+        assert_eq!(tokens, [1, 0, 0, 2]);
+        // every node points toward the closest holder
+        let parents = system
+            .ids()
+            .map(|node| system.tree.parent(node))
+            .collect::<Vec<_>>();
+        assert_eq!(parents, [None, Some(0), Some(3), None]);
+    }
+
+    #[test]
+    fn neighbours_follow_the_edges_of_the_tree() {
+        let system = description(&[("a", None), ("b", Some("a")), ("c", Some("a"))]).build_system();
+        assert_eq!(system.tree.data(0).neighbours, [1, 2]);
+        assert_eq!(system.tree.data(1).neighbours, [0]);
+        assert_eq!(system.tree.data(2).neighbours, [0]);
+    }
+
+    /// A triangle with a tail: a - b - c - a, c - d
+    fn graph(extra: &str) -> SystemDescription {
+        serde_json::from_str(&format!(
+            r#"{{"graph": {{
+                "nodes": ["a", "b", "c", "d"],
+                "node_holes": [],
+                "edge_property": "directed",
+                "edges": [[0, 1, null], [1, 2, null], [2, 0, null], [2, 3, null]]
+            }}{extra}}}"#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn tree_is_spanned_from_the_chosen_root() {
+        let mut description = graph(
+            r#", "root": "c", "nodes": {"b": {"instructions": [{"kind": "idle", "duration": 1}]}}"#,
+        );
+        description.span_graph().unwrap().unwrap();
+        let parents = description
+            .nodes
+            .iter()
+            .map(|(name, node)| (name.as_str(), node.parent.as_deref()))
+            .collect::<HashMap<_, _>>();
+        assert_eq!(parents["c"], None);
+        assert_eq!(parents["a"], Some("c"));
+        assert_eq!(parents["b"], Some("c"));
+        assert_eq!(parents["d"], Some("c"));
+        // the described node is kept with its instructions
+        assert_eq!(description.nodes[0].0, "b");
+        assert_eq!(description.nodes[0].1.instructions.len(), 1);
+        assert!(description.validate().is_ok());
+    }
+
+    #[test]
+    fn graph_problems_are_reported() {
+        let mut described = graph(r#", "nodes": {"a": {"parent": "b"}, "x": {}}"#);
+        assert_eq!(
+            described.span_graph().unwrap_err(),
+            vec![
+                TopologyError::ParentInGraph("a".to_string()),
+                TopologyError::NotInGraph("x".to_string()),
+            ]
+        );
+        let mut described = graph(r#", "root": "x""#);
+        assert_eq!(
+            described.span_graph().unwrap_err(),
+            vec![TopologyError::UnknownRoot("x".to_string())]
+        );
+        let mut described = description(&[("a", None)]).with_root("a".to_string());
+        assert_eq!(
+            described.span_graph().unwrap_err(),
+            vec![TopologyError::RootWithoutGraph("a".to_string())]
+        );
+    }
+}
@@ -0,0 +1,419 @@
+use std::{collections::HashMap, fmt::Write};
+
+use crate::system::System;
+
+/// The state of a node at the end of an iteration
+#[derive(Debug, Clone)]
+pub struct FrameNode {
+    pub name: String,
+    /// Index of the parent in [`Frame::nodes`]
+    pub parent: Option<usize>,
+    pub token: bool,
+    pub in_critical_section: bool,
+    pub queue: Vec<String>,
+    pub crashed: bool,
+}
+
+/// A snapshot of the holder pointers, the token and the request queues taken
+/// at the end of an iteration
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub iteration: usize,
+    pub nodes: Vec<FrameNode>,
+    /// Messages on their way: sender, receiver and the kind of the message
+    pub in_flight: Vec<(usize, usize, &'static str)>,
+}
+
+impl Frame {
+    pub fn capture(system: &System, iteration: usize) -> Self {
+        let nodes = system
+            .ids()
+            .map(|node| {
+                let node_data = system.tree.data(node);
+                FrameNode {
+                    name: node_data.id.clone(),
+                    parent: system.tree.parent(node),
+                    token: !node_data.tokens.is_empty() && !node_data.crashed,
+                    in_critical_section: node_data.in_critical_section,
+                    queue: system
+                        .queue_status(node)
+                        .into_iter()
+                        .map(str::to_string)
+                        .collect(),
+                    crashed: node_data.crashed,
+                }
+            })
+            .collect();
+        let in_flight = system
+            .channels
+            .in_flight()
+            .map(|(to, msg)| (msg.from, to, msg.msg.kind()))
+            .collect();
+
+        Self {
+            iteration,
+            nodes,
+            in_flight,
+        }
+    }
+
+    /// The frame as a Graphviz graph. Solid edges are the parent pointers,
+    /// dashed ones the messages in flight.
+    pub fn to_dot(&self) -> String {
+        let escape = |text: &str| text.replace('\\', "\\\\").replace('"', "\\\"");
+        let quote = |text: &str| format!("\"{}\"", escape(text));
+
+        let mut dot = String::new();
+        let _ = writeln!(dot, "digraph iteration_{} {{", self.iteration);
+        let _ = writeln!(
+            dot,
+            "    label={};",
+            quote(&format!("Iteration {}", self.iteration))
+        );
+        let _ = writeln!(dot, "    node [shape=box, style=filled, fillcolor=white];");
+        for node in &self.nodes {
+            let fill = match (node.crashed, node.token) {
+                (true, _) => "gray",
+                (_, true) => "gold",
+                _ => "white",
+            };
+            let border = if node.in_critical_section {
+                "red"
+            } else {
+                "black"
+            };
+            // `\n` is a line break in the labels of Graphviz
+            let _ = writeln!(
+                dot,
+                "    {} [label=\"{}\\nqueue: [{}]\", fillcolor={fill}, color={border}];",
+                quote(&node.name),
+                escape(&node.name),
+                escape(&node.queue.join(", "))
+            );
+        }
+        for node in &self.nodes {
+            if let Some(parent) = node.parent {
+                let _ = writeln!(
+                    dot,
+                    "    {} -> {};",
+                    quote(&node.name),
+                    quote(&self.nodes[parent].name)
+                );
+            }
+        }
+        for (from, to, kind) in &self.in_flight {
+            let color = if *kind == "token" { "red" } else { "blue" };
+            let _ = writeln!(
+                dot,
+                "    {} -> {} [style=dashed, color={color}, label={kind}, constraint=false];",
+                quote(&self.nodes[*from].name),
+                quote(&self.nodes[*to].name)
+            );
+        }
+        let _ = writeln!(dot, "}}");
+        dot
+    }
+}
+
+const NODE_WIDTH: f64 = 90.0;
+const NODE_HEIGHT: f64 = 30.0;
+const LEVEL_HEIGHT: f64 = 110.0;
+const MARGIN: f64 = 40.0;
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Positions of the nodes, taken from the tree of the first frame so that
+/// the nodes stay in place while the edges between them change
+fn layout(frame: &Frame) -> Vec<(f64, f64)> {
+    let mut children = HashMap::<usize, Vec<usize>>::new();
+    let mut roots = Vec::new();
+    for (idx, node) in frame.nodes.iter().enumerate() {
+        match node.parent {
+            Some(parent) => children.entry(parent).or_default().push(idx),
+            None => roots.push(idx),
+        }
+    }
+
+    // leaves get consecutive columns, a parent is centered over its children
+    fn place(
+        node: usize,
+        depth: usize,
+        children: &HashMap<usize, Vec<usize>>,
+        next_column: &mut f64,
+        positions: &mut Vec<(f64, f64)>,
+    ) -> f64 {
+        let column = match children.get(&node) {
+            Some(kids) => {
+                let columns = kids
+                    .iter()
+                    .map(|kid| place(*kid, depth + 1, children, next_column, positions))
+                    .collect::<Vec<_>>();
+                columns.iter().sum::<f64>() / columns.len() as f64
+            }
+            None => {
+                *next_column += 1.0;
+                *next_column - 1.0
+            }
+        };
+        positions[node] = (
+            MARGIN + column * (NODE_WIDTH + 30.0) + NODE_WIDTH / 2.0,
+            MARGIN + depth as f64 * LEVEL_HEIGHT + NODE_HEIGHT / 2.0,
+        );
+        column
+    }
+
+    let mut positions = vec![(0.0, 0.0); frame.nodes.len()];
+    let mut next_column = 0.0;
+    for root in roots {
+        place(root, 0, &children, &mut next_column, &mut positions);
+    }
+    positions
+}
+
+fn render_svg(frame: &Frame, positions: &[(f64, f64)], width: f64, height: f64) -> String {
+    let mut svg = String::new();
+    let _ = writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" font-family="monospace" font-size="12">"#
+    );
+    let _ = writeln!(
+        svg,
+        r##"<defs><marker id="arrow" viewBox="0 0 10 10" refX="10" refY="5" markerWidth="6" markerHeight="6" orient="auto"><path d="M 0 0 L 10 5 L 0 10 z" fill="#444"/></marker></defs>"##
+    );
+
+    // shortens the line so that the arrow ends at the border of the box and
+    // moves it aside so that the edges going both ways do not overlap
+    let edge = |from: usize, to: usize, aside: f64| {
+        let ((x1, y1), (x2, y2)) = (positions[from], positions[to]);
+        let (dx, dy) = (x2 - x1, y2 - y1);
+        let len = (dx * dx + dy * dy).sqrt().max(1.0);
+        let (ux, uy) = (dx / len, dy / len);
+        let cut = f64::min(
+            NODE_WIDTH / 2.0 / ux.abs().max(f64::EPSILON),
+            NODE_HEIGHT / 2.0 / uy.abs().max(f64::EPSILON),
+        ) + 3.0;
+        let (ox, oy) = (-uy * aside, ux * aside);
+        (
+            x1 + ux * cut + ox,
+            y1 + uy * cut + oy,
+            x2 - ux * cut + ox,
+            y2 - uy * cut + oy,
+        )
+    };
+
+    for (idx, node) in frame.nodes.iter().enumerate() {
+        if let Some(parent) = node.parent {
+            let (x1, y1, x2, y2) = edge(idx, parent, 4.0);
+            let _ = writeln!(
+                svg,
+                r##"<line x1="{x1:.1}" y1="{y1:.1}" x2="{x2:.1}" y2="{y2:.1}" stroke="#444" stroke-width="1.5" marker-end="url(#arrow)"/>"##
+            );
+        }
+    }
+    for (from, to, kind) in &frame.in_flight {
+        let (color, aside) = if *kind == "token" {
+            ("#d62728", 12.0)
+        } else {
+            ("#1f77b4", 18.0)
+        };
+        let (x1, y1, x2, y2) = edge(*from, *to, aside);
+        let _ = writeln!(
+            svg,
+            r#"<line x1="{x1:.1}" y1="{y1:.1}" x2="{x2:.1}" y2="{y2:.1}" stroke="{color}" stroke-width="2" stroke-dasharray="5,4"/>"#
+        );
+        let _ = writeln!(
+            svg,
+            r#"<text x="{:.1}" y="{:.1}" text-anchor="middle" font-size="10" fill="{color}">{kind}</text>"#,
+            (x1 + x2) / 2.0,
+            (y1 + y2) / 2.0
+        );
+    }
+    for (idx, node) in frame.nodes.iter().enumerate() {
+        let (x, y) = positions[idx];
+        let fill = match (node.crashed, node.token) {
+            (true, _) => "#a0a0a0",
+            (_, true) => "#ffd700",
+            _ => "#ffffff",
+        };
+        let stroke = if node.in_critical_section {
+            "#d62728"
+        } else {
+            "#000000"
+        };
+        let _ = writeln!(
+            svg,
+            r#"<rect x="{:.1}" y="{:.1}" width="{NODE_WIDTH}" height="{NODE_HEIGHT}" rx="6" fill="{fill}" stroke="{stroke}" stroke-width="2"/>"#,
+            x - NODE_WIDTH / 2.0,
+            y - NODE_HEIGHT / 2.0
+        );
+        let _ = writeln!(
+            svg,
+            r#"<text x="{x:.1}" y="{:.1}" text-anchor="middle">{}</text>"#,
+            y + 4.0,
+            escape(&node.name)
+        );
+        let _ = writeln!(
+            svg,
+            r##"<text x="{x:.1}" y="{:.1}" text-anchor="middle" font-size="10" fill="#555">[{}]</text>"##,
+            y + NODE_HEIGHT / 2.0 + 14.0,
+            escape(&node.queue.join(", "))
+        );
+    }
+    let _ = writeln!(svg, "</svg>");
+    svg
+}
+
+/// A page showing the frames one after another, with buttons and a slider to
+/// move between them. The token holder is drawn in gold, a crashed node in
+/// gray, a node in the critical section with a red border, the arrows are the
+/// parent pointers and the dashed lines the messages in flight labelled with
+/// their kind (red: the token, blue: the others).
+pub fn render_html(frames: &[Frame]) -> String {
+    let positions = frames.first().map(layout).unwrap_or_default();
+    let width = positions.iter().map(|(x, _)| *x).fold(0.0, f64::max) + NODE_WIDTH / 2.0 + MARGIN;
+    let height = positions.iter().map(|(_, y)| *y).fold(0.0, f64::max) + NODE_HEIGHT + MARGIN;
+
+    let mut html = String::new();
+    let _ = writeln!(
+        html,
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Holder tree</title>
+<style>
+body {{ font-family: monospace; }}
+.frame {{ display: none; }}
+.frame.shown {{ display: block; }}
+</style>
+</head>
+<body>
+<div>
+<button onclick="show(current - 1)">&lt;</button>
+<button onclick="toggle()" id="play">play</button>
+<button onclick="show(current + 1)">&gt;</button>
+<input type="range" id="slider" min="0" max="{}" value="0" oninput="show(+this.value)">
+<span id="label"></span>
+</div>
+<p>gold: token holder, gray: crashed, red border: in the critical section, arrows: parents,
+dashed: messages in flight (red: token, blue: the others), [queue]</p>"#,
+        frames.len().saturating_sub(1)
+    );
+    for frame in frames {
+        let _ = writeln!(
+            html,
+            r#"<div class="frame" data-label="Iteration {}">"#,
+            frame.iteration
+        );
+        html.push_str(&render_svg(frame, &positions, width, height));
+        let _ = writeln!(html, "</div>");
+    }
+    let _ = writeln!(
+        html,
+        r#"<script>
+const frames = document.querySelectorAll(".frame");
+let current = 0;
+let timer = null;
+function show(idx) {{
+    if (idx < 0 || idx >= frames.length) return;
+    frames[current].classList.remove("shown");
+    current = idx;
+    frames[current].classList.add("shown");
+    document.getElementById("slider").value = current;
+    document.getElementById("label").textContent = frames[current].dataset.label;
+}}
+function toggle() {{
+    if (timer) {{
+        clearInterval(timer);
+        timer = null;
+    }} else {{
+        timer = setInterval(() => current + 1 < frames.length ? show(current + 1) : toggle(), 700);
+    }}
+    document.getElementById("play").textContent = timer ? "pause" : "play";
+}}
+if (frames.length) {{
+    frames[0].classList.add("shown");
+    show(0);
+}}
+</script>
+</body>
+</html>"#
+    );
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::description::SystemDescription;
+
+    /// A holds the token, B is its child and has just asked for it
+    fn requested() -> System {
+        let description: SystemDescription = serde_json::from_str(
+            r#"{"nodes": {
+                "A": {"instructions": [], "parent": null},
+                "B": {"instructions": [{"kind": "critical_section", "duration": 2}], "parent": "A"}
+            }}"#,
+        )
+        .unwrap();
+        let mut system = description.build_system();
+        system.next_iteration();
+        for node in system.ids() {
+            system.iterate(node);
+        }
+        system
+    }
+
+    #[test]
+    fn frame_keeps_the_nodes_and_the_messages() {
+        let frame = Frame::capture(&requested(), 2);
+        assert_eq!(frame.iteration, 2);
+        assert_eq!(frame.nodes[0].name, "A");
+        assert!(frame.nodes[0].token);
+        assert_eq!(frame.nodes[1].parent, Some(0));
+        assert_eq!(frame.nodes[1].queue, ["B"]);
+        assert_eq!(frame.in_flight, [(1, 0, "request")]);
+    }
+
+    #[test]
+    fn dot_labels_the_messages_with_their_kind() {
+        let dot = Frame::capture(&requested(), 2).to_dot();
+        assert!(dot.starts_with("digraph iteration_2 {"));
+        assert!(dot.contains("\"A\" [label=\"A\\nqueue: []\", fillcolor=gold, color=black];"));
+        assert!(dot.contains("    \"B\" -> \"A\";"));
+        assert!(dot.contains(
+            "\"B\" -> \"A\" [style=dashed, color=blue, label=request, constraint=false];"
+        ));
+    }
+
+    #[test]
+    fn dot_escapes_the_names() {
+        let mut frame = Frame::capture(&requested(), 1);
+        frame.nodes[0].name = "say \"hi\"".to_string();
+        assert!(frame.to_dot().contains("\"say \\\"hi\\\"\" [label="));
+    }
+
+    #[test]
+    fn page_has_a_frame_per_iteration() {
+        let system = requested();
+        let frames = [Frame::capture(&system, 1), Frame::capture(&system, 2)];
+        let html = render_html(&frames);
+        assert_eq!(html.matches(r#"<div class="frame""#).count(), 2);
+        assert!(html.contains(r#"data-label="Iteration 2""#));
+        assert!(html.contains(r#"max="1""#));
+        assert!(html.contains(">request</text>"));
+    }
+
+    #[test]
+    fn children_are_laid_out_below_their_parents() {
+        let frame = Frame::capture(&requested(), 1);
+        let positions = layout(&frame);
+        assert_eq!(positions[0].0, positions[1].0);
+        assert!(positions[0].1 < positions[1].1);
+    }
+}
@@ -0,0 +1,401 @@
+use std::{collections::HashMap, fmt::Display};
+
+use crate::{
+    system::{Algorithm, System, SystemMsg, TaskKind},
+    trace::{Event, TraceRecord},
+    tree::NodeId,
+};
+
+/// Whether following the parents from the node reaches one of the holders
+fn leads_to(system: &System, mut node: NodeId, holders: &[NodeId]) -> bool {
+    for _ in system.ids() {
+        match system.tree.parent(node) {
+            Some(parent) if holders.contains(&parent) => return true,
+            Some(parent) => node = parent,
+            None => return false,
+        }
+    }
+    false
+}
+
+/// A property of Raymond's algorithm which does not hold in the system
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    /// There has to be exactly one token (or as many as the system was
+    /// given), held or in flight
+    TokenCount { count: usize, expected: usize },
+    /// The node holding the token is not the root of the tree
+    HolderNotRoot { holder: String },
+    /// Following the parents from the node does not lead to any token
+    LostPath { node: String, holders: Vec<String> },
+    /// More nodes in the critical section at once than there are tokens
+    MutualExclusion(Vec<String>),
+    /// A node in the critical section without the token
+    NoTokenInCs { node: String },
+    /// A node has been waiting for the critical section for too long
+    Starvation {
+        node: String,
+        since: usize,
+        bound: usize,
+    },
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Violation::TokenCount { count, expected } => {
+                write!(f, "there are {count} tokens in the system instead of {expected}")
+            }
+            Violation::HolderNotRoot { holder } => {
+                write!(f, "node {holder} holds the token but is not the root")
+            }
+            Violation::LostPath { node, holders } => write!(
+                f,
+                "the parent links from node {node} do not lead to a token (at {})",
+                holders.join(", ")
+            ),
+            Violation::MutualExclusion(nodes) => write!(
+                f,
+                "nodes {} are in the critical section at the same time, more than there are tokens",
+                nodes.join(", ")
+            ),
+            Violation::NoTokenInCs { node } => {
+                write!(f, "node {node} is in the critical section without the token")
+            }
+            Violation::Starvation { node, since, bound } => write!(
+                f,
+                "node {node} waits for the critical section since iteration {since}, longer than {bound} iterations"
+            ),
+        }
+    }
+}
+
+/// Checks the properties which have to hold after every iteration: a single
+/// token, holder pointers forming a tree directed toward it (only in
+/// Raymond's algorithm), mutual exclusion and that nobody waits for the
+/// critical section forever. The crashed nodes are left out.
+///
+/// With k tokens there have to be k of them, every node has to lead to one
+/// of the holders (a forest instead of a tree) and at most k nodes can be in
+/// the critical section.
+pub struct InvariantChecker {
+    /// How many iterations a node may wait for the critical section
+    service_bound: usize,
+    /// How many tokens the system has
+    tokens: usize,
+    /// Since when a node waits for the critical section
+    waiting_since: HashMap<NodeId, usize>,
+    /// The nodes in the critical section according to the events
+    in_cs: Vec<String>,
+}
+
+impl InvariantChecker {
+    pub fn new(service_bound: usize, tokens: usize) -> Self {
+        Self {
+            service_bound,
+            tokens,
+            waiting_since: HashMap::new(),
+            in_cs: Vec::new(),
+        }
+    }
+
+    /// A bound on the waiting time which cannot be exceeded if the algorithm
+    /// works: all the critical sections of the others might have to be
+    /// executed first and for every one of them the token might have to
+    /// travel through the whole tree, with the request going the other way.
+    pub fn default_bound(system: &System) -> usize {
+        let nodes = system.ids().len();
+        let max_delay = system
+            .ids()
+            .flat_map(|a| system.tree.parent(a).map(|b| system.channels.delay(a, b)))
+            .chain(system.channels.request_delay())
+            .max()
+            .unwrap_or(0);
+        let (cs_count, cs_duration) = system
+            .ids()
+            .flat_map(|node| system.tree.data(node).instructions.iter())
+            .filter(|instr| matches!(instr.kind, TaskKind::CriticalSection))
+            .fold((0, 0), |(count, duration), instr| {
+                (count + 1, duration + instr.duration)
+            });
+        let trip = 2 * nodes * (max_delay + 1);
+        cs_duration + (cs_count + 1) * trip
+    }
+
+    pub fn check(&mut self, system: &System) -> Result<(), Vec<Violation>> {
+        let mut violations = Vec::new();
+        let name = |node: NodeId| system.id(node).to_string();
+
+        let alive = |node: &NodeId| !system.tree.data(*node).crashed;
+        // the token might be lost until the recovery from a crash is over,
+        // but there must never be two of them
+        let recovering = system.is_recovering();
+
+        // where the tokens are: the holders or the receivers of the messages
+        // with them, an outdated token is going to be discarded by the
+        // receiver
+        let held = system
+            .ids()
+            .filter(alive)
+            .filter(|node| !system.tree.data(*node).tokens.is_empty())
+            .collect::<Vec<_>>();
+        let in_flight = system
+            .channels
+            .in_flight()
+            .filter(|(to, msg)| match &msg.msg {
+                SystemMsg::Token(token) => token.generation >= system.tree.data(*to).epoch,
+                _ => false,
+            })
+            .map(|(to, _)| to)
+            .collect::<Vec<_>>();
+
+        let count = held
+            .iter()
+            .map(|node| system.tree.data(*node).tokens.len())
+            .sum::<usize>()
+            + in_flight.len();
+
+        match count {
+            _ if recovering && count <= self.tokens => {}
+            // nobody left to hold it
+            0 if !system.ids().any(|node| alive(&node)) => {}
+            _ if count != self.tokens => violations.push(Violation::TokenCount {
+                count,
+                expected: self.tokens,
+            }),
+            // in Naimi–Trehel the pointers lead to the last requester instead
+            _ if system.algorithm() == Algorithm::NaimiTrehel => {}
+            _ => {
+                // the receiver of a token in flight still points at the
+                // sender until the token gets delivered
+                for holder in &held {
+                    if !in_flight.contains(holder) && system.tree.parent(*holder).is_some() {
+                        violations.push(Violation::HolderNotRoot {
+                            holder: name(*holder),
+                        });
+                    }
+                }
+                let holders = held.iter().chain(&in_flight).copied().collect::<Vec<_>>();
+                for node in system.ids().filter(alive) {
+                    if !holders.contains(&node) && !leads_to(system, node, &holders) {
+                        violations.push(Violation::LostPath {
+                            node: name(node),
+                            holders: holders.iter().map(|holder| name(*holder)).collect(),
+                        });
+                    }
+                }
+            }
+        }
+
+        let in_cs = system
+            .ids()
+            .filter(alive)
+            .filter(|node| system.tree.data(*node).in_critical_section)
+            .collect::<Vec<_>>();
+        if in_cs.len() > self.tokens {
+            violations.push(Violation::MutualExclusion(
+                in_cs.iter().map(|node| name(*node)).collect(),
+            ));
+        }
+        for node in &in_cs {
+            if system.tree.data(*node).tokens.is_empty() {
+                violations.push(Violation::NoTokenInCs { node: name(*node) });
+            }
+        }
+
+        // the waiting starts over after the recovery
+        if recovering {
+            self.waiting_since.clear();
+        }
+        for node in system.ids() {
+            let node_data = system.tree.data(node);
+            if node_data.self_req_issued
+                && !node_data.in_critical_section
+                && !node_data.crashed
+                && !recovering
+            {
+                let since = *self.waiting_since.entry(node).or_insert(system.now());
+                if system.now() - since > self.service_bound {
+                    violations.push(Violation::Starvation {
+                        node: name(node),
+                        since,
+                        bound: self.service_bound,
+                    });
+                }
+            } else {
+                self.waiting_since.remove(&node);
+            }
+        }
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(violations)
+        }
+    }
+
+    /// Checks mutual exclusion on the events as they come, when the state of
+    /// the whole system cannot be seen at once (with the threads). A node
+    /// records leaving the critical section before it passes the token on,
+    /// so the next node can enter only after that.
+    pub fn check_record(&mut self, record: &TraceRecord) -> Result<(), Violation> {
+        match record.event {
+            Event::CsEnter => {
+                self.in_cs.push(record.node.clone());
+                if self.in_cs.len() > self.tokens {
+                    return Err(Violation::MutualExclusion(self.in_cs.clone()));
+                }
+            }
+            Event::CsExit => self.in_cs.retain(|node| *node != record.node),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{description::SystemDescription, system::Token};
+
+    /// A chain A ← B ← C with the token at A
+    fn chain() -> System {
+        let description: SystemDescription = serde_json::from_str(
+            r#"{"nodes": {
+                "A": {"instructions": [], "parent": null},
+                "B": {"instructions": [{"kind": "critical_section", "duration": 2}], "parent": "A"},
+                "C": {"instructions": [{"kind": "critical_section", "duration": 3}], "parent": "B"}
+            }}"#,
+        )
+        .unwrap();
+        description.build_system()
+    }
+
+    fn check(system: &System) -> Result<(), Vec<Violation>> {
+        InvariantChecker::new(100, 1).check(system)
+    }
+
+    #[test]
+    fn built_system_is_consistent() {
+        assert_eq!(check(&chain()), Ok(()));
+    }
+
+    #[test]
+    fn second_token_is_found() {
+        let mut system = chain();
+        system.tree.data_mut(2).tokens.push(Token::default());
+        assert_eq!(
+            check(&system),
+            Err(vec![Violation::TokenCount {
+                count: 2,
+                expected: 1
+            }])
+        );
+    }
+
+    #[test]
+    fn holder_has_to_be_the_root_and_reachable() {
+        let mut system = chain();
+        system.tree.make_child_of(0, 1);
+        system.tree.make_root(2);
+        assert_eq!(
+            check(&system),
+            Err(vec![
+                Violation::HolderNotRoot {
+                    holder: "A".to_string()
+                },
+                Violation::LostPath {
+                    node: "C".to_string(),
+                    holders: vec!["A".to_string()]
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn critical_section_needs_the_token_and_to_be_alone() {
+        let mut system = chain();
+        system.tree.data_mut(0).in_critical_section = true;
+        system.tree.data_mut(2).in_critical_section = true;
+        assert_eq!(
+            check(&system),
+            Err(vec![
+                Violation::MutualExclusion(vec!["A".to_string(), "C".to_string()]),
+                Violation::NoTokenInCs {
+                    node: "C".to_string()
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn with_two_tokens_two_nodes_can_be_in_the_critical_section() {
+        let mut system = chain();
+        system.tree.make_root(2);
+        for node in [0, 2] {
+            let node_data = system.tree.data_mut(node);
+            node_data.tokens = vec![Token::default()];
+            node_data.in_critical_section = true;
+        }
+        let mut checker = InvariantChecker::new(100, 2);
+        assert_eq!(checker.check(&system), Ok(()));
+
+        system.tree.data_mut(1).in_critical_section = true;
+        assert_eq!(
+            checker.check(&system),
+            Err(vec![
+                Violation::MutualExclusion(vec!["A".to_string(), "B".to_string(), "C".to_string()]),
+                Violation::NoTokenInCs {
+                    node: "B".to_string()
+                }
+            ])
+        );
+    }
+
+    #[test]
+    fn waiting_longer_than_the_bound_is_starvation() {
+        let mut system = chain();
+        let mut checker = InvariantChecker::new(1, 1);
+        system.tree.data_mut(2).self_req_issued = true;
+        for _ in 0..2 {
+            system.next_iteration();
+            assert_eq!(checker.check(&system), Ok(()));
+        }
+        system.next_iteration();
+        assert_eq!(
+            checker.check(&system),
+            Err(vec![Violation::Starvation {
+                node: "C".to_string(),
+                since: 1,
+                bound: 1
+            }])
+        );
+    }
+
+    #[test]
+    fn default_bound_covers_every_critical_section() {
+        // both critical sections and three trips through the tree there and
+        // back, each hop taking the delay and an iteration
+        assert_eq!(InvariantChecker::default_bound(&chain()), 5 + 3 * 12);
+    }
+
+    #[test]
+    fn entering_before_the_other_leaves_breaks_mutual_exclusion() {
+        let record = |node: &str, event| TraceRecord {
+            iteration: 0,
+            node: node.to_string(),
+            event,
+        };
+        let mut checker = InvariantChecker::new(100, 1);
+        assert_eq!(checker.check_record(&record("A", Event::CsEnter)), Ok(()));
+        assert_eq!(checker.check_record(&record("A", Event::CsExit)), Ok(()));
+        assert_eq!(checker.check_record(&record("B", Event::CsEnter)), Ok(()));
+        assert_eq!(
+            checker.check_record(&record("C", Event::CsEnter)),
+            Err(Violation::MutualExclusion(vec![
+                "B".to_string(),
+                "C".to_string()
+            ]))
+        );
+    }
+}
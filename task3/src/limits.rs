@@ -0,0 +1,96 @@
+/// Decides when to stop a simulation which does not finish on its own, either
+/// because it runs for too long or because nothing happens in it anymore
+#[derive(Debug)]
+pub struct Limits {
+    max_iterations: usize,
+    stall_limit: usize,
+    /// Iterations in a row in which nothing happened
+    idle_iterations: usize,
+    /// What the iterations are called in the messages
+    unit: &'static str,
+}
+
+impl Limits {
+    pub fn new(max_iterations: usize, stall_limit: usize) -> Self {
+        Self {
+            max_iterations,
+            stall_limit,
+            idle_iterations: 0,
+            unit: "iterations",
+        }
+    }
+    /// When something else takes the place of the iterations, e.g. the ticks
+    /// with the threads
+    pub fn with_unit(mut self, unit: &'static str) -> Self {
+        self.unit = unit;
+        self
+    }
+    /// Checked before every iteration with the number of the iterations done
+    /// so far
+    pub fn check_iterations(&self, done: usize) -> Result<(), String> {
+        if done >= self.max_iterations {
+            Err(format!(
+                "STOPPED: the limit of {} {} was reached",
+                self.max_iterations, self.unit
+            ))
+        } else {
+            Ok(())
+        }
+    }
+    /// Checked after every iteration with whether anything happened in it
+    pub fn check_progress(&mut self, progress: bool) -> Result<(), String> {
+        if progress {
+            self.idle_iterations = 0;
+            return Ok(());
+        }
+        self.idle_iterations += 1;
+        if self.idle_iterations >= self.stall_limit {
+            Err(format!(
+                "STOPPED: no progress in the last {} {}",
+                self.stall_limit, self.unit
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn iterations_up_to_the_limit_are_allowed() {
+        let limits = Limits::new(3, 1);
+        assert!(limits.check_iterations(2).is_ok());
+        assert_eq!(
+            limits.check_iterations(3),
+            Err("STOPPED: the limit of 3 iterations was reached".to_string())
+        );
+    }
+
+    #[test]
+    fn progress_resets_the_stall() {
+        let mut limits = Limits::new(100, 2);
+        assert!(limits.check_progress(false).is_ok());
+        assert!(limits.check_progress(true).is_ok());
+        assert!(limits.check_progress(false).is_ok());
+        assert_eq!(
+            limits.check_progress(false),
+            Err("STOPPED: no progress in the last 2 iterations".to_string())
+        );
+    }
+
+    #[test]
+    fn messages_use_the_unit() {
+        let mut limits = Limits::new(5, 1).with_unit("ticks");
+        assert_eq!(
+            limits.check_iterations(5),
+            Err("STOPPED: the limit of 5 ticks was reached".to_string())
+        );
+        assert_eq!(
+            limits.check_progress(false),
+            Err("STOPPED: no progress in the last 1 ticks".to_string())
+        );
+    }
+}
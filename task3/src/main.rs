@@ -1,410 +1,373 @@
 use colored::*;
-use std::{
-    cell::RefCell,
-    collections::{HashMap, VecDeque},
-    mem::drop,
-    rc::Rc,
-};
-
-#[derive(Debug)]
-pub struct NodeInner<T> {
-    parent: Option<Node<T>>,
-    data: T,
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use std::time::Duration;
+
+mod channels;
+mod description;
+mod frames;
+mod invariants;
+mod limits;
+mod naimi_trehel;
+mod node;
+mod recovery;
+mod spanning_tree;
+mod stats;
+mod system;
+mod threaded;
+mod timeline;
+mod trace;
+mod tree;
+
+use description::SystemDescription;
+use frames::Frame;
+use invariants::{InvariantChecker, Violation};
+use limits::Limits;
+use stats::Statistics;
+use system::Algorithm;
+use threaded::{ThreadedSystem, Update};
+use timeline::Timeline;
+use trace::{Event, TraceWriter};
+
+/// The simulation is stopped after that many iterations
+const DEFAULT_MAX_ITERATIONS: usize = 10_000;
+/// Every that many iterations of waiting raise the priority of a request
+const DEFAULT_AGEING: usize = 10;
+/// The simulation is stopped if nothing happens in that many iterations in a
+/// row
+const DEFAULT_STALL_LIMIT: usize = 20;
+/// How long a tick takes with the threads, in milliseconds
+const DEFAULT_TICK_MS: u64 = 10;
+
+/// Prints why the simulation was stopped with the state of the system and
+/// exits with an error
+fn abort(reason: String, system: &system::System) -> ! {
+    eprintln!("{}", reason.red());
+    eprintln!("{}", system.diagnostics());
+    std::process::exit(2);
 }
 
-#[derive(Debug)]
-pub struct Node<T>(Rc<RefCell<NodeInner<T>>>);
-
-impl<T> Clone for Node<T> {
-    fn clone(&self) -> Self {
-        Self(self.0.clone())
-    }
+/// Prints why the input cannot be run and exits with an error
+fn refuse(reason: String) -> ! {
+    eprintln!("{}", reason.red());
+    std::process::exit(1);
 }
 
-impl<T> Node<T> {
-    pub fn root(data: T) -> Self {
-        Self(Rc::new(RefCell::new(NodeInner { parent: None, data })))
-    }
-    pub fn produce_child(&self, data: T) -> Self {
-        Self(Rc::new(RefCell::new(NodeInner {
-            parent: Some(self.clone()),
-            data,
-        })))
-    }
-    pub fn make_child_of(&mut self, other: &Self) {
-        let mut self_node = self.0.borrow_mut();
-        self_node.parent = Some(other.clone());
-    }
-    pub fn make_root(&mut self) {
-        let mut self_node = self.0.borrow_mut();
-        self_node.parent = None;
-    }
-    pub fn invert_relation_with(&mut self, other: &mut Self) {
-        let mut self_node = self.0.borrow_mut();
-        // hacky but works good as no one else has any other reason to modify the `parent`
-        let mut other_node = unsafe { other.0.as_ptr().as_mut().unwrap() };
-
-        match &self_node.parent {
-            Some(_) => match other_node.parent {
-                Some(_) => {
-                    // we are not the root and the other node is not the root
-                    self_node.parent = Some(other.clone());
-                    other_node.parent = None;
-                }
-                None => {
-                    // we are not the root but the other node is the root
-                    self_node.parent = None;
-                    other_node.parent = Some(self.clone());
-                }
-            },
-            None => {
-                // we are the root
-                self_node.parent = Some(other.clone());
-                other_node.parent = None;
-            }
-        };
-    }
-    pub fn data(&self) -> &T {
-        //hacky but the normal borrow rules should protect us well enough in the single threaded context
-        &unsafe { self.0.try_borrow_unguarded() }.unwrap().data
-    }
-    pub fn data_mut(&mut self) -> &mut T {
-        //also hacky
-        let ref_mut: &mut NodeInner<T> = unsafe { self.0.as_ptr().as_mut().unwrap() };
-        &mut ref_mut.data
-    }
-    pub fn is_root(&self) -> bool {
-        self.0.borrow().parent.is_none()
-    }
+/// The violations, one per line
+fn report(violations: &[Violation]) -> String {
+    let report = violations
+        .iter()
+        .map(|violation| format!("--- {violation}"))
+        .collect::<Vec<_>>()
+        .join("\n");
+    format!("INVARIANT VIOLATED:\n{report}")
 }
 
-#[derive(Debug)]
-pub struct Token;
-
-pub enum SystemMsg {
-    Token(Token),
-    Request { from: String, handle: SystemNode },
+/// Removes the flag from the arguments, returns whether it was there
+fn take_flag(args: &mut Vec<String>, flag: &str) -> bool {
+    let found = args.iter().any(|a| a == flag);
+    args.retain(|a| a != flag);
+    found
 }
 
-#[derive(Debug)]
-pub struct SystemNodeData {
-    /// will be asserted by the (de)serialiazation format that this value is unique
-    pub id: String,
-    pub request_queue: VecDeque<(String, SystemNode)>,
-    pub instructions: VecDeque<Instruction>,
-    pub token: Option<Token>,
-    pub is_req_sent: bool,
-    /// additional variable introduced to keep the state consistent between iterations
-    pub self_req_issued: bool,
+/// Removes the option and its value from the arguments, returns the value
+fn take_option(args: &mut Vec<String>, option: &str) -> Option<String> {
+    args.iter().position(|a| a == option).map(|idx| {
+        args.remove(idx);
+        assert!(idx < args.len(), "expected a value after `{option}`");
+        args.remove(idx)
+    })
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug)]
-pub struct Instruction {
-    pub kind: TaskKind,
-    /// How long will the task execute in the critical section
-    pub duration: usize,
-}
-
-#[derive(serde::Serialize, serde::Deserialize, Debug)]
-#[serde(rename_all = "snake_case")]
-pub enum TaskKind {
-    Idle,
-    CriticalSection,
-}
-
-#[derive(Clone, Debug)]
-pub struct SystemNode(pub Node<SystemNodeData>);
+fn main() {
+    let mut args = std::env::args().skip(1).collect::<Vec<_>>();
+    // reshuffles the order of the nodes on every iteration, reproducibly
+    let mut shuffle = take_option(&mut args, "--shuffle")
+        .map(|seed| StdRng::seed_from_u64(seed.parse().expect("expected a number as the seed")));
+    let max_iterations = take_option(&mut args, "--max-iterations")
+        .map(|n| n.parse().expect("expected a number of iterations"))
+        .unwrap_or(DEFAULT_MAX_ITERATIONS);
+    let stall_limit = take_option(&mut args, "--stall-limit")
+        .map(|n| n.parse().expect("expected a number of iterations"))
+        .unwrap_or(DEFAULT_STALL_LIMIT);
+    let algorithm = take_option(&mut args, "--algorithm")
+        .map(|name| name.parse::<Algorithm>().unwrap_or_else(|e| panic!("{e}")))
+        .unwrap_or_default();
+    let service_bound = take_option(&mut args, "--service-bound")
+        .map(|n| n.parse().expect("expected a number of iterations"));
+    let dot_dir = take_option(&mut args, "--dot").map(std::path::PathBuf::from);
+    let html_path = take_option(&mut args, "--html");
+    let mut trace = take_option(&mut args, "--trace").map(|path| TraceWriter::create(&path));
+    let root = take_option(&mut args, "--root");
+    let ageing = take_option(&mut args, "--ageing")
+        .map(|n| n.parse().expect("expected a number of iterations"))
+        .unwrap_or(DEFAULT_AGEING);
+    let threads = take_flag(&mut args, "--threads");
+    let tick = take_option(&mut args, "--tick")
+        .map(|ms| ms.parse().expect("expected a number of milliseconds"))
+        .unwrap_or(DEFAULT_TICK_MS);
+    let filename = args.first().expect("expected a filename as an input");
+    println!("Reading from file {filename}");
+    let file = std::fs::File::open(filename).expect("failed to open the file");
+    let mut sys_description: SystemDescription =
+        serde_json::from_reader(file).expect("failed to deserialize");
+    if let Some(root) = root {
+        sys_description = sys_description.with_root(root);
+    }
 
-impl SystemNode {
-    pub fn is_done(&self) -> bool {
-        let node_data = self.0.data();
-        node_data.request_queue.is_empty() && node_data.instructions.is_empty()
+    let checked = sys_description
+        .span_graph()
+        .and_then(|tree| sys_description.validate().map(|()| tree));
+    match checked {
+        Err(problems) => {
+            eprintln!("{}", "The described system is not a valid tree:".red());
+            for problem in problems {
+                eprintln!("{}", format!("--- {problem}").red());
+            }
+            std::process::exit(1);
+        }
+        Ok(Some(tree)) => println!("Built the tree from the graph: {tree}"),
+        Ok(None) => {}
     }
-    pub fn is_root(&self) -> bool {
-        self.0.is_root()
+    let tokens = sys_description.tokens();
+    if sys_description.has_crashes() && algorithm != Algorithm::Raymond {
+        refuse(format!(
+            "Crashes are supported only with {}",
+            Algorithm::Raymond.name()
+        ));
     }
-    pub fn id(&self) -> String {
-        self.0.data().id.clone()
+    if tokens > 1 && algorithm != Algorithm::Raymond {
+        refuse(format!(
+            "Several tokens are supported only with {}",
+            Algorithm::Raymond.name()
+        ));
     }
-    pub fn queue_status(&mut self) -> &[(String, SystemNode)] {
-        let node_data = self.0.data_mut();
-        node_data.request_queue.make_contiguous()
+    if tokens > 1 && sys_description.has_crashes() {
+        refuse("Crashes are not supported with several tokens".to_string());
     }
-    pub fn iterate(&mut self) {
-        let maybe_msg = self.execute_task();
-        let self_id = self.id();
-
-        match maybe_msg {
-            Some(msg) => {
-                let node = self.0 .0.borrow();
-                let parent = node.parent.clone();
-                match parent {
-                    Some(parent) => match msg {
-                        SystemMsg::Token(_) => {
-                            panic!("a non-root node should not be producing a token");
-                        }
-                        SystemMsg::Request { from, handle } => {
-                            drop(node);
-
-                            let node_data = self.0.data_mut();
-
-                            if from == self_id && !node_data.self_req_issued {
-                                println!(
-                                    "{}",
-                                    format!("--- Node {self_id} produced a request").bright_blue()
-                                );
-
-                                node_data
-                                    .request_queue
-                                    .push_back((from.clone(), handle.clone()));
-                                node_data.self_req_issued = true;
-                            }
-                            if !node_data.is_req_sent {
-                                node_data.is_req_sent = true;
-                                drop(node_data);
-
-                                SystemNode(parent).recv_msg(SystemMsg::Request { from, handle })
-                            }
-                        }
-                    },
-                    None => {
-                        match msg {
-                            SystemMsg::Token(t) => {
-                                // drop what we do not need and reborrow
-                                drop(node);
-
-                                println!(
-                                    "{}",
-                                    format!("--- Node {self_id} relieves a token").bright_blue()
-                                );
-
-                                let node_data = self.0.data_mut();
-                                let next_req = node_data.request_queue.pop_front();
-                                match next_req {
-                                    // pass on the token and stop being a root
-                                    Some((name, mut handle)) => {
-                                        handle.recv_msg(SystemMsg::Token(t));
-
-                                        drop(node_data);
-                                        self.0.invert_relation_with(&mut handle.0);
-                                        let node_data = self.0.data_mut();
 
-                                        if let Some(_) = node_data.request_queue.iter().nth(0) {
-                                            handle.recv_msg(SystemMsg::Request {
-                                                from: node_data.id.clone(),
-                                                handle: self.clone(),
-                                            })
-                                        }
-                                    }
-                                    None => {
-                                        // nothing to do
-                                    }
-                                }
-                            }
-                            SystemMsg::Request { from: _, handle: _ } => { /* do nothing, resolve in the next iteration */
-                            }
-                        }
-                    }
-                }
-            }
-            None => {
-                // nothing to do
-            }
+    if threads {
+        let unsupported = [
+            (sys_description.has_crashes(), "crashes"),
+            (shuffle.is_some(), "--shuffle"),
+            (dot_dir.is_some(), "--dot"),
+            (html_path.is_some(), "--html"),
+        ]
+        .into_iter()
+        .filter(|(given, _)| *given)
+        .map(|(_, what)| what)
+        .collect::<Vec<_>>();
+        if !unsupported.is_empty() {
+            refuse(format!(
+                "Not supported with --threads: {}",
+                unsupported.join(", ")
+            ));
         }
     }
-    pub fn recv_msg(&mut self, msg: SystemMsg) {
-        match msg {
-            SystemMsg::Token(t) => {
-                let node_data = self.0.data_mut();
-                node_data.token = Some(t);
-                node_data.is_req_sent = false;
 
-                if let Some(next_req) = node_data.request_queue.pop_front() {
-                    let (name, mut handle) = next_req;
-
-                    if name == node_data.id {
-                        /* do nothing, keep the token to execute the task in the next iter*/
-                    } else {
-                        println!(
-                            "{}",
-                            format!(
-                                "--- Node {} received a token and passed it further to {name}",
-                                node_data.id
-                            )
-                            .bright_blue()
-                        );
-
-                        drop(node_data); //drop
-                        self.0.invert_relation_with(&mut handle.0);
-                        let node_data = self.0.data_mut(); //reborrow
+    let mut system = sys_description
+        .build_system()
+        .with_algorithm(algorithm)
+        .with_ageing(ageing);
+    let mut order = system.ids().collect::<Vec<_>>();
+    let mut checker = InvariantChecker::new(
+        service_bound.unwrap_or_else(|| InvariantChecker::default_bound(&system)),
+        tokens,
+    );
+    if threads {
+        assert!(tick > 0, "the tick has to be at least a millisecond");
+        let tick = Duration::from_millis(tick);
+        return run_threaded(system, tick, max_iterations, stall_limit, checker, trace);
+    }
 
-                        handle.recv_msg(SystemMsg::Token(node_data.token.take().unwrap()));
-                        println!(
-                            "{}",
-                            format!("--- Node {name} received a token and became a root")
-                                .bright_blue()
-                        );
+    let mut limits = Limits::new(max_iterations, stall_limit);
+    let mut frames = Vec::new();
+    let mut stats = Statistics::new(&system);
+    let mut timeline = Timeline::new(&system);
+    if let Some(dir) = &dot_dir {
+        std::fs::create_dir_all(dir).expect("failed to create the directory for the frames");
+    }
+    // the initial state is iteration 0
+    let mut record_frame = |system: &system::System| {
+        let iteration = system.now();
+        let frame = Frame::capture(system, iteration);
+        if let Some(dir) = &dot_dir {
+            std::fs::write(
+                dir.join(format!("iteration-{iteration:04}.dot")),
+                frame.to_dot(),
+            )
+            .expect("failed to write a frame");
+        }
+        if html_path.is_some() {
+            frames.push(frame);
+        }
+    };
+    record_frame(&system);
 
-                        if let Some(_) = node_data.request_queue.iter().nth(0) {
-                            handle.recv_msg(SystemMsg::Request {
-                                from: node_data.id.clone(),
-                                handle: self.clone(),
-                            })
-                        }
-                    }
-                }
-            }
-            SystemMsg::Request { from, handle } => {
-                let node_data = self.0.data_mut();
-                node_data.request_queue.push_back((from, handle));
+    let outcome = loop {
+        if let Err(reason) = limits.check_iterations(system.now()) {
+            break Err(reason);
+        }
+        let steps = system.steps();
+        system.next_iteration();
+        println!("{}", format!("ITERATION {}", system.now()).yellow());
+        if let Some(rng) = &mut shuffle {
+            order.shuffle(rng);
+        }
+        for &node in &order {
+            system.iterate(node);
+            println!(
+                "{}",
+                format!(
+                    "--- Node \"{}\" queue: {:?}",
+                    system.id(node),
+                    system.queue_status(node)
+                )
+                .bright_yellow()
+            );
+        }
+        record_frame(&system);
+        for record in system.take_events() {
+            stats.record(&record);
+            timeline.record(&record);
+            if let Some(trace) = &mut trace {
+                trace.write(&record);
             }
         }
-    }
-    pub fn execute_task(&mut self) -> Option<SystemMsg> {
-        let self_id = self.id();
-        let node_data = self.0.data_mut();
-        let next = node_data.instructions.iter_mut().nth(0);
-        match next {
-            Some(instr) => match instr.kind {
-                TaskKind::Idle => {
-                    println!(
-                        "{}",
-                        format!("--- Node {self_id} executes the idle task").bright_blue()
-                    );
-                    if instr.duration > 1 {
-                        instr.duration -= 1;
-                        None
-                    } else {
-                        node_data.instructions.pop_front();
-                        None
-                    }
-                }
-                TaskKind::CriticalSection => match node_data.token {
-                    Some(_) => {
-                        println!(
-                            "{}",
-                            format!("--- Node {self_id} executes in the critical section")
-                                .bright_blue()
-                        );
-                        if instr.duration > 1 {
-                            instr.duration -= 1;
-                            None
-                        } else {
-                            node_data.instructions.pop_front();
-                            Some(SystemMsg::Token(Token))
-                        }
-                    }
-                    None => Some(SystemMsg::Request {
-                        from: node_data.id.clone(),
-                        handle: self.clone(),
-                    }),
-                },
-            },
-            None => None,
+        stats.observe(&system);
+        if let Err(violations) = checker.check(&system) {
+            break Err(report(&violations));
         }
-    }
-}
+        if system.all_done() {
+            break Ok(());
+        }
+        // messages still travelling mean that something is going to happen
+        let progress = system.steps() != steps || !system.channels.is_empty();
+        if let Err(reason) = limits.check_progress(progress) {
+            break Err(reason);
+        }
+    };
 
-#[derive(serde::Serialize, serde::Deserialize, Debug)]
-pub struct SystemNodeDescription {
-    pub instructions: VecDeque<Instruction>,
-    pub parent: Option<String>,
-}
+    if let Some(trace) = &mut trace {
+        trace.flush();
+    }
+    if let Some(path) = html_path {
+        std::fs::write(path, frames::render_html(&frames)).expect("failed to write the page");
+    }
+    if let Err(reason) = outcome {
+        abort(reason, &system);
+    }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug)]
-pub struct SystemDescription {
-    nodes: HashMap<String, SystemNodeDescription>,
+    println!("{}", "FINISHED".yellow());
+    println!("{stats}");
+    println!("{timeline}");
 }
 
-impl SystemDescription {
-    /// Produces a vector of nodes and takes the execution duration out of the
-    /// description.
-    pub fn build_system(self) -> Vec<SystemNode> {
-        let relations: HashMap<String, Option<String>> = self
-            .nodes
-            .iter()
-            .map(|(name, data)| (name.to_owned(), data.parent.to_owned()))
-            .collect();
-
-        let nodes: HashMap<String, SystemNode> = self
-            .nodes
-            .into_iter()
-            .map(|(name, data)| {
-                (
-                    name.clone(),
-                    SystemNode(Node::root(SystemNodeData {
-                        id: name,
-                        request_queue: VecDeque::new(),
-                        instructions: data.instructions,
-                        token: None,
-                        is_req_sent: false,
-                        self_req_issued: false,
-                    })),
-                )
-            })
-            .collect();
-
-        let mut final_nodes = Vec::new();
-
-        for (rel_target, maybe_parent) in relations {
-            let target_node = nodes.get(&rel_target).unwrap();
-            match maybe_parent {
-                Some(parent) => {
-                    let target_parent = nodes.get(&parent).unwrap();
-                    let mut target_clone = target_node.0.clone();
-                    target_clone.make_child_of(&target_parent.0);
-
-                    final_nodes.push(SystemNode(target_clone));
+/// Runs every node on its own thread until all of them are done. The ticks
+/// take the place of the iterations: the limits are given in ticks and the
+/// events are stamped with them. Mutual exclusion is checked on the events as
+/// they come, the rest of the invariants on the state the nodes end in.
+fn run_threaded(
+    system: system::System,
+    tick: Duration,
+    max_ticks: usize,
+    stall_limit: usize,
+    mut checker: InvariantChecker,
+    mut trace: Option<TraceWriter>,
+) {
+    let mut stats = Statistics::new(&system);
+    let mut timeline = Timeline::new(&system);
+    // a message might take that long to arrive without anything happening
+    let max_delay = system
+        .ids()
+        .flat_map(|a| system.ids().map(move |b| (a, b)))
+        .map(|(a, b)| system.channels.delay(a, b))
+        .max()
+        .unwrap_or(0);
+    let mut limits = Limits::new(max_ticks, stall_limit + max_delay).with_unit("ticks");
+    println!(
+        "{}",
+        format!("Running every node on its own thread, a tick takes {tick:?}").yellow()
+    );
+
+    let running = ThreadedSystem::start(system, tick);
+    let outcome = loop {
+        // the current tick is not over yet
+        if let Err(reason) = limits.check_iterations(running.ticks() - 1) {
+            break Err(reason);
+        }
+        let update = running.next_update();
+        // waiting is not progress, a lost token looks like that
+        let progress = match &update {
+            Update::Event(record, _) => record.event != Event::Wait,
+            Update::Quiet => false,
+            Update::Finished => true,
+        };
+        if let Err(reason) = limits.check_progress(progress) {
+            break Err(reason);
+        }
+        match update {
+            Update::Finished => break Ok(()),
+            Update::Quiet => {}
+            Update::Event(record, queue) => {
+                println!(
+                    "{}",
+                    format!(
+                        "--- [tick {}] Node {} {}",
+                        record.iteration, record.node, record.event
+                    )
+                    .bright_blue()
+                );
+                stats.record(&record);
+                stats.observe_queue(&record.node, queue);
+                timeline.record(&record);
+                if let Some(trace) = &mut trace {
+                    trace.write(&record);
                 }
-                None => {
-                    let mut target_clone = target_node.0.clone();
-                    let data = target_clone.data_mut();
-                    data.token = Some(Token);
-
-                    final_nodes.push(SystemNode(target_clone))
+                if let Err(violation) = checker.check_record(&record) {
+                    break Err(report(&[violation]));
                 }
             }
         }
+    };
+    stats.iterations = running.ticks();
+    let system = running.stop();
 
-        final_nodes
+    if let Some(trace) = &mut trace {
+        trace.flush();
+    }
+    let outcome = outcome.and_then(|()| checker.check(&system).map_err(|v| report(&v)));
+    if let Err(reason) = outcome {
+        abort(reason, &system);
     }
-}
-
-fn main() {
-    let filename = std::env::args()
-        .nth(1)
-        .expect("expected a filename as an input");
-    println!("Reading from file {filename}");
-    let file = std::fs::File::open(&filename).expect("failed to open the file");
-    let sys_description: SystemDescription =
-        serde_json::from_reader(file).expect("failed to deserialize");
-
-    let mut nodes = sys_description.build_system();
 
-    let mut i: usize = 1;
-    loop {
-        i += 1;
-        println!("{}", format!("ITERATION {}", i).yellow());
-        for node in &mut nodes {
-            node.iterate();
-            println!(
-                "{}",
-                format!(
-                    "--- Node \"{}\" queue: {:?}",
-                    node.id(),
-                    node.queue_status()
-                        .iter()
-                        .map(|(name, _)| name)
-                        .collect::<Vec<_>>()
-                )
-                .bright_yellow()
-            );
-            drop(node);
-        }
-        if nodes.iter().all(|n| n.is_done()) {
-            println!("{}", format!("FINISHED").yellow());
+    println!("{}", "FINISHED".yellow());
+    println!("{stats}");
+    println!("{timeline}");
+}
 
-            break;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shuffled_order_is_reproducible() {
+        let shuffled = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut order = (0..8).collect::<Vec<usize>>();
+            (0..5)
+                .map(|_| {
+                    order.shuffle(&mut rng);
+                    order.clone()
+                })
+                .collect::<Vec<_>>()
+        };
+        let orders = shuffled(42);
+        assert_eq!(orders, shuffled(42));
+        assert_ne!(orders, shuffled(43));
+        for order in &orders {
+            let mut sorted = order.clone();
+            sorted.sort_unstable();
+            assert_eq!(sorted, (0..8).collect::<Vec<_>>());
         }
     }
 }
@@ -0,0 +1,197 @@
+use crate::{node::Node, system::SystemMsg, trace::Event, tree::NodeId};
+
+/// Naimi–Trehel's algorithm. The parent of a node is its `last` pointer: the
+/// last node it knows to have asked for the token. A request travels along
+/// those pointers to the end of the chain of waiting nodes, turning every
+/// pointer on its way toward the requester (path reversal). The node at the
+/// end remembers the requester as its `next` and passes the token to it after
+/// leaving the critical section.
+impl Node<'_> {
+    pub(crate) fn naimi_trehel_request(&mut self) {
+        match *self.parent {
+            // the last one to ask, it either holds the token already or it
+            // has been promised it
+            None => {
+                if !self.data.tokens.is_empty() {
+                    self.enter_cs();
+                }
+            }
+            Some(last) => {
+                let to = self.name(last);
+                self.log(Event::RequestSent { to });
+                *self.parent = None;
+                // no crashes, so no recovery epochs either
+                let msg = SystemMsg::Request {
+                    origin: self.id,
+                    epoch: 0,
+                    priority: 0,
+                    raise: false,
+                };
+                self.send(last, msg);
+            }
+        }
+    }
+
+    pub(crate) fn naimi_trehel_release(&mut self) {
+        if let Some(next) = self.data.next.take() {
+            let token = self
+                .data
+                .tokens
+                .pop()
+                .expect("a node leaving the critical section holds the token");
+            self.pass_token_to(next, token);
+        }
+    }
+
+    pub(crate) fn naimi_trehel_recv_msg(&mut self, from: NodeId, msg: SystemMsg) {
+        match msg {
+            SystemMsg::Token(t) => {
+                let from = self.name(from);
+                self.log(Event::TokenReceived { from });
+                self.data.tokens.push(t);
+                if self.data.self_req_issued {
+                    self.enter_cs();
+                }
+            }
+            SystemMsg::Request {
+                origin,
+                epoch,
+                priority,
+                raise,
+            } => {
+                let event = Event::RequestReceived {
+                    from: self.name(from),
+                    origin: self.name(origin),
+                };
+                self.log(event);
+                match *self.parent {
+                    // wants the token or uses it, the requester is next
+                    None if self.data.self_req_issued => self.data.next = Some(origin),
+                    None => {
+                        let token = self
+                            .data
+                            .tokens
+                            .pop()
+                            .expect("the last node which does not need the token holds it");
+                        self.pass_token_to(origin, token);
+                    }
+                    Some(last) => {
+                        let event = Event::RequestForwarded {
+                            to: self.name(last),
+                            origin: self.name(origin),
+                        };
+                        self.log(event);
+                        let msg = SystemMsg::Request {
+                            origin,
+                            epoch,
+                            priority,
+                            raise,
+                        };
+                        self.send(last, msg);
+                    }
+                }
+                *self.parent = Some(origin);
+            }
+            _ => unreachable!("crashes are not supported with Naimi–Trehel"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        description::SystemDescription,
+        invariants::InvariantChecker,
+        stats::Statistics,
+        system::{Algorithm, System},
+    };
+
+    fn build(json: &str) -> System {
+        let description: SystemDescription = serde_json::from_str(json).unwrap();
+        description
+            .build_system()
+            .with_algorithm(Algorithm::NaimiTrehel)
+    }
+
+    /// Runs iterations until everything is done, checking the invariants
+    /// after every one of them
+    fn run(system: &mut System) -> Statistics {
+        let mut checker = InvariantChecker::new(InvariantChecker::default_bound(system), 1);
+        let mut stats = Statistics::new(system);
+        for _ in 0..100 {
+            if system.all_done() && system.channels.is_empty() {
+                return stats;
+            }
+            system.next_iteration();
+            for node in system.ids() {
+                system.iterate(node);
+            }
+            for record in system.take_events() {
+                stats.record(&record);
+            }
+            checker.check(system).unwrap();
+        }
+        panic!("the system did not finish:\n{}", system.diagnostics());
+    }
+
+    #[test]
+    fn request_turns_the_pointers_toward_the_requester() {
+        // A holds the token, C is at the end of the chain and asks for it
+        let mut system = build(
+            r#"{"nodes": {
+                "A": {"instructions": [], "parent": null},
+                "B": {"instructions": [], "parent": "A"},
+                "C": {"instructions": [{"kind": "critical_section", "duration": 1}], "parent": "B"}
+            }}"#,
+        );
+        let stats = run(&mut system);
+        assert_eq!(system.tree.parent(0), Some(2));
+        assert_eq!(system.tree.parent(1), Some(2));
+        assert_eq!(system.tree.parent(2), None);
+        assert!(system.tree.data(2).tokens.len() == 1);
+        // the request to B, forwarded to A and the token straight to C
+        assert_eq!((stats.requests, stats.tokens), (2, 1));
+        assert_eq!(stats.cs_entries(), 1);
+    }
+
+    #[test]
+    fn holder_in_the_critical_section_remembers_the_next() {
+        let mut system = build(
+            r#"{"nodes": {
+                "A": {"instructions": [{"kind": "critical_section", "duration": 3}], "parent": null},
+                "B": {"instructions": [{"kind": "critical_section", "duration": 1}], "parent": "A"}
+            }}"#,
+        );
+        system.next_iteration();
+        for node in system.ids() {
+            system.iterate(node);
+        }
+        assert!(system.tree.data(0).in_critical_section);
+        system.next_iteration();
+        for node in system.ids() {
+            system.iterate(node);
+        }
+        // the request of B reached A, which still uses the token
+        assert_eq!(system.tree.data(0).next, Some(1));
+        assert!(system.diagnostics().contains("Node A: next: B"));
+        assert_eq!(system.tree.parent(0), Some(1));
+
+        let stats = run(&mut system);
+        assert_eq!(system.tree.data(0).next, None);
+        assert!(system.tree.data(1).tokens.len() == 1);
+        assert_eq!(stats.cs_entries(), 2);
+    }
+
+    #[test]
+    fn request_of_the_last_node_with_the_token_needs_no_messages() {
+        let mut system = build(
+            r#"{"nodes": {
+                "A": {"instructions": [{"kind": "critical_section", "duration": 1}], "parent": null},
+                "B": {"instructions": [], "parent": "A"}
+            }}"#,
+        );
+        let stats = run(&mut system);
+        assert_eq!(stats.messages(), 0);
+        assert_eq!(stats.cs_entries(), 1);
+    }
+}
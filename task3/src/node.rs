@@ -0,0 +1,453 @@
+use crate::{
+    system::{Algorithm, QueuedRequest, SystemMsg, SystemNodeData, TaskKind, Token},
+    trace::Event,
+    tree::NodeId,
+};
+
+/// What a node has done that concerns the others, in order: the messages to
+/// send and the events to report
+#[derive(Debug, Default)]
+pub struct Outbox {
+    pub messages: Vec<(NodeId, SystemMsg)>,
+    pub events: Vec<Event>,
+}
+
+/// A single node running the algorithm. It only sees its own data and its
+/// parent pointer, the rest it learns from the messages. Whoever drives it
+/// (the [`crate::system::System`] in the iterations or a thread of
+/// [`crate::threaded`]) hands it the messages and delivers what ends up in
+/// its outbox.
+pub struct Node<'a> {
+    pub id: NodeId,
+    pub data: &'a mut SystemNodeData,
+    pub parent: &'a mut Option<NodeId>,
+    /// The names of all the nodes, for the events
+    pub names: &'a [String],
+    pub algorithm: Algorithm,
+    /// Every that many iterations of waiting in a queue raise the priority of
+    /// a request by one, no ageing if zero
+    pub ageing: usize,
+    /// The current iteration, or tick with the threads
+    pub now: usize,
+    pub outbox: Outbox,
+}
+
+impl Node<'_> {
+    pub(crate) fn name(&self, node: NodeId) -> String {
+        self.names[node].clone()
+    }
+    pub(crate) fn log(&mut self, event: Event) {
+        self.outbox.events.push(event);
+    }
+    pub(crate) fn send(&mut self, to: NodeId, msg: SystemMsg) {
+        self.outbox.messages.push((to, msg));
+    }
+
+    /// Begins a step of the current task. A critical section has to be
+    /// requested first, the step can be made only once the node is in it. A
+    /// task takes exactly `duration` steps, the waiting for the critical
+    /// section aside, so a task of zero duration is over right away and the
+    /// node goes on with the next one. Returns false if there is nothing to
+    /// do but to wait.
+    pub fn start_step(&mut self) -> bool {
+        loop {
+            let Some(instr) = self.data.instructions.front() else {
+                return false;
+            };
+            let (critical, priority, ticked) = (
+                matches!(instr.kind, TaskKind::CriticalSection),
+                instr.priority,
+                instr.duration > 0,
+            );
+            if critical {
+                if !self.data.in_critical_section && !self.data.self_req_issued {
+                    self.log(Event::RequestProduced { priority });
+                    self.data.self_req_issued = true;
+                    self.request_cs();
+                }
+                if !self.data.in_critical_section {
+                    return false;
+                }
+            }
+            if ticked {
+                self.log(if critical { Event::Cs } else { Event::Idle });
+                return true;
+            }
+            self.finish_step();
+        }
+    }
+    /// The step of the current task is over, after the last step of a
+    /// critical section the node leaves it
+    pub fn finish_step(&mut self) {
+        let instr = self
+            .data
+            .instructions
+            .front_mut()
+            .expect("the task is still there");
+        instr.duration = instr.duration.saturating_sub(1);
+        if instr.duration > 0 {
+            return;
+        }
+        let critical_section = matches!(instr.kind, TaskKind::CriticalSection);
+        self.data.instructions.pop_front();
+        if critical_section {
+            self.data.in_critical_section = false;
+            self.data.self_req_issued = false;
+            self.log(Event::CsExit);
+            self.release_cs();
+        }
+    }
+    /// Whether the node waits for the critical section
+    pub fn waiting(&self) -> bool {
+        self.data.self_req_issued && !self.data.in_critical_section
+    }
+
+    pub(crate) fn enter_cs(&mut self) {
+        self.data.in_critical_section = true;
+        self.log(Event::CsEnter);
+    }
+    /// The node needs the critical section
+    fn request_cs(&mut self) {
+        match self.algorithm {
+            Algorithm::Raymond => {
+                let priority = self
+                    .data
+                    .instructions
+                    .front()
+                    .map_or(0, |instr| instr.priority);
+                self.enqueue(self.id, priority);
+                self.assign_privilege();
+                self.make_request();
+            }
+            Algorithm::NaimiTrehel => self.naimi_trehel_request(),
+        }
+    }
+    /// The node has just left the critical section
+    fn release_cs(&mut self) {
+        match self.algorithm {
+            Algorithm::Raymond => {
+                self.assign_privilege();
+                self.make_request();
+            }
+            Algorithm::NaimiTrehel => self.naimi_trehel_release(),
+        }
+    }
+
+    /// Handles a message of the algorithm, the messages from before the last
+    /// recovery the node took part in are thrown away
+    pub fn recv_msg(&mut self, from: NodeId, msg: SystemMsg) {
+        match msg {
+            SystemMsg::Token(t) if t.generation < self.data.epoch => {
+                let from = self.name(from);
+                self.log(Event::TokenDiscarded { from });
+            }
+            SystemMsg::Request { epoch, .. } if epoch < self.data.epoch => {}
+            SystemMsg::Probe { .. } | SystemMsg::Echo { .. } | SystemMsg::RecoveryDone { .. } => {
+                unreachable!("handled by the recovery")
+            }
+            msg => match self.algorithm {
+                Algorithm::Raymond => self.raymond_recv_msg(from, msg),
+                Algorithm::NaimiTrehel => self.naimi_trehel_recv_msg(from, msg),
+            },
+        }
+    }
+    fn raymond_recv_msg(&mut self, from: NodeId, msg: SystemMsg) {
+        match msg {
+            SystemMsg::Token(t) => {
+                let from = self.name(from);
+                self.log(Event::TokenReceived { from });
+                self.log(Event::BecameRoot);
+                *self.parent = None;
+                self.data.tokens.push(t);
+            }
+            SystemMsg::Request {
+                priority, raise, ..
+            } => {
+                let name = self.name(from);
+                self.log(Event::RequestReceived {
+                    from: name.clone(),
+                    origin: name,
+                });
+                let queue = &mut self.data.request_queue;
+                match queue.iter_mut().find(|request| request.node == from) {
+                    Some(request) if raise => request.priority = request.priority.max(priority),
+                    // the token is already on its way to the sender
+                    None if raise => {}
+                    _ => self.enqueue(from, priority),
+                }
+            }
+            _ => unreachable!("only the token and the requests get here"),
+        }
+        self.assign_privilege();
+        self.make_request();
+    }
+
+    fn enqueue(&mut self, from: NodeId, priority: usize) {
+        self.data.request_queue.push_back(QueuedRequest {
+            node: from,
+            priority,
+            since: self.now,
+        });
+    }
+    /// The priority of the request raised by the time it has been waiting
+    fn effective_priority(&self, request: &QueuedRequest) -> usize {
+        match self.ageing {
+            0 => request.priority,
+            ageing => request.priority + (self.now - request.since) / ageing,
+        }
+    }
+    /// The request to serve first: the highest priority, the oldest one of
+    /// those with the same. Its position in the queue and its priority.
+    pub(crate) fn next_request(&self) -> Option<(usize, usize)> {
+        self.data
+            .request_queue
+            .iter()
+            .enumerate()
+            .map(|(idx, request)| (idx, self.effective_priority(request)))
+            .max_by(|(a_idx, a), (b_idx, b)| a.cmp(b).then(b_idx.cmp(a_idx)))
+    }
+    /// If the node holds a token it does not need, it passes the token to the
+    /// node whose request goes first. That might be the node itself, then it
+    /// enters the critical section. With several tokens the node keeps
+    /// serving the requests while it has any to spare, and stays a root
+    /// until it gives away the last one.
+    pub fn assign_privilege(&mut self) {
+        // one of the tokens is used in the critical section
+        while self.data.tokens.len() > usize::from(self.data.in_critical_section) {
+            let Some((idx, _)) = self.next_request() else {
+                return;
+            };
+            let next = self.data.request_queue[idx].node;
+            // the token stays where the recovery has found it until it ends
+            if next != self.id && self.data.wave.is_some() {
+                return;
+            }
+            self.data.request_queue.remove(idx);
+            self.data.is_req_sent = false;
+
+            if next == self.id {
+                self.enter_cs();
+            } else {
+                let token = self.data.tokens.pop().unwrap();
+                // from now on a token is reachable through the next node
+                if self.data.tokens.is_empty() {
+                    *self.parent = Some(next);
+                }
+                self.pass_token_to(next, token);
+            }
+        }
+    }
+    /// Asks the holder for the token if anyone needs it and the request has
+    /// not been sent yet, or has been sent with a lower priority
+    pub fn make_request(&mut self) {
+        let Some(holder) = *self.parent else {
+            return;
+        };
+        let Some((_, priority)) = self.next_request() else {
+            return;
+        };
+        // the requests wait until the recovery ends
+        if self.data.is_req_sent && priority <= self.data.sent_priority || self.data.wave.is_some()
+        {
+            return;
+        }
+        let raise = self.data.is_req_sent;
+        self.data.is_req_sent = true;
+        self.data.sent_priority = priority;
+        let to = self.name(holder);
+        self.log(Event::RequestSent { to });
+        let msg = SystemMsg::Request {
+            origin: self.id,
+            epoch: self.data.epoch,
+            priority,
+            raise,
+        };
+        self.send(holder, msg);
+    }
+    pub(crate) fn pass_token_to(&mut self, next: NodeId, token: Token) {
+        let to = self.name(next);
+        self.log(Event::TokenPassed { to });
+        self.send(next, SystemMsg::Token(token));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+    use crate::system::Instruction;
+
+    const NAMES: [&str; 3] = ["A", "B", "C"];
+
+    fn names() -> Vec<String> {
+        NAMES.iter().map(|name| name.to_string()).collect()
+    }
+
+    fn critical_section(duration: usize) -> SystemNodeData {
+        let instr = Instruction {
+            kind: TaskKind::CriticalSection,
+            duration,
+            priority: 0,
+        };
+        SystemNodeData::new("A".to_string(), VecDeque::from([instr]))
+    }
+
+    /// The node A of the three at the given time, Raymond's algorithm
+    fn node<'a>(
+        data: &'a mut SystemNodeData,
+        parent: &'a mut Option<NodeId>,
+        names: &'a [String],
+        now: usize,
+    ) -> Node<'a> {
+        Node {
+            id: 0,
+            data,
+            parent,
+            names,
+            algorithm: Algorithm::Raymond,
+            ageing: 0,
+            now,
+            outbox: Outbox::default(),
+        }
+    }
+
+    fn queued(node: NodeId, priority: usize, since: usize) -> QueuedRequest {
+        QueuedRequest {
+            node,
+            priority,
+            since,
+        }
+    }
+
+    #[test]
+    fn waiting_node_asks_its_holder() {
+        let (mut data, mut parent, names) = (critical_section(1), Some(1), names());
+        let mut node = node(&mut data, &mut parent, &names, 0);
+        assert!(!node.start_step());
+        assert_eq!(
+            node.outbox.events,
+            [
+                Event::RequestProduced { priority: 0 },
+                Event::RequestSent { to: "B".into() }
+            ]
+        );
+        assert!(matches!(
+            node.outbox.messages[..],
+            [(1, SystemMsg::Request { origin: 0, .. })]
+        ));
+        // the request is not repeated in the next step
+        node.outbox = Outbox::default();
+        assert!(!node.start_step());
+        assert!(node.outbox.messages.is_empty());
+    }
+
+    #[test]
+    fn holder_passes_the_token_on_after_the_critical_section() {
+        let (mut data, mut parent, names) = (critical_section(2), None, names());
+        data.tokens.push(Token::default());
+        let mut node = node(&mut data, &mut parent, &names, 0);
+        assert!(node.start_step());
+        node.finish_step();
+        assert!(node.data.in_critical_section);
+        node.data.request_queue.push_back(queued(2, 0, 0));
+        assert!(node.start_step());
+        node.finish_step();
+        assert_eq!(
+            node.outbox.events,
+            [
+                Event::RequestProduced { priority: 0 },
+                Event::CsEnter,
+                Event::Cs,
+                Event::Cs,
+                Event::CsExit,
+                Event::TokenPassed { to: "C".into() }
+            ]
+        );
+        assert!(matches!(
+            node.outbox.messages[..],
+            [(2, SystemMsg::Token(_))]
+        ));
+        assert_eq!(*node.parent, Some(2));
+        assert!(node.data.instructions.is_empty());
+    }
+
+    #[test]
+    fn spare_tokens_serve_the_queue_and_the_last_one_moves_the_root() {
+        let (mut data, mut parent, names) = (critical_section(1), None, names());
+        data.instructions.clear();
+        data.tokens = vec![Token::default(), Token::default()];
+        data.request_queue = VecDeque::from([queued(1, 0, 0)]);
+        let mut node = node(&mut data, &mut parent, &names, 0);
+        node.assign_privilege();
+        // one given away, the node still holds the other one
+        assert_eq!(node.data.tokens.len(), 1);
+        assert_eq!(*node.parent, None);
+        node.data.request_queue.push_back(queued(2, 0, 0));
+        node.assign_privilege();
+        assert!(node.data.tokens.is_empty());
+        assert_eq!(*node.parent, Some(2));
+        assert!(matches!(
+            node.outbox.messages[..],
+            [(1, SystemMsg::Token(_)), (2, SystemMsg::Token(_))]
+        ));
+    }
+
+    #[test]
+    fn tasks_of_zero_duration_take_no_step() {
+        let (mut data, mut parent, names) = (critical_section(0), None, names());
+        data.tokens.push(Token::default());
+        data.instructions.push_front(Instruction {
+            kind: TaskKind::Idle,
+            duration: 0,
+            priority: 0,
+        });
+        let mut node = node(&mut data, &mut parent, &names, 0);
+        assert!(!node.start_step());
+        // the critical section has been entered and left without a step
+        assert_eq!(
+            node.outbox.events,
+            [
+                Event::RequestProduced { priority: 0 },
+                Event::CsEnter,
+                Event::CsExit
+            ]
+        );
+        assert!(node.data.instructions.is_empty());
+        assert!(!node.waiting());
+    }
+
+    #[test]
+    fn token_from_before_the_recovery_is_discarded() {
+        let (mut data, mut parent, names) = (critical_section(1), Some(1), names());
+        data.epoch = 1;
+        let mut node = node(&mut data, &mut parent, &names, 0);
+        node.recv_msg(1, SystemMsg::Token(Token { generation: 0 }));
+        assert_eq!(
+            node.outbox.events,
+            [Event::TokenDiscarded { from: "B".into() }]
+        );
+        assert!(node.data.tokens.is_empty());
+        assert_eq!(*node.parent, Some(1));
+    }
+
+    #[test]
+    fn waiting_requests_age() {
+        let (mut data, mut parent, names) = (critical_section(1), None, names());
+        data.request_queue = VecDeque::from([queued(1, 0, 0), queued(2, 1, 4)]);
+        let mut node = node(&mut data, &mut parent, &names, 4);
+        node.ageing = 2;
+        // 0 + 4 / 2 against 1 + 0 / 2
+        assert_eq!(node.next_request(), Some((0, 2)));
+        node.ageing = 0;
+        assert_eq!(node.next_request(), Some((1, 1)));
+    }
+
+    #[test]
+    fn oldest_of_the_same_priority_goes_first() {
+        let (mut data, mut parent, names) = (critical_section(1), None, names());
+        data.request_queue = VecDeque::from([queued(2, 1, 0), queued(1, 1, 1)]);
+        let node = node(&mut data, &mut parent, &names, 1);
+        assert_eq!(node.next_request(), Some((0, 1)));
+    }
+}
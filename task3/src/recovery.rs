@@ -0,0 +1,440 @@
+use crate::{
+    system::{System, SystemMsg, Token},
+    trace::Event,
+    tree::NodeId,
+};
+
+/// The part of an echo wave a node takes care of
+#[derive(Debug, Clone)]
+pub struct Wave {
+    /// The neighbour the probe came from, none at the node which started the
+    /// wave
+    pub parent: Option<NodeId>,
+    /// How many echoes the node still waits for
+    pub pending: usize,
+    /// Whether the token was found at the node or in the subtree below it
+    pub token_seen: bool,
+    /// The neighbour through which the token was found
+    pub token_child: Option<NodeId>,
+}
+
+/// Crashes of the nodes and the recovery from them, only in Raymond's
+/// algorithm.
+///
+/// A crashed node stops, everything sent to it is lost. After the detection
+/// delay its neighbours notice it: they forget the crashed node, the one
+/// with the smallest id becomes the coordinator and connects to the others,
+/// so the nodes form a tree again. The neighbours which pointed at the
+/// crashed node point at the coordinator now.
+///
+/// The coordinator then starts an echo wave with a new epoch. A node which
+/// holds the token when the probe reaches it stamps the token with the epoch
+/// and reports it in its echo. A token from an older epoch arriving at a node
+/// which has already seen the probe is discarded, so when the wave comes back
+/// to the coordinator either the token has been stamped and is the only
+/// valid one, or there is no valid token at all and the coordinator creates a
+/// new one. The stamped token is not passed on until the recovery ends. The
+/// end of the recovery travels down the wave, every node points toward the
+/// token.
+///
+/// The probe also clears the request queue of the node and the requests from
+/// before the recovery are thrown away when they arrive. No requests are made
+/// during the recovery, the nodes ask for the token again after it ends.
+///
+/// A crash noticed during a recovery starts a new wave which takes over the
+/// old one.
+impl System {
+    /// The node crashes at the beginning of the iteration
+    pub fn schedule_crash(&mut self, node: NodeId, at: usize) {
+        self.crashes.push((node, at));
+    }
+
+    /// Whether a crash has not been noticed yet or the token is being looked
+    /// for, the token might be missing in the meantime
+    pub fn is_recovering(&self) -> bool {
+        !self.undetected.is_empty()
+            || self.ids().any(|node| {
+                let node_data = self.tree.data(node);
+                !node_data.crashed && node_data.wave.is_some()
+            })
+    }
+
+    /// Crashes the nodes scheduled for the current iteration and lets the
+    /// neighbours notice the crashes whose detection delay is over
+    pub(crate) fn apply_crashes(&mut self) {
+        let now = self.now();
+        let crashing = self
+            .crashes
+            .iter()
+            .filter(|(_, at)| *at == now)
+            .map(|(node, _)| *node)
+            .collect::<Vec<_>>();
+        for node in crashing {
+            if self.tree.data(node).crashed {
+                continue;
+            }
+            self.log(node, Event::Crashed);
+            self.tree.data_mut(node).crashed = true;
+            self.channels.clear(node);
+            self.undetected.push((node, now + self.detection_delay));
+        }
+
+        while let Some(idx) = self.undetected.iter().position(|(_, at)| *at <= now) {
+            let (node, _) = self.undetected.remove(idx);
+            self.detect_crash(node);
+        }
+    }
+
+    fn detect_crash(&mut self, crashed: NodeId) {
+        self.steps += 1;
+        // the crashed neighbours nobody has noticed yet are handled together
+        // with this one, otherwise the nodes behind them would stay cut off
+        let mut region = vec![crashed];
+        let mut noticed = Vec::new();
+        let mut idx = 0;
+        while idx < region.len() {
+            let node = region[idx];
+            idx += 1;
+            for neighbour in std::mem::take(&mut self.tree.data_mut(node).neighbours) {
+                if !self.tree.data(neighbour).crashed {
+                    noticed.push((neighbour, node));
+                } else if !region.contains(&neighbour) {
+                    region.push(neighbour);
+                }
+            }
+        }
+        self.undetected.retain(|(node, _)| !region.contains(node));
+        // the links to the crashed nodes are closed
+        for &node in &region {
+            self.channels.drop_from(node);
+        }
+
+        for (survivor, node) in &noticed {
+            let node = self.id(*node).to_string();
+            self.log(*survivor, Event::CrashDetected { node });
+        }
+        let mut survivors = noticed
+            .into_iter()
+            .map(|(node, _)| node)
+            .collect::<Vec<_>>();
+        survivors.sort();
+        survivors.dedup();
+        let Some(&coordinator) = survivors.first() else {
+            return;
+        };
+
+        for &node in &survivors {
+            let node_data = self.tree.data_mut(node);
+            node_data.neighbours.retain(|n| !region.contains(n));
+            node_data
+                .request_queue
+                .retain(|request| !region.contains(&request.node));
+            if node != coordinator {
+                self.connect(node, coordinator);
+            }
+            if let Some(parent) = self.tree.parent(node) {
+                if region.contains(&parent) {
+                    if node == coordinator {
+                        // until the wave finds out where the token is
+                        self.tree.make_root(node);
+                    } else {
+                        self.tree.make_child_of(node, coordinator);
+                    }
+                }
+            }
+        }
+
+        self.epoch += 1;
+        let epoch = self.epoch;
+        self.log(coordinator, Event::RecoveryStarted { epoch });
+        self.start_wave(coordinator, None, epoch);
+    }
+
+    pub(crate) fn recovery_recv_msg(&mut self, node: NodeId, from: NodeId, msg: SystemMsg) {
+        let epoch = self.tree.data(node).epoch;
+        match msg {
+            // a probe of an older wave is ignored, the newer one gets
+            // everywhere anyway
+            SystemMsg::Probe { epoch: probe } if probe > epoch => {
+                self.start_wave(node, Some(from), probe)
+            }
+            SystemMsg::Echo {
+                epoch: echo,
+                token_seen,
+            } if echo == epoch => {
+                let Some(wave) = &mut self.tree.data_mut(node).wave else {
+                    return;
+                };
+                wave.pending -= 1;
+                if token_seen {
+                    wave.token_seen = true;
+                    wave.token_child = Some(from);
+                }
+                if wave.pending == 0 {
+                    self.complete_wave(node);
+                }
+            }
+            SystemMsg::RecoveryDone { epoch: done }
+                if done == epoch && self.tree.data(node).wave.is_some() =>
+            {
+                self.end_wave(node)
+            }
+            _ => {}
+        }
+    }
+
+    fn send_recovery(&mut self, node: NodeId, to: NodeId, msg: SystemMsg) {
+        let event = Event::RecoverySent {
+            to: self.id(to).to_string(),
+            message: msg.kind().to_string(),
+        };
+        self.log(node, event);
+        self.send(node, to, msg);
+    }
+
+    /// The probe reached the node, it passes it on to the other neighbours
+    fn start_wave(&mut self, node: NodeId, parent: Option<NodeId>, epoch: usize) {
+        let node_data = self.tree.data_mut(node);
+        node_data.epoch = epoch;
+        // the requests are made again after the recovery
+        node_data
+            .request_queue
+            .retain(|request| request.node == node);
+        node_data.is_req_sent = false;
+        // the token held right now is the one valid after the recovery
+        for token in &mut node_data.tokens {
+            token.generation = epoch;
+        }
+        let token_seen = !node_data.tokens.is_empty();
+        let children = node_data
+            .neighbours
+            .iter()
+            .copied()
+            .filter(|neighbour| Some(*neighbour) != parent)
+            .collect::<Vec<_>>();
+        node_data.wave = Some(Wave {
+            parent,
+            pending: children.len(),
+            token_seen,
+            token_child: None,
+        });
+        for child in children {
+            self.send_recovery(node, child, SystemMsg::Probe { epoch });
+        }
+        if self.tree.data(node).wave.as_ref().unwrap().pending == 0 {
+            self.complete_wave(node);
+        }
+    }
+
+    /// All the echoes came back, the result goes up the wave
+    fn complete_wave(&mut self, node: NodeId) {
+        let node_data = self.tree.data(node);
+        let epoch = node_data.epoch;
+        let wave = node_data
+            .wave
+            .as_ref()
+            .expect("the node takes part in a wave");
+        match wave.parent {
+            Some(parent) => {
+                let token_seen = wave.token_seen;
+                self.send_recovery(node, parent, SystemMsg::Echo { epoch, token_seen });
+            }
+            None => self.finish_recovery(node),
+        }
+    }
+
+    /// The wave came back to the coordinator, it knows whether the token
+    /// survived
+    fn finish_recovery(&mut self, coordinator: NodeId) {
+        let node_data = self.tree.data_mut(coordinator);
+        let epoch = node_data.epoch;
+        let regenerated = !node_data
+            .wave
+            .as_ref()
+            .expect("the coordinator started a wave")
+            .token_seen;
+        if regenerated {
+            node_data.tokens.push(Token { generation: epoch });
+            self.log(coordinator, Event::TokenRegenerated);
+        }
+        self.log(coordinator, Event::RecoveryFinished { regenerated });
+        self.end_wave(coordinator);
+    }
+
+    /// Tells the rest of the wave that the recovery is over. The token has
+    /// not moved since the wave found it, so the node points the way the
+    /// token was found, or toward the coordinator, and asks for the token
+    /// again if it needs it.
+    fn end_wave(&mut self, node: NodeId) {
+        let node_data = self.tree.data_mut(node);
+        let wave = node_data
+            .wave
+            .take()
+            .expect("the node takes part in a wave");
+        let epoch = node_data.epoch;
+        let children = node_data
+            .neighbours
+            .iter()
+            .copied()
+            .filter(|neighbour| Some(*neighbour) != wave.parent)
+            .collect::<Vec<_>>();
+        match (
+            !node_data.tokens.is_empty(),
+            wave.token_child.or(wave.parent),
+        ) {
+            (false, Some(holder)) => self.tree.make_child_of(node, holder),
+            _ => self.tree.make_root(node),
+        }
+        for child in children {
+            self.send_recovery(node, child, SystemMsg::RecoveryDone { epoch });
+        }
+        self.drive(node, |node| {
+            node.assign_privilege();
+            node.make_request();
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        description::SystemDescription,
+        invariants::InvariantChecker,
+        system::{System, SystemMsg, Token},
+        trace::Event,
+    };
+
+    /// A holds the token, B is its child and C the child of B. Nobody needs
+    /// the critical section, the node crashes in the second iteration.
+    fn chain(crashing: &str) -> System {
+        let description: SystemDescription = serde_json::from_str(&format!(
+            r#"{{"nodes": {{
+                "A": {{"instructions": [], "parent": null}},
+                "B": {{"instructions": [], "parent": "A"}},
+                "C": {{"instructions": [], "parent": "B"}}
+            }}, "crashes": [{{"node": "{crashing}", "at": 2}}], "detection_delay": 1}}"#
+        ))
+        .unwrap();
+        description.build_system()
+    }
+
+    /// Runs until the recovery is over, checking the invariants on the way,
+    /// and gives all the events
+    fn recover(system: &mut System) -> Vec<Event> {
+        let mut checker = InvariantChecker::new(InvariantChecker::default_bound(system), 1);
+        let mut events = Vec::new();
+        for _ in 0..20 {
+            system.next_iteration();
+            for node in system.ids() {
+                system.iterate(node);
+            }
+            events.extend(system.take_events().into_iter().map(|record| record.event));
+            checker.check(system).unwrap();
+            if system.now() > 2 && !system.is_recovering() && system.channels.is_empty() {
+                return events;
+            }
+        }
+        panic!("the recovery did not finish:\n{}", system.diagnostics());
+    }
+
+    #[test]
+    fn crash_is_noticed_after_the_detection_delay() {
+        let mut system = chain("C");
+        system.next_iteration();
+        system.next_iteration();
+        assert!(system.tree.data(2).crashed);
+        assert!(system.is_recovering());
+        assert_eq!(system.tree.data(1).neighbours, [0, 2]);
+        system.next_iteration();
+        // B forgot C and started looking for the token
+        assert_eq!(system.tree.data(1).neighbours, [0]);
+        assert_eq!(system.tree.data(1).epoch, 1);
+        assert!(system.tree.data(1).wave.is_some());
+        assert_eq!(
+            system.take_events().last().unwrap().event,
+            Event::RecoverySent {
+                to: "A".to_string(),
+                message: "probe".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn surviving_token_is_kept() {
+        let mut system = chain("C");
+        let events = recover(&mut system);
+        assert!(events.contains(&Event::RecoveryFinished { regenerated: false }));
+        assert!(!events.contains(&Event::TokenRegenerated));
+        // confirmed in the new epoch where it was
+        let token = system.tree.data(0).tokens.first().unwrap();
+        assert_eq!(token.generation, 1);
+        assert_eq!(system.tree.parent(1), Some(0));
+    }
+
+    #[test]
+    fn token_of_the_crashed_holder_is_regenerated() {
+        let mut system = chain("A");
+        let events = recover(&mut system);
+        assert!(events.contains(&Event::TokenRegenerated));
+        assert!(events.contains(&Event::RecoveryFinished { regenerated: true }));
+        // B was the only one to notice, it coordinated and made the new token
+        let token = system.tree.data(1).tokens.first().unwrap();
+        assert_eq!(token.generation, 1);
+        assert_eq!(system.tree.parent(1), None);
+        assert_eq!(system.tree.parent(2), Some(1));
+        assert!(system.tree.data(2).tokens.is_empty());
+    }
+
+    #[test]
+    fn neighbours_of_the_crashed_node_are_connected_to_the_coordinator() {
+        let mut system = chain("B");
+        let events = recover(&mut system);
+        assert!(events.contains(&Event::CrashDetected {
+            node: "B".to_string()
+        }));
+        assert_eq!(system.tree.data(0).neighbours, [2]);
+        assert_eq!(system.tree.data(2).neighbours, [0]);
+        // the probe, the echo and the end of the recovery
+        let sent = events
+            .iter()
+            .filter(|event| matches!(event, Event::RecoverySent { .. }))
+            .count();
+        assert_eq!(sent, 3);
+        assert_eq!(system.tree.parent(2), Some(0));
+        assert!(system.tree.data(0).tokens.len() == 1);
+    }
+
+    #[test]
+    fn messages_from_before_the_recovery_are_thrown_away() {
+        let mut system = chain("C");
+        recover(&mut system);
+        let now = system.now();
+        system.channels.send(
+            1,
+            0,
+            SystemMsg::Request {
+                origin: 1,
+                epoch: 0,
+                priority: 0,
+                raise: false,
+            },
+            now,
+        );
+        system
+            .channels
+            .send(0, 1, SystemMsg::Token(Token::default()), now);
+        system.next_iteration();
+        for node in system.ids() {
+            system.iterate(node);
+        }
+        assert!(system.tree.data(0).request_queue.is_empty());
+        assert!(system.tree.data(1).tokens.is_empty());
+        let events = system.take_events();
+        assert_eq!(
+            events.last().unwrap().event,
+            Event::TokenDiscarded {
+                from: "A".to_string()
+            }
+        );
+    }
+}
@@ -0,0 +1,207 @@
+use std::fmt::Display;
+
+use petgraph::{Directed, Graph, Undirected};
+
+/// A graph as serialized by petgraph (the format of the inputs of task1),
+/// either directed or not. The directions of the edges are ignored anyway.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[serde(untagged)]
+pub enum InputGraph {
+    Directed(Graph<String, (), Directed>),
+    Undirected(Graph<String, (), Undirected>),
+}
+
+impl InputGraph {
+    pub fn names(&self) -> Vec<String> {
+        match self {
+            InputGraph::Directed(graph) => graph.node_weights().cloned().collect(),
+            InputGraph::Undirected(graph) => graph.node_weights().cloned().collect(),
+        }
+    }
+
+    /// The neighbours of every node, by the indices of the nodes
+    pub fn neighbours(&self) -> Vec<Vec<usize>> {
+        let (count, edges) = match self {
+            InputGraph::Directed(graph) => (
+                graph.node_count(),
+                graph
+                    .raw_edges()
+                    .iter()
+                    .map(|e| (e.source().index(), e.target().index()))
+                    .collect::<Vec<_>>(),
+            ),
+            InputGraph::Undirected(graph) => (
+                graph.node_count(),
+                graph
+                    .raw_edges()
+                    .iter()
+                    .map(|e| (e.source().index(), e.target().index()))
+                    .collect::<Vec<_>>(),
+            ),
+        };
+        let mut neighbours = vec![Vec::new(); count];
+        for (a, b) in edges.into_iter().filter(|(a, b)| a != b) {
+            neighbours[a].push(b);
+            neighbours[b].push(a);
+        }
+        for list in &mut neighbours {
+            list.sort();
+            list.dedup();
+        }
+        neighbours
+    }
+}
+
+/// The tree found by the echo algorithm
+#[derive(Debug, Clone)]
+pub struct SpanningTree {
+    pub root: usize,
+    /// The parent of every node, none for the root and the nodes the probes
+    /// have not reached
+    pub parents: Vec<Option<usize>>,
+    pub messages: usize,
+    pub rounds: usize,
+}
+
+impl SpanningTree {
+    /// The echo algorithm run in synchronous rounds, every message takes one
+    /// round. The root sends a probe to all its neighbours. A node which gets
+    /// its first message takes the sender as its parent and sends the probe
+    /// to all the other neighbours, once it has got a message from every
+    /// neighbour it sends the echo to the parent. The construction is over
+    /// when the root has got a message from every neighbour.
+    ///
+    /// The first message reaching a node comes through a shortest path, so
+    /// the tree is a BFS tree of the graph. Among the messages arriving in the
+    /// same round the one from the node with the smallest index wins.
+    pub fn echo(neighbours: &[Vec<usize>], root: usize) -> Self {
+        let mut parents = vec![None; neighbours.len()];
+        let mut reached = vec![false; neighbours.len()];
+        let mut received = vec![0; neighbours.len()];
+        reached[root] = true;
+
+        let mut in_flight = neighbours[root]
+            .iter()
+            .map(|neighbour| (root, *neighbour))
+            .collect::<Vec<_>>();
+        let mut messages = in_flight.len();
+        let mut rounds = 0;
+        while !in_flight.is_empty() {
+            rounds += 1;
+            in_flight.sort();
+            let mut sent = Vec::new();
+            for (from, to) in in_flight {
+                if !reached[to] {
+                    reached[to] = true;
+                    parents[to] = Some(from);
+                    sent.extend(
+                        neighbours[to]
+                            .iter()
+                            .filter(|neighbour| **neighbour != from)
+                            .map(|neighbour| (to, *neighbour)),
+                    );
+                }
+                received[to] += 1;
+                if received[to] == neighbours[to].len() {
+                    if let Some(parent) = parents[to] {
+                        sent.push((to, parent));
+                    }
+                }
+            }
+            messages += sent.len();
+            in_flight = sent;
+        }
+
+        Self {
+            root,
+            parents,
+            messages,
+            rounds,
+        }
+    }
+
+    /// The nodes which have not been reached from the root
+    pub fn unreached(&self) -> Vec<usize> {
+        (0..self.parents.len())
+            .filter(|node| *node != self.root && self.parents[*node].is_none())
+            .collect()
+    }
+
+    pub fn depth(&self) -> usize {
+        (0..self.parents.len())
+            .map(|mut node| {
+                let mut depth = 0;
+                while let Some(parent) = self.parents[node] {
+                    node = parent;
+                    depth += 1;
+                }
+                depth
+            })
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+impl Display for SpanningTree {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "the echo algorithm took {} messages in {} rounds, the tree has depth {}",
+            self.messages,
+            self.rounds,
+            self.depth()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A square A-B-C-D with E hanging off C and F on its own
+    fn graph() -> InputGraph {
+        let mut graph = Graph::new_undirected();
+        let nodes = ["A", "B", "C", "D", "E", "F"].map(|name| graph.add_node(name.to_string()));
+        for (a, b) in [(0, 1), (1, 2), (2, 3), (3, 0), (2, 4)] {
+            graph.add_edge(nodes[a], nodes[b], ());
+        }
+        InputGraph::Undirected(graph)
+    }
+
+    #[test]
+    fn neighbours_ignore_directions_loops_and_duplicates() {
+        let mut graph = Graph::new();
+        let a = graph.add_node("A".to_string());
+        let b = graph.add_node("B".to_string());
+        graph.add_edge(a, b, ());
+        graph.add_edge(b, a, ());
+        graph.add_edge(a, a, ());
+        let graph = InputGraph::Directed(graph);
+        assert_eq!(graph.names(), vec!["A", "B"]);
+        assert_eq!(graph.neighbours(), vec![vec![1], vec![0]]);
+    }
+
+    #[test]
+    fn echo_builds_a_bfs_tree() {
+        let neighbours = graph().neighbours();
+        let tree = SpanningTree::echo(&neighbours, 0);
+        // C is reached through B and D in the same round, B has the smaller index
+        assert_eq!(
+            tree.parents,
+            vec![None, Some(0), Some(1), Some(0), Some(2), None]
+        );
+        assert_eq!(tree.unreached(), vec![5]);
+        assert_eq!(tree.depth(), 3);
+        // a probe and an echo through every edge
+        assert_eq!(tree.messages, 2 * 5);
+    }
+
+    #[test]
+    fn echo_from_a_lonely_node() {
+        let neighbours = graph().neighbours();
+        let tree = SpanningTree::echo(&neighbours, 5);
+        assert_eq!(tree.messages, 0);
+        assert_eq!(tree.rounds, 0);
+        assert_eq!(tree.unreached(), vec![0, 1, 2, 3, 4]);
+    }
+}
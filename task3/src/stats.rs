@@ -0,0 +1,294 @@
+use std::{collections::BTreeMap, fmt::Display};
+
+use crate::{
+    system::{Algorithm, System},
+    trace::{Event, TraceRecord},
+};
+
+#[derive(Debug, Default, Clone)]
+pub struct NodeStatistics {
+    pub name: String,
+    pub cs_entries: usize,
+    /// Iterations between producing the requests and entering the critical
+    /// section, summed up
+    pub waited: usize,
+    pub max_queue: usize,
+    /// When the request the node waits with right now was produced and its
+    /// priority
+    requested_at: Option<(usize, usize)>,
+}
+
+impl NodeStatistics {
+    pub fn mean_wait(&self) -> f64 {
+        self.waited as f64 / self.cs_entries.max(1) as f64
+    }
+}
+
+/// The entries to the critical section of the requests with the same
+/// priority
+#[derive(Debug, Default, Clone)]
+pub struct PriorityStatistics {
+    pub cs_entries: usize,
+    pub waited: usize,
+}
+
+/// Summary of a run, collected from the events and the state of the queues
+#[derive(Debug, Clone)]
+pub struct Statistics {
+    pub algorithm: Algorithm,
+    pub iterations: usize,
+    /// Requests sent, including the forwarded ones
+    pub requests: usize,
+    pub tokens: usize,
+    /// Probes, echoes and the ends of the recoveries
+    pub recovery: usize,
+    /// In the order of the nodes in the system
+    pub nodes: Vec<NodeStatistics>,
+    pub priorities: BTreeMap<usize, PriorityStatistics>,
+}
+
+impl Statistics {
+    pub fn new(system: &System) -> Self {
+        Self {
+            algorithm: system.algorithm(),
+            iterations: 0,
+            requests: 0,
+            tokens: 0,
+            recovery: 0,
+            nodes: system
+                .ids()
+                .map(|node| NodeStatistics {
+                    name: system.id(node).to_string(),
+                    ..Default::default()
+                })
+                .collect(),
+            priorities: BTreeMap::new(),
+        }
+    }
+
+    fn node_mut(&mut self, name: &str) -> &mut NodeStatistics {
+        self.nodes
+            .iter_mut()
+            .find(|node| node.name == name)
+            .expect("the events come from the nodes of the system")
+    }
+
+    pub fn record(&mut self, record: &TraceRecord) {
+        match &record.event {
+            Event::RequestSent { .. } | Event::RequestForwarded { .. } => self.requests += 1,
+            Event::TokenPassed { .. } => self.tokens += 1,
+            Event::RecoverySent { .. } => self.recovery += 1,
+            Event::RequestProduced { priority } => {
+                self.node_mut(&record.node).requested_at = Some((record.iteration, *priority));
+            }
+            Event::CsEnter => {
+                let node = self.node_mut(&record.node);
+                node.cs_entries += 1;
+                if let Some((requested_at, priority)) = node.requested_at.take() {
+                    let waited = record.iteration - requested_at;
+                    node.waited += waited;
+                    let class = self.priorities.entry(priority).or_default();
+                    class.cs_entries += 1;
+                    class.waited += waited;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Takes the lengths of the queues at the end of an iteration
+    pub fn observe(&mut self, system: &System) {
+        self.iterations += 1;
+        for (node, stats) in system.ids().zip(self.nodes.iter_mut()) {
+            let queue = system.tree.data(node).request_queue.len();
+            stats.max_queue = stats.max_queue.max(queue);
+        }
+    }
+
+    /// Takes the length of the queue of a single node, when the whole system
+    /// cannot be seen at once (with the threads)
+    pub fn observe_queue(&mut self, name: &str, queue: usize) {
+        let stats = self.node_mut(name);
+        stats.max_queue = stats.max_queue.max(queue);
+    }
+
+    pub fn messages(&self) -> usize {
+        self.requests + self.tokens + self.recovery
+    }
+
+    pub fn cs_entries(&self) -> usize {
+        self.nodes.iter().map(|node| node.cs_entries).sum()
+    }
+
+    /// Mean number of iterations between a request and the entry to the
+    /// critical section
+    pub fn mean_wait(&self) -> f64 {
+        let waited = self.nodes.iter().map(|node| node.waited).sum::<usize>();
+        waited as f64 / self.cs_entries().max(1) as f64
+    }
+}
+
+impl Display for Statistics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Algorithm: {}, iterations: {}",
+            self.algorithm.name(),
+            self.iterations
+        )?;
+        writeln!(
+            f,
+            "Messages: {} requests, {} tokens, {} for the recovery, {} in total, {:.2} per critical section entry",
+            self.requests,
+            self.tokens,
+            self.recovery,
+            self.messages(),
+            self.messages() as f64 / self.cs_entries().max(1) as f64
+        )?;
+        writeln!(
+            f,
+            "{:<12} {:>10} {:>17} {:>10} {:>10}",
+            "node", "cs entries", "iterations waited", "mean wait", "max queue"
+        )?;
+        for node in &self.nodes {
+            writeln!(
+                f,
+                "{:<12} {:>10} {:>17} {:>10.2} {:>10}",
+                node.name,
+                node.cs_entries,
+                node.waited,
+                node.mean_wait(),
+                node.max_queue
+            )?;
+        }
+        writeln!(
+            f,
+            "{:<12} {:>10} {:>10}",
+            "priority", "cs entries", "mean wait"
+        )?;
+        for (priority, class) in &self.priorities {
+            writeln!(
+                f,
+                "{:<12} {:>10} {:>10.2}",
+                priority,
+                class.cs_entries,
+                class.waited as f64 / class.cs_entries.max(1) as f64
+            )?;
+        }
+        write!(
+            f,
+            "Mean request to critical section delay: {:.2} iterations",
+            self.mean_wait()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::description::SystemDescription;
+
+    /// A holds the token, B is its child and wants the critical section
+    fn handover() -> (System, Statistics) {
+        let description: SystemDescription = serde_json::from_str(
+            r#"{"nodes": {
+                "A": {"instructions": [], "parent": null},
+                "B": {"instructions": [{"kind": "critical_section", "duration": 2}], "parent": "A"}
+            }}"#,
+        )
+        .unwrap();
+        let mut system = description.build_system();
+        let mut stats = Statistics::new(&system);
+        while !system.all_done() {
+            system.next_iteration();
+            for node in system.ids() {
+                system.iterate(node);
+            }
+            for record in system.take_events() {
+                stats.record(&record);
+            }
+            stats.observe(&system);
+        }
+        (system, stats)
+    }
+
+    #[test]
+    fn messages_and_entries_are_counted() {
+        let (_, stats) = handover();
+        assert_eq!(stats.iterations, 4);
+        assert_eq!((stats.requests, stats.tokens), (1, 1));
+        assert_eq!(stats.messages(), 2);
+        assert_eq!(stats.cs_entries(), 1);
+        assert_eq!(stats.nodes[0].cs_entries, 0);
+        assert_eq!(stats.nodes[1].cs_entries, 1);
+    }
+
+    #[test]
+    fn waiting_is_measured_from_the_request() {
+        let (_, stats) = handover();
+        // produced in the first iteration, the token came in the third one
+        assert_eq!(stats.nodes[1].waited, 2);
+        assert_eq!(stats.nodes[1].mean_wait(), 2.0);
+        assert_eq!(stats.mean_wait(), 2.0);
+        // nobody entered, nothing to divide
+        assert_eq!(stats.nodes[0].mean_wait(), 0.0);
+    }
+
+    #[test]
+    fn longest_queues_are_kept() {
+        let (system, stats) = handover();
+        assert!(system.tree.data(1).request_queue.is_empty());
+        // B waited with its own request, A passed the token on right away
+        assert_eq!(stats.nodes[1].max_queue, 1);
+        assert_eq!(stats.nodes[0].max_queue, 0);
+    }
+
+    #[test]
+    fn queues_seen_one_by_one_are_kept_too() {
+        let (_, mut stats) = handover();
+        stats.observe_queue("A", 3);
+        stats.observe_queue("A", 2);
+        stats.observe_queue("B", 0);
+        assert_eq!(stats.nodes[0].max_queue, 3);
+        assert_eq!(stats.nodes[1].max_queue, 1);
+    }
+
+    #[test]
+    fn report_has_a_line_per_node() {
+        let (_, stats) = handover();
+        let report = stats.to_string();
+        assert!(report.starts_with("Algorithm: raymond, iterations: 4\n"));
+        assert!(report.contains(
+            "Messages: 1 requests, 1 tokens, 0 for the recovery, 2 in total, 2.00 per critical section entry"
+        ));
+        assert!(report.contains(&format!(
+            "{:<12} {:>10} {:>17} {:>10.2} {:>10}",
+            "B", 1, 2, 2.0, 1
+        )));
+        assert!(report.ends_with("Mean request to critical section delay: 2.00 iterations"));
+    }
+
+    #[test]
+    fn waiting_is_summed_up_per_priority() {
+        let mut stats = Statistics::new(&handover().0);
+        let record = |iteration, node: &str, event| TraceRecord {
+            iteration,
+            node: node.to_string(),
+            event,
+        };
+        for record in [
+            record(1, "A", Event::RequestProduced { priority: 2 }),
+            record(2, "B", Event::RequestProduced { priority: 0 }),
+            record(4, "A", Event::CsEnter),
+            record(9, "B", Event::CsEnter),
+        ] {
+            stats.record(&record);
+        }
+        assert_eq!(stats.priorities[&2].cs_entries, 1);
+        assert_eq!(stats.priorities[&2].waited, 3);
+        assert_eq!(stats.priorities[&0].waited, 7);
+        assert!(stats
+            .to_string()
+            .contains(&format!("{:<12} {:>10} {:>10.2}", 2, 1, 3.0)));
+    }
+}
@@ -0,0 +1,574 @@
+use colored::*;
+use std::collections::VecDeque;
+
+use crate::{
+    channels::Channels,
+    node::{Node, Outbox},
+    recovery::Wave,
+    trace::{Event, TraceRecord},
+    tree::{NodeId, Tree},
+};
+
+#[derive(Debug, Default)]
+pub struct Token {
+    /// The recovery epoch in which the token was created or last confirmed
+    /// to exist, a token from an older epoch is discarded on arrival
+    pub generation: usize,
+}
+
+/// What travels between the nodes, the sender is known from the envelope
+/// ([`crate::channels::InFlight`])
+#[derive(Debug)]
+pub enum SystemMsg {
+    Token(Token),
+    /// A request for the token on behalf of the `origin`, in Raymond's
+    /// algorithm that is always the sender
+    Request {
+        origin: NodeId,
+        /// The recovery epoch of the sender, the requests made before a
+        /// recovery are thrown away
+        epoch: usize,
+        /// The highest priority among the requests waiting at the sender,
+        /// sent again when a higher one arrives
+        priority: usize,
+        /// Only raises the priority of the request sent before, if it is
+        /// still waiting
+        raise: bool,
+    },
+    /// Looks for the token after a crash (see [`crate::recovery`])
+    Probe {
+        epoch: usize,
+    },
+    /// The answer to a probe, whether the token is in the subtree
+    Echo {
+        epoch: usize,
+        token_seen: bool,
+    },
+    /// Ends the recovery
+    RecoveryDone {
+        epoch: usize,
+    },
+}
+
+impl SystemMsg {
+    /// What kind of message it is, without the contents
+    pub fn kind(&self) -> &'static str {
+        match self {
+            SystemMsg::Token(_) => "token",
+            SystemMsg::Request { .. } => "request",
+            SystemMsg::Probe { .. } => "probe",
+            SystemMsg::Echo { .. } => "echo",
+            SystemMsg::RecoveryDone { .. } => "recovery_done",
+        }
+    }
+}
+
+/// The algorithm the nodes use to pass the token around
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    /// The token moves along the edges of a static tree
+    #[default]
+    Raymond,
+    /// The tree of the `last` pointers changes with every request
+    NaimiTrehel,
+}
+
+impl Algorithm {
+    /// The name used to select the algorithm in the command line
+    pub fn name(&self) -> &'static str {
+        match self {
+            Algorithm::Raymond => "raymond",
+            Algorithm::NaimiTrehel => "naimi-trehel",
+        }
+    }
+}
+
+impl std::str::FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raymond" => Ok(Algorithm::Raymond),
+            "naimi-trehel" => Ok(Algorithm::NaimiTrehel),
+            other => Err(format!(
+                "unknown algorithm `{other}`, expected `raymond` or `naimi-trehel`"
+            )),
+        }
+    }
+}
+
+/// A request waiting in the queue of a node
+#[derive(Debug, Clone, Copy)]
+pub struct QueuedRequest {
+    /// The neighbour the request came from, or the node itself
+    pub node: NodeId,
+    pub priority: usize,
+    /// The iteration in which the request got into the queue
+    pub since: usize,
+}
+
+#[derive(Debug)]
+pub struct SystemNodeData {
+    /// will be asserted by the (de)serialiazation format that this value is unique
+    pub id: String,
+    pub request_queue: VecDeque<QueuedRequest>,
+    pub instructions: VecDeque<Instruction>,
+    /// More than one only with several tokens in the system
+    pub tokens: Vec<Token>,
+    pub is_req_sent: bool,
+    /// The priority of the request sent to the holder
+    pub sent_priority: usize,
+    /// additional variable introduced to keep the state consistent between iterations
+    pub self_req_issued: bool,
+    pub in_critical_section: bool,
+    /// Only in Naimi–Trehel: the node to pass the token to after leaving the
+    /// critical section
+    pub next: Option<NodeId>,
+    /// The nodes it is connected with in the (undirected) tree
+    pub neighbours: Vec<NodeId>,
+    pub crashed: bool,
+    /// The last recovery the node took part in
+    pub epoch: usize,
+    /// The state of the recovery in progress
+    pub wave: Option<Wave>,
+}
+
+impl SystemNodeData {
+    pub fn new(id: String, instructions: VecDeque<Instruction>) -> Self {
+        Self {
+            id,
+            request_queue: VecDeque::new(),
+            instructions,
+            tokens: Vec::new(),
+            is_req_sent: false,
+            sent_priority: 0,
+            self_req_issued: false,
+            in_critical_section: false,
+            next: None,
+            neighbours: Vec::new(),
+            crashed: false,
+            epoch: 0,
+            wave: None,
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct Instruction {
+    pub kind: TaskKind,
+    /// How long will the task execute in the critical section
+    pub duration: usize,
+    /// Requests with a higher priority are served first, only in Raymond's
+    /// algorithm
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub priority: usize,
+}
+
+fn is_zero(priority: &usize) -> bool {
+    *priority == 0
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskKind {
+    Idle,
+    CriticalSection,
+}
+
+/// All the nodes of the simulated system. The parent of a node in the tree is
+/// the neighbour through which the token can be reached (the holder), or in
+/// Naimi–Trehel the last node known to have requested it. The nodes
+/// communicate only by sending messages through the [`Channels`].
+#[derive(Debug, Default)]
+pub struct System {
+    pub tree: Tree<SystemNodeData>,
+    pub channels: Channels,
+    /// The names of the nodes by their ids, for the events
+    names: Vec<String>,
+    algorithm: Algorithm,
+    /// What happened since the events were taken last time
+    events: Vec<TraceRecord>,
+    /// Scheduled crashes: the node and the iteration
+    pub(crate) crashes: Vec<(NodeId, usize)>,
+    /// Crashes which happened but have not been noticed yet: the node and
+    /// the iteration in which its neighbours notice it
+    pub(crate) undetected: Vec<(NodeId, usize)>,
+    /// How many iterations it takes the neighbours to notice a crash
+    pub(crate) detection_delay: usize,
+    /// The last recovery started in the system
+    pub(crate) epoch: usize,
+    /// Every that many iterations of waiting in a queue raise the priority of
+    /// a request by one, no ageing if zero
+    pub(crate) ageing: usize,
+    /// The current iteration
+    pub(crate) now: usize,
+    /// Counts everything that moves the simulation forward: executed task
+    /// steps, sent and delivered messages
+    pub(crate) steps: usize,
+}
+
+impl System {
+    /// An empty system in which the messages take `default_delay` iterations
+    /// to get delivered
+    pub fn new(default_delay: usize) -> Self {
+        Self {
+            channels: Channels::new(default_delay),
+            ..Default::default()
+        }
+    }
+    pub fn with_algorithm(mut self, algorithm: Algorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+    pub fn with_ageing(mut self, ageing: usize) -> Self {
+        self.ageing = ageing;
+        self
+    }
+    /// How many iterations it takes the neighbours to notice a crash
+    pub fn with_detection_delay(mut self, detection_delay: usize) -> Self {
+        self.detection_delay = detection_delay;
+        self
+    }
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+    pub fn add_node(&mut self, data: SystemNodeData) -> NodeId {
+        self.channels.add_node();
+        self.names.push(data.id.clone());
+        self.tree.root(data)
+    }
+    /// Makes the nodes neighbours in the undirected tree
+    pub fn connect(&mut self, a: NodeId, b: NodeId) {
+        self.tree.data_mut(a).neighbours.push(b);
+        self.tree.data_mut(b).neighbours.push(a);
+    }
+    pub fn ids(&self) -> std::ops::Range<NodeId> {
+        self.tree.ids()
+    }
+    pub fn is_done(&self, node: NodeId) -> bool {
+        let node_data = self.tree.data(node);
+        node_data.crashed || node_data.request_queue.is_empty() && node_data.instructions.is_empty()
+    }
+    /// Whether all the nodes are done and there are no more messages to deliver
+    pub fn all_done(&self) -> bool {
+        self.ids().all(|node| self.is_done(node)) && self.channels.is_empty()
+    }
+    pub fn id(&self, node: NodeId) -> &str {
+        &self.tree.data(node).id
+    }
+    pub fn queue_status(&self, node: NodeId) -> Vec<&str> {
+        self.tree
+            .data(node)
+            .request_queue
+            .iter()
+            .map(|request| self.id(request.node))
+            .collect()
+    }
+    pub fn now(&self) -> usize {
+        self.now
+    }
+    pub fn next_iteration(&mut self) {
+        self.now += 1;
+        self.apply_crashes();
+    }
+    /// Everything that happened since the last call, in order
+    pub fn take_events(&mut self) -> Vec<TraceRecord> {
+        std::mem::take(&mut self.events)
+    }
+    /// Prints what happened at the node and keeps it for the trace
+    pub(crate) fn log(&mut self, node: NodeId, event: Event) {
+        println!(
+            "{}",
+            format!("--- Node {} {event}", self.id(node)).bright_blue()
+        );
+        self.events.push(TraceRecord {
+            iteration: self.now,
+            node: self.id(node).to_string(),
+            event,
+        });
+    }
+    /// Grows whenever anything happens in the system, if it stays the same
+    /// over an iteration nothing happened in it
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+    /// The state of every node and of the messages in flight, useful to find
+    /// out why the simulation got stuck
+    pub fn diagnostics(&self) -> String {
+        let mut lines = Vec::new();
+        for node in self.ids() {
+            let node_data = self.tree.data(node);
+            lines.push(format!(
+                "Node {}: parent: {}, token: {}, is_req_sent: {}, in_critical_section: {}, request_queue: {:?}, tasks left: {}",
+                node_data.id,
+                self.tree.parent(node).map(|p| self.id(p)).unwrap_or("none (root)"),
+                match node_data.tokens.len() {
+                    0 => "none".to_string(),
+                    1 => "held".to_string(),
+                    count => format!("{count} held"),
+                },
+                node_data.is_req_sent,
+                node_data.in_critical_section,
+                self.queue_status(node),
+                node_data.instructions.len()
+            ));
+            if let Some(next) = node_data.next {
+                lines.push(format!("Node {}: next: {}", node_data.id, self.id(next)));
+            }
+            if node_data.crashed {
+                lines.push(format!("Node {}: crashed", node_data.id));
+            }
+            if let Some(wave) = &node_data.wave {
+                lines.push(format!("Node {}: recovery {wave:?}", node_data.id));
+            }
+        }
+        for (to, msg) in self.channels.in_flight() {
+            lines.push(format!(
+                "Message {:?} from {} to {} arriving in iteration {}",
+                msg.msg,
+                self.id(msg.from),
+                self.id(to),
+                msg.deliver_at
+            ));
+        }
+        lines.join("\n")
+    }
+    /// The turn of the node in the current iteration. It first handles all
+    /// the messages which have reached it and then executes its task.
+    pub fn iterate(&mut self, node: NodeId) {
+        if self.tree.data(node).crashed {
+            return;
+        }
+        for msg in self.channels.deliver(node, self.now) {
+            self.steps += 1;
+            self.recv_msg(node, msg.from, msg.msg);
+        }
+        let step = self.drive(node, |node| {
+            let step = node.start_step();
+            if step {
+                node.finish_step();
+            } else if node.waiting() {
+                node.log(Event::Wait);
+            }
+            step
+        });
+        if step {
+            self.steps += 1;
+        }
+    }
+    /// Lets the node act on its own, then sends its messages and reports its
+    /// events
+    pub(crate) fn drive<R>(&mut self, node: NodeId, act: impl FnOnce(&mut Node) -> R) -> R {
+        let (parent, data) = self.tree.node_mut(node);
+        let mut state = Node {
+            id: node,
+            data,
+            parent,
+            names: &self.names,
+            algorithm: self.algorithm,
+            ageing: self.ageing,
+            now: self.now,
+            outbox: Outbox::default(),
+        };
+        let result = act(&mut state);
+        let Outbox { messages, events } = state.outbox;
+        for event in events {
+            self.log(node, event);
+        }
+        for (to, msg) in messages {
+            self.send(node, to, msg);
+        }
+        result
+    }
+    pub(crate) fn send(&mut self, from: NodeId, to: NodeId, msg: SystemMsg) {
+        self.steps += 1;
+        // whatever is sent to a crashed node is lost
+        if !self.tree.data(to).crashed {
+            self.channels.send(from, to, msg, self.now);
+        }
+    }
+    fn recv_msg(&mut self, node: NodeId, from: NodeId, msg: SystemMsg) {
+        match msg {
+            SystemMsg::Probe { .. } | SystemMsg::Echo { .. } | SystemMsg::RecoveryDone { .. } => {
+                self.recovery_recv_msg(node, from, msg)
+            }
+            msg => self.drive(node, |node| node.recv_msg(from, msg)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{description::SystemDescription, invariants::InvariantChecker};
+
+    /// A holds the token, B is its child and wants the critical section
+    fn handover() -> System {
+        let description: SystemDescription = serde_json::from_str(
+            r#"{"nodes": {
+                "A": {"instructions": [], "parent": null},
+                "B": {"instructions": [{"kind": "critical_section", "duration": 2}], "parent": "A"}
+            }}"#,
+        )
+        .unwrap();
+        description.build_system()
+    }
+
+    fn iteration(system: &mut System) {
+        system.next_iteration();
+        for node in system.ids() {
+            system.iterate(node);
+        }
+    }
+
+    #[test]
+    fn token_travels_to_the_requester() {
+        let mut system = handover();
+        iteration(&mut system);
+        assert!(system.tree.data(1).is_req_sent);
+        assert!(!system.channels.is_empty());
+        iteration(&mut system);
+        // the token is on its way, A already points at B
+        assert_eq!(system.tree.parent(0), Some(1));
+        iteration(&mut system);
+        assert!(system.tree.data(1).tokens.len() == 1);
+        assert!(system.tree.data(1).in_critical_section);
+        iteration(&mut system);
+        assert!(system.all_done());
+    }
+
+    #[test]
+    fn waiting_is_reported_every_iteration() {
+        let mut system = handover();
+        let mut waited = Vec::new();
+        while !system.all_done() {
+            iteration(&mut system);
+            for record in system.take_events() {
+                if record.event == Event::Wait {
+                    waited.push(record.iteration);
+                }
+            }
+        }
+        // from the request until the token came in the third iteration
+        assert_eq!(waited, [1, 2]);
+    }
+
+    #[test]
+    fn steps_count_the_tasks_and_the_messages() {
+        let mut system = handover();
+        iteration(&mut system);
+        // the request sent
+        assert_eq!(system.steps(), 1);
+        iteration(&mut system);
+        // delivered and answered with the token
+        assert_eq!(system.steps(), 3);
+        let steps = system.steps();
+        iteration(&mut system);
+        iteration(&mut system);
+        assert!(system.all_done());
+        iteration(&mut system);
+        // nothing happens once everything is done
+        assert_eq!(system.steps(), steps + 3);
+    }
+
+    #[test]
+    fn diagnostics_show_the_nodes_and_the_messages() {
+        let mut system = handover();
+        iteration(&mut system);
+        let diagnostics = system.diagnostics();
+        assert!(diagnostics.contains("Node B: parent: A, token: none, is_req_sent: true"));
+        assert!(diagnostics.contains("Node A: parent: none (root), token: held"));
+        assert!(diagnostics.contains(
+            "Message Request { origin: 1, epoch: 0, priority: 0, raise: false } from B to A arriving in iteration 2"
+        ));
+    }
+
+    /// A holds the token and uses it for 3 iterations, B and C are its
+    /// children and ask for it with the given priorities in the meantime
+    fn contest(b: usize, c: usize) -> System {
+        let description: SystemDescription = serde_json::from_str(&format!(
+            r#"{{"nodes": {{
+                "A": {{"instructions": [{{"kind": "critical_section", "duration": 3}}], "parent": null}},
+                "B": {{"instructions": [{{"kind": "critical_section", "duration": 1, "priority": {b}}}], "parent": "A"}},
+                "C": {{"instructions": [{{"kind": "critical_section", "duration": 1, "priority": {c}}}], "parent": "A"}}
+            }}}}"#
+        ))
+        .unwrap();
+        description.build_system()
+    }
+
+    /// The nodes in the order they entered the critical section
+    fn entries(system: &mut System) -> Vec<String> {
+        let mut entered = Vec::new();
+        while !system.all_done() {
+            iteration(system);
+            entered.extend(
+                system
+                    .take_events()
+                    .into_iter()
+                    .filter(|record| record.event == Event::CsEnter)
+                    .map(|record| record.node),
+            );
+        }
+        entered
+    }
+
+    #[test]
+    fn higher_priority_goes_first() {
+        assert_eq!(entries(&mut contest(0, 0)), ["A", "B", "C"]);
+        assert_eq!(entries(&mut contest(0, 2)), ["A", "C", "B"]);
+    }
+
+    #[test]
+    fn two_tokens_let_two_nodes_in_at_once() {
+        let description: SystemDescription = serde_json::from_str(
+            r#"{"tokens": 2, "nodes": {
+                "A": {"instructions": [], "parent": null},
+                "B": {"instructions": [{"kind": "critical_section", "duration": 3}], "parent": "A"},
+                "C": {"instructions": [{"kind": "critical_section", "duration": 3}], "parent": "A"}
+            }}"#,
+        )
+        .unwrap();
+        let mut system = description.build_system();
+        let mut checker = InvariantChecker::new(100, 2);
+        let mut together = false;
+        while !system.all_done() {
+            iteration(&mut system);
+            assert_eq!(checker.check(&system), Ok(()));
+            together |= system
+                .ids()
+                .all(|node| node == 0 || system.tree.data(node).in_critical_section);
+        }
+        assert!(together);
+        // A gave away both tokens, so it points at the last one served
+        assert!(system.tree.data(0).tokens.is_empty());
+        assert_eq!(system.tree.parent(0), Some(2));
+    }
+
+    #[test]
+    fn raised_request_replaces_the_waiting_one() {
+        let mut system = handover();
+        iteration(&mut system);
+        iteration(&mut system);
+        // B asks again with a higher priority while its request waits at A
+        system.tree.data_mut(0).in_critical_section = true;
+        let waiting = QueuedRequest {
+            node: 1,
+            priority: 0,
+            since: system.now(),
+        };
+        system.tree.data_mut(0).request_queue.push_back(waiting);
+        let msg = SystemMsg::Request {
+            origin: 1,
+            epoch: 0,
+            priority: 3,
+            raise: true,
+        };
+        let now = system.now();
+        system.channels.send(1, 0, msg, now);
+        iteration(&mut system);
+        let queue = &system.tree.data(0).request_queue;
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue[0].priority, 3);
+    }
+}
@@ -0,0 +1,443 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{Receiver, RecvTimeoutError, Sender, TryRecvError},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    node::{Node, Outbox},
+    system::{Algorithm, System, SystemMsg, SystemNodeData},
+    trace::{Event, TraceRecord},
+    tree::NodeId,
+};
+
+/// The current tick, counted from 1 like the iterations
+fn ticks(start: Instant, tick: Duration) -> usize {
+    (start.elapsed().as_nanos() / tick.as_nanos()) as usize + 1
+}
+
+/// What the thread of a node receives
+enum NodeMsg {
+    Deliver(Envelope),
+    /// Sent by the main thread at the end, the node stops right away
+    Stop,
+}
+
+/// A message from another node, the receiver holds it back until the delay
+/// of the link is over
+struct Envelope {
+    from: NodeId,
+    deliver_at: Instant,
+    msg: SystemMsg,
+}
+
+/// The thread of a node, it drives the [`Node`] just as the [`System`] does
+/// in the iterations but with the messages going through channels and the
+/// steps taking real time
+struct NodeThread {
+    node: NodeId,
+    data: SystemNodeData,
+    parent: Option<NodeId>,
+    /// The names of all the nodes, for the events
+    names: Arc<Vec<String>>,
+    /// The inboxes of all the nodes with the delays of the links to them
+    links: Vec<(Sender<NodeMsg>, Duration)>,
+    /// The delay of the requests instead of the one of the link
+    request_delay: Option<Duration>,
+    /// When the last message sent to each of the nodes gets delivered, a
+    /// later one never overtakes it
+    last_sent: Vec<Instant>,
+    inbox: Receiver<NodeMsg>,
+    /// The messages which have come but whose delay is not over yet
+    arrived: Vec<Envelope>,
+    /// The events with the length of the queue right after them
+    reports: Sender<(TraceRecord, usize)>,
+    /// How many nodes have something to do plus how many messages have not
+    /// been handled yet, nothing can happen anymore once it drops to zero
+    busy: Arc<AtomicUsize>,
+    /// Whether the node itself is counted in `busy`
+    counted: bool,
+    /// The last tick the node reported waiting in
+    waited_in: usize,
+    algorithm: Algorithm,
+    ageing: usize,
+    tick: Duration,
+    start: Instant,
+}
+
+impl NodeThread {
+    fn run(mut self) -> Self {
+        loop {
+            loop {
+                match self.inbox.try_recv() {
+                    Ok(NodeMsg::Deliver(envelope)) => self.arrived.push(envelope),
+                    Ok(NodeMsg::Stop) | Err(TryRecvError::Disconnected) => return self,
+                    Err(TryRecvError::Empty) => break,
+                }
+            }
+            self.deliver();
+            let worked = self.execute_task();
+            self.update_busy();
+            if worked {
+                continue;
+            }
+
+            // nothing to do until a message comes or its delay is over, a
+            // node waiting for the critical section says so every tick
+            let mut wake_at = self
+                .arrived
+                .iter()
+                .map(|envelope| envelope.deliver_at)
+                .min();
+            if self.data.self_req_issued && !self.data.in_critical_section {
+                let now = ticks(self.start, self.tick);
+                if self.waited_in < now {
+                    self.waited_in = now;
+                    self.act(|node| node.log(Event::Wait));
+                }
+                let next_tick = self.start + self.tick * now as u32;
+                wake_at = Some(wake_at.map_or(next_tick, |at| at.min(next_tick)));
+            }
+            let msg = match wake_at {
+                Some(at) => {
+                    match self
+                        .inbox
+                        .recv_timeout(at.saturating_duration_since(Instant::now()))
+                    {
+                        Ok(msg) => msg,
+                        Err(RecvTimeoutError::Timeout) => continue,
+                        Err(RecvTimeoutError::Disconnected) => return self,
+                    }
+                }
+                None => match self.inbox.recv() {
+                    Ok(msg) => msg,
+                    Err(_) => return self,
+                },
+            };
+            match msg {
+                NodeMsg::Deliver(envelope) => self.arrived.push(envelope),
+                NodeMsg::Stop => return self,
+            }
+        }
+    }
+
+    /// Lets the node act, then reports its events and sends its messages
+    fn act<R>(&mut self, act: impl FnOnce(&mut Node) -> R) -> R {
+        let now = ticks(self.start, self.tick);
+        let mut node = Node {
+            id: self.node,
+            data: &mut self.data,
+            parent: &mut self.parent,
+            names: &self.names,
+            algorithm: self.algorithm,
+            ageing: self.ageing,
+            now,
+            outbox: Outbox::default(),
+        };
+        let result = act(&mut node);
+        let Outbox { messages, events } = node.outbox;
+        for event in events {
+            let record = TraceRecord {
+                iteration: now,
+                node: self.names[self.node].clone(),
+                event,
+            };
+            // nobody might be listening anymore, which is not a reason to stop
+            let _ = self.reports.send((record, self.data.request_queue.len()));
+        }
+        for (to, msg) in messages {
+            self.send(to, msg);
+        }
+        result
+    }
+
+    /// Keeps the node counted in `busy` as long as it has tasks or requests
+    /// to serve
+    fn update_busy(&mut self) {
+        let busy = !self.data.instructions.is_empty() || !self.data.request_queue.is_empty();
+        if busy && !self.counted {
+            self.busy.fetch_add(1, Ordering::SeqCst);
+        } else if !busy && self.counted {
+            self.busy.fetch_sub(1, Ordering::SeqCst);
+        }
+        self.counted = busy;
+    }
+
+    fn send(&mut self, to: NodeId, msg: SystemMsg) {
+        let (link, delay) = &self.links[to];
+        let delay = match (&msg, self.request_delay) {
+            (SystemMsg::Request { .. }, Some(delay)) => delay,
+            _ => *delay,
+        };
+        let deliver_at = (Instant::now() + delay).max(self.last_sent[to]);
+        self.last_sent[to] = deliver_at;
+        // counted before it leaves, so that `busy` cannot drop to zero while
+        // the message is on its way
+        self.busy.fetch_add(1, Ordering::SeqCst);
+        let envelope = Envelope {
+            from: self.node,
+            deliver_at,
+            msg,
+        };
+        // the receiver keeps its inbox until the end, even when stopped
+        let _ = link.send(NodeMsg::Deliver(envelope));
+    }
+
+    /// Handles the messages whose delay is over, the earliest first
+    fn deliver(&mut self) {
+        let now = Instant::now();
+        let (mut due, waiting): (Vec<_>, Vec<_>) = std::mem::take(&mut self.arrived)
+            .into_iter()
+            .partition(|envelope| envelope.deliver_at <= now);
+        self.arrived = waiting;
+        due.sort_by_key(|envelope| envelope.deliver_at);
+        for envelope in due {
+            self.act(|node| node.recv_msg(envelope.from, envelope.msg));
+            self.update_busy();
+            // whatever the message caused is counted by now
+            self.busy.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Makes a step of the current task, which takes a tick. Returns false if
+    /// there is nothing to do but to wait for the messages.
+    fn execute_task(&mut self) -> bool {
+        if !self.act(|node| node.start_step()) {
+            return false;
+        }
+        // the step takes the rest of the tick, so the next one starts in the
+        // next tick even if the thread did not get to run on time
+        let end = self.start + self.tick * ticks(self.start, self.tick) as u32;
+        std::thread::sleep(end.saturating_duration_since(Instant::now()));
+        self.act(|node| node.finish_step());
+        true
+    }
+}
+
+/// What the main thread learns from the running system
+pub enum Update {
+    /// Something happened at a node, with the length of its request queue
+    /// right after that
+    Event(TraceRecord, usize),
+    /// Nothing happened in the last tick
+    Quiet,
+    /// All the nodes are done, no message is on its way and all the events
+    /// have been taken
+    Finished,
+}
+
+/// The system with every node running on its own thread, the nodes talk to
+/// each other through channels only. The durations of the tasks and the
+/// delays of the links are measured in ticks of real time.
+pub struct ThreadedSystem {
+    /// The system without its nodes, they come back when the threads stop
+    system: System,
+    threads: Vec<JoinHandle<NodeThread>>,
+    links: Vec<Sender<NodeMsg>>,
+    reports: Receiver<(TraceRecord, usize)>,
+    busy: Arc<AtomicUsize>,
+    tick: Duration,
+    start: Instant,
+}
+
+impl ThreadedSystem {
+    /// Takes the nodes out of the system and starts a thread for each of them
+    pub fn start(mut system: System, tick: Duration) -> Self {
+        let nodes = system.tree.take_nodes();
+        let names = Arc::new(
+            nodes
+                .iter()
+                .map(|(_, data)| data.id.clone())
+                .collect::<Vec<_>>(),
+        );
+        let (links, inboxes): (Vec<_>, Vec<_>) =
+            nodes.iter().map(|_| std::sync::mpsc::channel()).unzip();
+        let (reports_tx, reports) = std::sync::mpsc::channel();
+        let busy = Arc::new(AtomicUsize::new(0));
+        let start = Instant::now();
+
+        // all the nodes are counted before any of them starts
+        let nodes = nodes
+            .into_iter()
+            .zip(inboxes)
+            .enumerate()
+            .map(|(node, ((parent, data), inbox))| {
+                let counted = !data.instructions.is_empty() || !data.request_queue.is_empty();
+                if counted {
+                    busy.fetch_add(1, Ordering::SeqCst);
+                }
+                NodeThread {
+                    node,
+                    data,
+                    parent,
+                    names: names.clone(),
+                    links: links
+                        .iter()
+                        .enumerate()
+                        .map(|(to, link)| {
+                            let delay = system.channels.delay(node, to) as u32;
+                            (link.clone(), tick * delay)
+                        })
+                        .collect(),
+                    inbox,
+                    arrived: Vec::new(),
+                    reports: reports_tx.clone(),
+                    busy: busy.clone(),
+                    counted,
+                    waited_in: 0,
+                    request_delay: system
+                        .channels
+                        .request_delay()
+                        .map(|delay| tick * delay as u32),
+                    last_sent: vec![start; links.len()],
+                    algorithm: system.algorithm(),
+                    ageing: system.ageing,
+                    tick,
+                    start,
+                }
+            })
+            .collect::<Vec<_>>();
+        let threads = nodes
+            .into_iter()
+            .map(|node| std::thread::spawn(move || node.run()))
+            .collect();
+
+        Self {
+            system,
+            threads,
+            links,
+            reports,
+            busy,
+            tick,
+            start,
+        }
+    }
+
+    pub fn ticks(&self) -> usize {
+        ticks(self.start, self.tick)
+    }
+
+    /// Waits for something to happen, at most a tick
+    pub fn next_update(&self) -> Update {
+        // a node sends all its events before it stops being counted as busy
+        let finished = self.busy.load(Ordering::SeqCst) == 0;
+        let received = if finished {
+            self.reports.try_recv().ok()
+        } else {
+            self.reports.recv_timeout(self.tick).ok()
+        };
+        match received {
+            Some((record, queue)) => Update::Event(record, queue),
+            None if finished => Update::Finished,
+            None => Update::Quiet,
+        }
+    }
+
+    /// Stops the threads and puts the nodes back into the system, with the
+    /// messages they have not handled yet in its channels
+    pub fn stop(self) -> System {
+        for link in &self.links {
+            // a thread which is gone has panicked, that shows up below
+            let _ = link.send(NodeMsg::Stop);
+        }
+        let nodes = self
+            .threads
+            .into_iter()
+            .map(|thread| thread.join().expect("a node thread panicked"))
+            .collect::<Vec<_>>();
+
+        let mut system = self.system;
+        system.now = ticks(self.start, self.tick);
+        let mut unhandled = Vec::new();
+        for node in nodes {
+            let id = system.tree.root(node.data);
+            if let Some(parent) = node.parent {
+                system.tree.make_child_of(id, parent);
+            }
+            let waiting = node.inbox.try_iter().filter_map(|msg| match msg {
+                NodeMsg::Deliver(envelope) => Some(envelope),
+                NodeMsg::Stop => None,
+            });
+            unhandled.extend(node.arrived.into_iter().chain(waiting).map(|e| (id, e)));
+        }
+        for (to, envelope) in unhandled {
+            let now = system.now;
+            system.channels.send(envelope.from, to, envelope.msg, now);
+        }
+        system
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{description::SystemDescription, invariants::InvariantChecker, trace::Event};
+
+    /// A holds the token, B and C below it both want the critical section
+    fn contest(algorithm: Algorithm) -> System {
+        let description: SystemDescription = serde_json::from_str(
+            r#"{"nodes": {
+                "A": {"instructions": [{"kind": "idle", "duration": 1}], "parent": null},
+                "B": {"instructions": [{"kind": "critical_section", "duration": 2}], "parent": "A"},
+                "C": {"instructions": [{"kind": "critical_section", "duration": 1}], "parent": "A"}
+            }}"#,
+        )
+        .unwrap();
+        description.build_system().with_algorithm(algorithm)
+    }
+
+    /// Runs the system on the threads until it finishes, returns it with the
+    /// nodes which entered the critical section in order
+    fn run(system: System) -> (System, Vec<String>) {
+        let running = ThreadedSystem::start(system, Duration::from_millis(1));
+        let mut checker = InvariantChecker::new(0, 1);
+        let mut entered = Vec::new();
+        loop {
+            match running.next_update() {
+                Update::Event(record, _) => {
+                    assert_eq!(checker.check_record(&record), Ok(()));
+                    if record.event == Event::CsEnter {
+                        entered.push(record.node);
+                    }
+                }
+                Update::Quiet => assert!(running.ticks() < 5000, "the threads got stuck"),
+                Update::Finished => break,
+            }
+        }
+        (running.stop(), entered)
+    }
+
+    fn check_finished(system: &System, mut entered: Vec<String>) {
+        assert!(system.all_done());
+        assert!(system.channels.is_empty());
+        let holders = system
+            .ids()
+            .filter(|&node| system.tree.data(node).tokens.len() == 1)
+            .count();
+        assert_eq!(holders, 1);
+        entered.sort();
+        assert_eq!(entered, ["B", "C"]);
+    }
+
+    #[test]
+    fn raymond_serves_everyone_on_the_threads() {
+        let (system, entered) = run(contest(Algorithm::Raymond));
+        check_finished(&system, entered);
+        // the token holder is the root of the tree put back together
+        let holder = system
+            .ids()
+            .find(|&node| system.tree.data(node).tokens.len() == 1)
+            .unwrap();
+        assert_eq!(system.tree.parent(holder), None);
+    }
+
+    #[test]
+    fn naimi_trehel_serves_everyone_on_the_threads() {
+        let (system, entered) = run(contest(Algorithm::NaimiTrehel));
+        check_finished(&system, entered);
+    }
+}
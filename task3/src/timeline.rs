@@ -0,0 +1,203 @@
+use std::fmt::Display;
+
+use crate::{
+    system::System,
+    trace::{Event, TraceRecord},
+};
+
+/// What a node does in a tick, the later ones win if there are several in
+/// the same tick
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Phase {
+    /// No task left, or nothing has been recorded
+    #[default]
+    Nothing,
+    Idle,
+    Wait,
+    Cs,
+    Crashed,
+}
+
+impl Phase {
+    fn symbol(self) -> char {
+        match self {
+            Phase::Nothing => ' ',
+            Phase::Idle => '.',
+            Phase::Wait => 'w',
+            Phase::Cs => '#',
+            Phase::Crashed => 'x',
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct NodeTimeline {
+    name: String,
+    /// The first tick goes first
+    phases: Vec<Phase>,
+    /// When the request the node waits with was produced
+    requested_at: Option<usize>,
+    crashed_at: Option<usize>,
+}
+
+impl NodeTimeline {
+    fn set(&mut self, tick: usize, phase: Phase) {
+        let idx = tick.saturating_sub(1);
+        if self.phases.len() <= idx {
+            self.phases.resize(idx + 1, Phase::Nothing);
+        }
+        self.phases[idx] = self.phases[idx].max(phase);
+    }
+}
+
+/// What every node did in every tick, put together from the events and
+/// printed as a Gantt chart at the end of the run
+#[derive(Debug)]
+pub struct Timeline {
+    /// In the order of the nodes in the system
+    nodes: Vec<NodeTimeline>,
+    /// The last tick with an event
+    end: usize,
+}
+
+impl Timeline {
+    pub fn new(system: &System) -> Self {
+        Self {
+            nodes: system
+                .ids()
+                .map(|node| NodeTimeline {
+                    name: system.id(node).to_string(),
+                    ..Default::default()
+                })
+                .collect(),
+            end: 0,
+        }
+    }
+
+    pub fn record(&mut self, record: &TraceRecord) {
+        let tick = record.iteration;
+        self.end = self.end.max(tick);
+        let node = self
+            .nodes
+            .iter_mut()
+            .find(|node| node.name == record.node)
+            .expect("the events come from the nodes of the system");
+        match record.event {
+            Event::Idle => node.set(tick, Phase::Idle),
+            Event::Wait => node.set(tick, Phase::Wait),
+            Event::Cs => node.set(tick, Phase::Cs),
+            Event::RequestProduced { .. } => node.requested_at = Some(tick),
+            // the threads do not report every tick of waiting
+            Event::CsEnter => {
+                if let Some(since) = node.requested_at.take() {
+                    for tick in since..tick {
+                        node.set(tick, Phase::Wait);
+                    }
+                }
+            }
+            Event::Crashed => node.crashed_at = Some(tick),
+            _ => {}
+        }
+    }
+}
+
+impl Display for Timeline {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Timeline (. idle, w waiting, # critical section, x crashed):"
+        )?;
+        // the first tick and every tenth one, if there is room for them
+        let mut header = String::new();
+        for tick in (1..=self.end).filter(|tick| *tick == 1 || tick % 10 == 0) {
+            if header.len() > tick - 1 {
+                continue;
+            }
+            header.push_str(&" ".repeat(tick - 1 - header.len()));
+            header.push_str(&tick.to_string());
+        }
+        write!(f, "{:<12} {header}", "tick")?;
+        for node in &self.nodes {
+            let line = (1..=self.end)
+                .map(|tick| match node.crashed_at {
+                    Some(at) if at <= tick => Phase::Crashed,
+                    _ => node.phases.get(tick - 1).copied().unwrap_or(Phase::Nothing),
+                })
+                .map(Phase::symbol)
+                .collect::<String>();
+            write!(f, "\n{:<12} {}", node.name, line.trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::description::SystemDescription;
+
+    /// Only the nodes matter, the events are made up
+    fn system() -> System {
+        let description: SystemDescription = serde_json::from_str(
+            r#"{"nodes": {
+                "A": {"instructions": [], "parent": null},
+                "B": {"instructions": [
+                    {"kind": "idle", "duration": 1},
+                    {"kind": "critical_section", "duration": 2}
+                ], "parent": "A"},
+                "C": {"instructions": [], "parent": "A"}
+            }}"#,
+        )
+        .unwrap();
+        description.build_system()
+    }
+
+    fn record(iteration: usize, node: &str, event: Event) -> TraceRecord {
+        TraceRecord {
+            iteration,
+            node: node.to_string(),
+            event,
+        }
+    }
+
+    #[test]
+    fn every_tick_gets_a_symbol() {
+        let mut timeline = Timeline::new(&system());
+        timeline.record(&record(1, "B", Event::Idle));
+        timeline.record(&record(2, "B", Event::RequestProduced { priority: 0 }));
+        timeline.record(&record(2, "B", Event::Wait));
+        timeline.record(&record(3, "B", Event::Wait));
+        timeline.record(&record(4, "B", Event::CsEnter));
+        timeline.record(&record(4, "B", Event::Cs));
+        timeline.record(&record(5, "B", Event::Cs));
+        timeline.record(&record(3, "C", Event::Crashed));
+        assert_eq!(
+            timeline.to_string(),
+            [
+                "Timeline (. idle, w waiting, # critical section, x crashed):",
+                "tick         1",
+                "A            ",
+                "B            .ww##",
+                "C              xxx",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn waiting_is_filled_in_when_not_reported() {
+        let mut timeline = Timeline::new(&system());
+        timeline.record(&record(2, "B", Event::RequestProduced { priority: 0 }));
+        timeline.record(&record(5, "B", Event::CsEnter));
+        timeline.record(&record(5, "B", Event::Cs));
+        assert!(timeline.to_string().contains("\nB             www#"));
+    }
+
+    #[test]
+    fn header_marks_every_tenth_tick() {
+        let mut timeline = Timeline::new(&system());
+        timeline.record(&record(21, "A", Event::Idle));
+        let header = format!("{:<12} 1{}10{}20", "tick", " ".repeat(8), " ".repeat(8));
+        assert!(timeline.to_string().contains(&format!("\n{header}\n")));
+    }
+}
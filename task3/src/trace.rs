@@ -0,0 +1,189 @@
+use std::{fmt::Display, io::Write};
+
+/// Something that happened at a node, the other nodes are referred to by
+/// their names
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case", tag = "kind")]
+pub enum Event {
+    Idle,
+    /// A tick spent waiting for the critical section
+    Wait,
+    RequestProduced {
+        #[serde(default)]
+        priority: usize,
+    },
+    RequestSent {
+        to: String,
+    },
+    RequestReceived {
+        from: String,
+        origin: String,
+    },
+    RequestForwarded {
+        to: String,
+        origin: String,
+    },
+    TokenPassed {
+        to: String,
+    },
+    TokenReceived {
+        from: String,
+    },
+    BecameRoot,
+    CsEnter,
+    Cs,
+    CsExit,
+    Crashed,
+    CrashDetected {
+        node: String,
+    },
+    RecoveryStarted {
+        epoch: usize,
+    },
+    TokenDiscarded {
+        from: String,
+    },
+    TokenRegenerated,
+    RecoveryFinished {
+        regenerated: bool,
+    },
+    /// A probe, an echo or the end of the recovery
+    RecoverySent {
+        to: String,
+        message: String,
+    },
+}
+
+impl Display for Event {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Event::Idle => write!(f, "executes the idle task"),
+            Event::Wait => write!(f, "waits for the token"),
+            Event::RequestProduced { priority: 0 } => write!(f, "produced a request"),
+            Event::RequestProduced { priority } => {
+                write!(f, "produced a request with priority {priority}")
+            }
+            Event::RequestSent { to } => write!(f, "sends a request to {to}"),
+            Event::RequestReceived { from, origin } if from == origin => {
+                write!(f, "received a request from {from}")
+            }
+            Event::RequestReceived { from, origin } => {
+                write!(f, "received a request of {origin} from {from}")
+            }
+            Event::RequestForwarded { to, origin } => {
+                write!(f, "forwards the request of {origin} to {to}")
+            }
+            Event::TokenPassed { to } => write!(f, "passes the token to {to}"),
+            Event::TokenReceived { from } => write!(f, "received a token from {from}"),
+            Event::BecameRoot => write!(f, "became a root"),
+            Event::CsEnter => write!(f, "enters the critical section"),
+            Event::Cs => write!(f, "executes in the critical section"),
+            Event::CsExit => write!(f, "leaves the critical section"),
+            Event::Crashed => write!(f, "crashed"),
+            Event::CrashDetected { node } => write!(f, "noticed that {node} crashed"),
+            Event::RecoveryStarted { epoch } => {
+                write!(f, "starts looking for the token (recovery {epoch})")
+            }
+            Event::TokenDiscarded { from } => {
+                write!(f, "discarded an outdated token from {from}")
+            }
+            Event::TokenRegenerated => write!(f, "regenerated the lost token"),
+            Event::RecoveryFinished { regenerated: true } => {
+                write!(f, "finished the recovery, the token had to be regenerated")
+            }
+            Event::RecoveryFinished { regenerated: false } => {
+                write!(f, "finished the recovery, the token is still there")
+            }
+            Event::RecoverySent { to, message } => write!(f, "sends a {message} to {to}"),
+        }
+    }
+}
+
+/// A line of the trace
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub iteration: usize,
+    pub node: String,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+/// Writes the records as JSON lines
+pub struct TraceWriter {
+    out: std::io::BufWriter<std::fs::File>,
+}
+
+impl TraceWriter {
+    pub fn create(path: &str) -> Self {
+        let file = std::fs::File::create(path).expect("failed to create the trace file");
+        Self {
+            out: std::io::BufWriter::new(file),
+        }
+    }
+    pub fn write(&mut self, record: &TraceRecord) {
+        serde_json::to_writer(&mut self.out, record).expect("failed to serialize a trace record");
+        writeln!(self.out).expect("failed to write to the trace file");
+    }
+    pub fn flush(&mut self) {
+        self.out.flush().expect("failed to write to the trace file");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_are_flat_json_objects() {
+        let record = TraceRecord {
+            iteration: 3,
+            node: "B".to_string(),
+            event: Event::RequestForwarded {
+                to: "A".to_string(),
+                origin: "C".to_string(),
+            },
+        };
+        let line = serde_json::to_string(&record).unwrap();
+        assert_eq!(
+            line,
+            r#"{"iteration":3,"node":"B","kind":"request_forwarded","to":"A","origin":"C"}"#
+        );
+        assert_eq!(serde_json::from_str::<TraceRecord>(&line).unwrap(), record);
+    }
+
+    #[test]
+    fn request_from_its_origin_is_not_repeated() {
+        let received = |from: &str, origin: &str| {
+            Event::RequestReceived {
+                from: from.to_string(),
+                origin: origin.to_string(),
+            }
+            .to_string()
+        };
+        assert_eq!(received("B", "B"), "received a request from B");
+        assert_eq!(received("B", "C"), "received a request of C from B");
+    }
+
+    #[test]
+    fn writer_puts_a_record_on_every_line() {
+        let path = std::env::temp_dir().join(format!("task3-trace-{}.jsonl", std::process::id()));
+        let records =
+            [Event::RequestProduced { priority: 2 }, Event::CsEnter].map(|event| TraceRecord {
+                iteration: 1,
+                node: "A".to_string(),
+                event,
+            });
+        let mut writer = TraceWriter::create(path.to_str().unwrap());
+        for record in &records {
+            writer.write(record);
+        }
+        writer.flush();
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let read = written
+            .lines()
+            .map(|line| serde_json::from_str::<TraceRecord>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(read, records);
+    }
+}
@@ -0,0 +1,114 @@
+/// Position of a node in the [`Tree`], stays valid for the whole lifetime of
+/// the tree as the nodes are never removed.
+pub type NodeId = usize;
+
+#[derive(Debug)]
+struct TreeNode<T> {
+    parent: Option<NodeId>,
+    data: T,
+}
+
+/// A tree stored in an arena. All the nodes live in a single vector and refer
+/// to their parents by their [`NodeId`]s so the relations can be rearranged
+/// freely without any shared ownership or fighting the borrow checker.
+#[derive(Debug)]
+pub struct Tree<T> {
+    nodes: Vec<TreeNode<T>>,
+}
+
+impl<T> Default for Tree<T> {
+    fn default() -> Self {
+        Self { nodes: Vec::new() }
+    }
+}
+
+impl<T> Tree<T> {
+    pub fn root(&mut self, data: T) -> NodeId {
+        self.nodes.push(TreeNode { parent: None, data });
+        self.nodes.len() - 1
+    }
+    pub fn make_child_of(&mut self, node: NodeId, parent: NodeId) {
+        self.nodes[node].parent = Some(parent);
+    }
+    pub fn make_root(&mut self, node: NodeId) {
+        self.nodes[node].parent = None;
+    }
+    pub fn parent(&self, node: NodeId) -> Option<NodeId> {
+        self.nodes[node].parent
+    }
+    pub fn data(&self, node: NodeId) -> &T {
+        &self.nodes[node].data
+    }
+    pub fn data_mut(&mut self, node: NodeId) -> &mut T {
+        &mut self.nodes[node].data
+    }
+    /// The parent pointer and the data of the node, to be changed together
+    pub fn node_mut(&mut self, node: NodeId) -> (&mut Option<NodeId>, &mut T) {
+        let node = &mut self.nodes[node];
+        (&mut node.parent, &mut node.data)
+    }
+    /// Identifiers of all the nodes in the order they were added
+    pub fn ids(&self) -> std::ops::Range<NodeId> {
+        0..self.nodes.len()
+    }
+    /// Takes all the nodes out with their parents, in the order of their
+    /// ids, and leaves the tree empty
+    pub fn take_nodes(&mut self) -> Vec<(Option<NodeId>, T)> {
+        std::mem::take(&mut self.nodes)
+            .into_iter()
+            .map(|node| (node.parent, node.data))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn children_point_at_their_parents() {
+        let mut tree = Tree::default();
+        let root = tree.root("root");
+        let child = tree.root("child");
+        let other = tree.root("other");
+        tree.make_child_of(child, root);
+        tree.make_child_of(other, child);
+
+        assert_eq!(tree.ids().collect::<Vec<_>>(), vec![root, child, other]);
+        assert_eq!(tree.parent(root), None);
+        assert_eq!(tree.parent(child), Some(root));
+        assert_eq!(tree.parent(other), Some(child));
+        assert_eq!(*tree.data(other), "other");
+    }
+
+    #[test]
+    fn moving_the_root_keeps_the_other_relations() {
+        let mut tree = Tree::default();
+        let ids = [1, 2, 3].map(|data| tree.root(data));
+        tree.make_child_of(ids[1], ids[0]);
+        tree.make_child_of(ids[2], ids[1]);
+
+        // what happens when the token moves from the first to the second
+        tree.make_child_of(ids[0], ids[1]);
+        tree.make_root(ids[1]);
+        assert_eq!(tree.parent(ids[1]), None);
+        assert_eq!(tree.parent(ids[0]), Some(ids[1]));
+        assert_eq!(tree.parent(ids[2]), Some(ids[1]));
+
+        *tree.data_mut(ids[2]) += 10;
+        assert_eq!(*tree.data(ids[2]), 13);
+    }
+
+    #[test]
+    fn parent_and_data_change_together() {
+        let mut tree = Tree::default();
+        let ids = [1, 2].map(|data| tree.root(data));
+        let (parent, data) = tree.node_mut(ids[1]);
+        *parent = Some(ids[0]);
+        *data += 10;
+        assert_eq!(tree.parent(ids[1]), Some(ids[0]));
+        assert_eq!(*tree.data(ids[1]), 12);
+        assert_eq!(tree.take_nodes(), [(None, 1), (Some(ids[0]), 12)]);
+        assert_eq!(tree.ids().count(), 0);
+    }
+}
@@ -0,0 +1,347 @@
+//! Cycles and strongly connected components of a wait-for graph. Every
+//! elementary cycle is found with Johnson's algorithm, the deadlocked sets are
+//! the strongly connected components that contain a cycle.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use crate::ProcessLabel;
+
+/// A wait-for graph in the order of the labels, so that the results do not
+/// depend on the order of a `HashMap`
+type Graph<'a> = BTreeMap<&'a ProcessLabel, BTreeSet<&'a ProcessLabel>>;
+
+fn ordered(waits_for: &HashMap<ProcessLabel, Vec<ProcessLabel>>) -> Graph<'_> {
+    let mut graph = Graph::new();
+    for (proc, waits) in waits_for {
+        graph.entry(proc).or_default().extend(waits);
+        for other in waits {
+            graph.entry(other).or_default();
+        }
+    }
+    graph
+}
+
+/// All the elementary cycles, each one starting with its smallest label, in
+/// the order of the labels
+pub fn elementary_cycles(
+    waits_for: &HashMap<ProcessLabel, Vec<ProcessLabel>>,
+) -> Vec<Vec<&ProcessLabel>> {
+    let graph = ordered(waits_for);
+    let mut cycles = Vec::new();
+    for &start in graph.keys() {
+        // only the cycles through `start` and the processes after it are
+        // left, and they all stay in the component of `start`
+        let rest = graph
+            .range::<&ProcessLabel, _>(start..)
+            .map(|(proc, waits)| {
+                (
+                    *proc,
+                    waits.range::<&ProcessLabel, _>(start..).copied().collect(),
+                )
+            })
+            .collect::<Graph>();
+        let Some(component) = strongly_connected(&rest)
+            .into_iter()
+            .find(|component| component.contains(start))
+        else {
+            continue;
+        };
+        let subgraph = rest
+            .into_iter()
+            .filter(|(proc, _)| component.contains(proc))
+            .map(|(proc, waits)| {
+                let waits = waits.into_iter().filter(|w| component.contains(w));
+                (proc, waits.collect())
+            })
+            .collect::<Graph>();
+        let mut search = CircuitSearch {
+            graph: &subgraph,
+            start,
+            stack: Vec::new(),
+            blocked: HashSet::new(),
+            unblock_with: HashMap::new(),
+            cycles: &mut cycles,
+        };
+        search.circuit(start);
+    }
+    cycles.sort();
+    cycles
+}
+
+/// The search for the cycles through a single process
+struct CircuitSearch<'g, 'a> {
+    graph: &'g Graph<'a>,
+    start: &'a ProcessLabel,
+    stack: Vec<&'a ProcessLabel>,
+    blocked: HashSet<&'a ProcessLabel>,
+    /// The processes to unblock once the key gets unblocked
+    unblock_with: HashMap<&'a ProcessLabel, HashSet<&'a ProcessLabel>>,
+    cycles: &'g mut Vec<Vec<&'a ProcessLabel>>,
+}
+
+impl<'a> CircuitSearch<'_, 'a> {
+    /// Returns whether a cycle has been closed through the process
+    fn circuit(&mut self, proc: &'a ProcessLabel) -> bool {
+        let mut found = false;
+        let graph = self.graph;
+        self.stack.push(proc);
+        self.blocked.insert(proc);
+        for &next in &graph[proc] {
+            if next == self.start {
+                self.cycles.push(self.stack.clone());
+                found = true;
+            } else if !self.blocked.contains(next) && self.circuit(next) {
+                found = true;
+            }
+        }
+        if found {
+            self.unblock(proc);
+        } else {
+            for &next in &graph[proc] {
+                self.unblock_with.entry(next).or_default().insert(proc);
+            }
+        }
+        self.stack.pop();
+        found
+    }
+
+    fn unblock(&mut self, proc: &'a ProcessLabel) {
+        self.blocked.remove(proc);
+        for other in self.unblock_with.remove(proc).unwrap_or_default() {
+            if self.blocked.contains(other) {
+                self.unblock(other);
+            }
+        }
+    }
+}
+
+/// The deadlocked sets: the strongly connected components with a cycle in
+/// them, in the order of their smallest labels
+pub fn deadlocked_sets(
+    waits_for: &HashMap<ProcessLabel, Vec<ProcessLabel>>,
+) -> Vec<BTreeSet<&ProcessLabel>> {
+    let graph = ordered(waits_for);
+    let mut sets = strongly_connected(&graph)
+        .into_iter()
+        .filter(|component| {
+            let first = component.first().unwrap();
+            // a single process only if it waits for itself
+            component.len() > 1 || graph[first].contains(first)
+        })
+        .collect::<Vec<_>>();
+    sets.sort();
+    sets
+}
+
+/// The knots: the deadlocked sets with no way out of them. In the OR model a
+/// cycle can be left through another resource that gets free, a knot cannot
+pub fn knots(waits_for: &HashMap<ProcessLabel, Vec<ProcessLabel>>) -> Vec<BTreeSet<&ProcessLabel>> {
+    let graph = ordered(waits_for);
+    deadlocked_sets(waits_for)
+        .into_iter()
+        .filter(|set| {
+            set.iter()
+                .all(|proc| graph[proc].iter().all(|next| set.contains(next)))
+        })
+        .collect()
+}
+
+/// The processes that are not deadlocked themselves but wait, directly or
+/// not, for a deadlocked one, so they are stuck as well
+pub fn waiting_on_deadlock<'a>(
+    waits_for: &'a HashMap<ProcessLabel, Vec<ProcessLabel>>,
+    deadlocked: &[BTreeSet<&'a ProcessLabel>],
+) -> BTreeSet<&'a ProcessLabel> {
+    let graph = ordered(waits_for);
+    let mut stuck = deadlocked
+        .iter()
+        .flatten()
+        .copied()
+        .collect::<BTreeSet<_>>();
+    let mut waiting = BTreeSet::new();
+    // until nothing more waits for a stuck process
+    loop {
+        let more = graph
+            .iter()
+            .filter(|(proc, waits)| {
+                !stuck.contains(*proc) && waits.iter().any(|w| stuck.contains(w))
+            })
+            .map(|(proc, _)| *proc)
+            .collect::<Vec<_>>();
+        if more.is_empty() {
+            return waiting;
+        }
+        stuck.extend(&more);
+        waiting.extend(more);
+    }
+}
+
+/// The strongly connected components by Tarjan's algorithm
+fn strongly_connected<'a>(graph: &Graph<'a>) -> Vec<BTreeSet<&'a ProcessLabel>> {
+    let mut tarjan = Tarjan {
+        graph,
+        index: HashMap::new(),
+        low: HashMap::new(),
+        stack: Vec::new(),
+        on_stack: HashSet::new(),
+        components: Vec::new(),
+    };
+    for &proc in graph.keys() {
+        if !tarjan.index.contains_key(proc) {
+            tarjan.visit(proc);
+        }
+    }
+    tarjan.components
+}
+
+struct Tarjan<'g, 'a> {
+    graph: &'g Graph<'a>,
+    /// In the order of the visits
+    index: HashMap<&'a ProcessLabel, usize>,
+    /// The smallest index reachable through the subtree and a single back edge
+    low: HashMap<&'a ProcessLabel, usize>,
+    stack: Vec<&'a ProcessLabel>,
+    on_stack: HashSet<&'a ProcessLabel>,
+    components: Vec<BTreeSet<&'a ProcessLabel>>,
+}
+
+impl<'a> Tarjan<'_, 'a> {
+    fn visit(&mut self, proc: &'a ProcessLabel) {
+        let index = self.index.len();
+        self.index.insert(proc, index);
+        self.low.insert(proc, index);
+        self.stack.push(proc);
+        self.on_stack.insert(proc);
+        let graph = self.graph;
+        for &next in &graph[proc] {
+            if !self.index.contains_key(next) {
+                self.visit(next);
+                let low = self.low[proc].min(self.low[next]);
+                self.low.insert(proc, low);
+            } else if self.on_stack.contains(next) {
+                let low = self.low[proc].min(self.index[next]);
+                self.low.insert(proc, low);
+            }
+        }
+        if self.low[proc] == index {
+            let mut component = BTreeSet::new();
+            loop {
+                let top = self.stack.pop().unwrap();
+                self.on_stack.remove(top);
+                component.insert(top);
+                if top == proc {
+                    break;
+                }
+            }
+            self.components.push(component);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn graph(edges: &[(&str, &[&str])]) -> HashMap<ProcessLabel, Vec<ProcessLabel>> {
+        edges
+            .iter()
+            .map(|(proc, waits)| {
+                let waits = waits.iter().map(|w| ProcessLabel(w.to_string()));
+                (ProcessLabel(proc.to_string()), waits.collect())
+            })
+            .collect()
+    }
+
+    fn labels(procs: &[&str]) -> Vec<ProcessLabel> {
+        procs.iter().map(|p| ProcessLabel(p.to_string())).collect()
+    }
+
+    fn owned<'a>(procs: impl IntoIterator<Item = &'a ProcessLabel>) -> Vec<ProcessLabel> {
+        procs.into_iter().cloned().collect()
+    }
+
+    #[test]
+    fn cycles_of_example1() {
+        // the wait-for graph of inputs/task4/example1.json
+        let waits_for = graph(&[("P1", &["P3"]), ("P2", &["P1", "P3"]), ("P3", &["P2"])]);
+        let cycles = elementary_cycles(&waits_for)
+            .into_iter()
+            .map(owned)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            cycles,
+            vec![labels(&["P1", "P3", "P2"]), labels(&["P2", "P3"])]
+        );
+    }
+
+    #[test]
+    fn every_cycle_of_a_complete_graph() {
+        // sum over k of n! / (n - k)! / k for the cycles of length k >= 2
+        for (n, expected) in [(3, 5), (4, 20), (5, 84), (6, 409)] {
+            let procs = (1..=n).map(|i| format!("P{i}")).collect::<Vec<_>>();
+            let waits_for = procs
+                .iter()
+                .map(|proc| {
+                    let others = procs.iter().filter(|other| *other != proc);
+                    (
+                        ProcessLabel(proc.clone()),
+                        others.cloned().map(ProcessLabel).collect(),
+                    )
+                })
+                .collect();
+            assert_eq!(
+                elementary_cycles(&waits_for).len(),
+                expected,
+                "{n} processes"
+            );
+        }
+    }
+
+    #[test]
+    fn no_cycles_in_a_chain() {
+        let waits_for = graph(&[("P1", &["P2"]), ("P2", &["P3"])]);
+        assert!(elementary_cycles(&waits_for).is_empty());
+        assert!(deadlocked_sets(&waits_for).is_empty());
+    }
+
+    #[test]
+    fn deadlocked_sets_are_the_components_with_a_cycle() {
+        // two separate cycles, P5 waiting for one of them and P6 for itself
+        let waits_for = graph(&[
+            ("P1", &["P2"]),
+            ("P2", &["P1"]),
+            ("P3", &["P4"]),
+            ("P4", &["P3"]),
+            ("P5", &["P1", "P7"]),
+            ("P6", &["P6"]),
+        ]);
+        let sets = deadlocked_sets(&waits_for)
+            .into_iter()
+            .map(owned)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            sets,
+            vec![
+                labels(&["P1", "P2"]),
+                labels(&["P3", "P4"]),
+                labels(&["P6"])
+            ]
+        );
+        let deadlocked = deadlocked_sets(&waits_for);
+        let waiting = waiting_on_deadlock(&waits_for, &deadlocked);
+        assert_eq!(owned(waiting), labels(&["P5"]));
+    }
+
+    #[test]
+    fn a_cycle_with_a_way_out_is_not_a_knot() {
+        // P2 can also get what it waits for from P3, which waits for nobody
+        let waits_for = graph(&[
+            ("P1", &["P2"]),
+            ("P2", &["P1", "P3"]),
+            ("P4", &["P5"]),
+            ("P5", &["P4"]),
+        ]);
+        let knots = knots(&waits_for).into_iter().map(owned).collect::<Vec<_>>();
+        assert_eq!(knots, vec![labels(&["P4", "P5"])]);
+    }
+}
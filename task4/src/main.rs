@@ -1,4 +1,16 @@
-use std::collections::{hash_map::Entry, HashMap};
+use std::collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap};
+
+use colored::Colorize;
+
+mod cycles;
+mod protocol;
+mod reduction;
+mod resolution;
+mod validation;
+
+use protocol::{Protocol, SiteUpdate};
+use reduction::{Request, ResourceGraph};
+use resolution::Victims;
 
 #[derive(PartialEq, Eq, Debug, Default, Clone, Copy, Hash)]
 pub enum ResourceState {
@@ -33,13 +45,49 @@ pub struct Network {
     pub nodes: Vec<Node>,
     /// Assumed that it has a direct access to all other nodes
     pub controller: Controller,
+    /// The changes of the sites that are still to come
+    pub updates: Vec<SiteUpdate>,
+    /// How many times the controller has read a site so far
+    pub step: usize,
 }
 
 /// A site running multiple processes and owning multiple resources
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct Node {
     pub resources: HashMap<ResourceLabel, ProcessLabel>,
     pub processes: HashMap<ProcessLabel, Vec<ResourceLabel>>,
+    /// The processes queued for the resources of the site, only the
+    /// one-phase detection looks at them. Not given means nothing is known
+    /// about the queues, unlike an empty table
+    #[serde(default)]
+    pub waiting: Option<HashMap<ResourceLabel, Vec<ProcessLabel>>>,
+    /// How much it costs to abort the processes of the site, 1 if not given
+    #[serde(default)]
+    pub costs: HashMap<ProcessLabel, usize>,
+    /// How many units the resources of the site have, 1 if not given
+    #[serde(default)]
+    pub units: HashMap<ResourceLabel, usize>,
+    /// How many units of the resources of the site the processes hold, in
+    /// place of `resources` for the ones given
+    #[serde(default)]
+    pub allocation: HashMap<ResourceLabel, HashMap<ProcessLabel, usize>>,
+    /// What the processes of the site wait for, in place of the resources
+    /// listed in `processes`
+    #[serde(default)]
+    pub requests: HashMap<ProcessLabel, Request>,
+}
+
+/// What the input file holds, either just the sites or the sites with the
+/// changes they go through while the controller reads them
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum Input {
+    Sites(Vec<Node>),
+    Scenario {
+        sites: Vec<Node>,
+        #[serde(default)]
+        updates: Vec<SiteUpdate>,
+    },
 }
 
 #[derive(Default)]
@@ -50,14 +98,26 @@ pub struct Controller {
 }
 
 impl Controller {
+    /// Prints the wait-for graph and the status table in the order of the
+    /// labels
+    pub fn print_state(&self) {
+        println!("Recorded wait-for-graph:");
+        let graph = self
+            .waits_for
+            .iter()
+            .map(|(proc, waits)| (proc, waits.iter().collect::<BTreeSet<_>>()))
+            .collect::<BTreeMap<_, _>>();
+        println!("{graph:?}");
+
+        println!("Recorded status table:");
+        println!("{:?}", protocol::sorted(&self.status_table));
+    }
+
     pub fn processes(&self) -> impl Iterator<Item = &ProcessLabel> {
         self.status_table.keys()
     }
     pub fn resources(&self) -> Option<impl Iterator<Item = &ResourceLabel>> {
-        self.status_table
-            .values()
-            .nth(0)
-            .and_then(|map| Some(map.keys()))
+        self.status_table.values().next().map(|map| map.keys())
     }
     pub fn collect_tables(
         &mut self,
@@ -69,13 +129,13 @@ impl Controller {
                 Entry::Occupied(mut entry) => {
                     let entry = entry.get_mut();
                     entry.extend(
-                        res.into_iter()
+                        res.iter()
                             .map(|res| (res.clone(), ResourceState::Requested)),
                     );
                 }
                 Entry::Vacant(entry) => {
                     entry.insert(
-                        res.into_iter()
+                        res.iter()
                             .map(|res| (res.clone(), ResourceState::Requested))
                             .collect::<HashMap<ResourceLabel, ResourceState>>(),
                     );
@@ -104,46 +164,31 @@ impl Controller {
         }
     }
     pub fn update_wait_for_graph(&mut self) -> Result<(), String> {
+        // the processes requesting and the processes holding each resource,
+        // there can be several of both
         let mut resource_deps =
-            HashMap::<&ResourceLabel, (Option<&ProcessLabel>, Option<&ProcessLabel>)>::new();
-        for (proc, res_table) in &mut self.status_table {
-            for (res, state) in res_table.iter_mut() {
-                if *state == ResourceState::Requested {
-                    match resource_deps.entry(res) {
-                        Entry::Occupied(mut entry) => {
-                            let mapping = entry.get_mut();
-                            mapping.0 = Some(proc);
-                        }
-                        Entry::Vacant(entry) => {
-                            entry.insert((Some(proc), None));
-                        }
-                    }
-                }
-                if *state == ResourceState::InUse {
-                    match resource_deps.entry(res) {
-                        Entry::Occupied(mut entry) => {
-                            let mapping = entry.get_mut();
-                            mapping.1 = Some(proc);
-                        }
-                        Entry::Vacant(entry) => {
-                            entry.insert((None, Some(proc)));
-                        }
-                    }
+            HashMap::<&ResourceLabel, (Vec<&ProcessLabel>, Vec<&ProcessLabel>)>::new();
+        for (proc, res_table) in &self.status_table {
+            for (res, state) in res_table {
+                let (requesters, holders) = resource_deps.entry(res).or_default();
+                match state {
+                    ResourceState::Requested => requesters.push(proc),
+                    ResourceState::InUse => holders.push(proc),
+                    ResourceState::Free => {}
                 }
             }
         }
+        // every requester waits for every holder
         let mut waits_for = HashMap::<ProcessLabel, Vec<ProcessLabel>>::new();
-        for (_, (p1, p2)) in resource_deps {
-            if let (Some(p1), Some(p2)) = (p1, p2) {
-                match waits_for.entry(p1.clone()) {
-                    Entry::Occupied(mut entry) => {
-                        let waits_for = entry.get_mut();
-                        waits_for.push(p2.clone())
-                    }
-                    Entry::Vacant(entry) => {
-                        entry.insert(vec![p2.clone()]);
-                    }
-                }
+        for (requesters, holders) in resource_deps.into_values() {
+            if holders.is_empty() {
+                continue;
+            }
+            for requester in requesters {
+                let waits = waits_for.entry(requester.clone()).or_default();
+                waits.extend(holders.iter().map(|holder| (*holder).clone()));
+                waits.sort();
+                waits.dedup();
             }
         }
 
@@ -151,102 +196,195 @@ impl Controller {
 
         Ok(())
     }
+}
 
-    /// Collects all cycles found in the graph
-    pub fn detect_all_cycles<'a>(&'a self) -> Vec<Vec<&'a ProcessLabel>> {
-        let mut reslt = Vec::new();
+impl Network {
+    /// The updates have to be of the given sites (see [`check_updates`])
+    pub fn new(nodes: Vec<Node>, mut updates: Vec<SiteUpdate>) -> Self {
+        // the earliest ones go last, to be popped first
+        updates.sort_by_key(|update| update.at);
+        updates.reverse();
+        Self {
+            nodes,
+            controller: Controller::default(),
+            updates,
+            step: 0,
+        }
+    }
+
+    /// Applies the updates due by the given step
+    fn apply_updates(&mut self, step: usize) {
+        while self.updates.last().is_some_and(|update| update.at <= step) {
+            let update = self.updates.pop().unwrap();
+            let site = update.site;
+            println!("Site {site} changes before read {}", update.at);
+            update.apply(&mut self.nodes[site]);
+        }
+        // a single update might not make sense without the others of the step
+        check_sites(&self.nodes);
+    }
+
+    /// The tables of the site as the controller gets them in the next step
+    pub fn read(&mut self, site: usize) -> Node {
+        self.step += 1;
+        self.apply_updates(self.step);
+        println!("Read {}: site {site}", self.step);
+        self.nodes[site].clone()
+    }
 
-        let mut visited = HashMap::<&ProcessLabel, DfsSearchStatus>::new();
-        visited.extend(
-            self.waits_for
-                .keys()
-                .map(|proc| (proc, DfsSearchStatus::NotVisited)),
+    /// Lets all the updates happen, the sites end up in their final state
+    pub fn settle(&mut self) {
+        self.apply_updates(usize::MAX);
+    }
+}
+
+/// Prints the updates of the sites which do not exist, if there are any, and
+/// exits with an error
+fn check_updates(nodes: &[Node], updates: &[SiteUpdate]) {
+    let unknown = updates
+        .iter()
+        .filter(|update| update.site >= nodes.len())
+        .collect::<Vec<_>>();
+    if unknown.is_empty() {
+        return;
+    }
+    eprintln!("{}", "The updates change sites which do not exist:".red());
+    for update in unknown {
+        eprintln!(
+            "{}",
+            format!(
+                "--- an update before read {} of the site {}, but there are only {} sites",
+                update.at,
+                update.site,
+                nodes.len()
+            )
+            .red()
         );
+    }
+    std::process::exit(1);
+}
 
-        for p in self.processes() {
-            if visited.get(p).copied() == Some(DfsSearchStatus::NotVisited) {
-                let mut tracked = Vec::new();
-                tracked.push(p);
-                visited.insert(p, DfsSearchStatus::Tracked);
-                self.detect_cycle(&mut tracked, &mut visited, &mut reslt);
-            }
+/// Prints the conflicts between the tables of the sites, if there are any, and
+/// exits with an error
+fn check_sites(nodes: &[Node]) {
+    if let Err(conflicts) = validation::validate(nodes) {
+        eprintln!("{}", "The tables of the sites contradict each other:".red());
+        for conflict in conflicts {
+            eprintln!("{}", format!("--- {conflict}").red());
         }
-        reslt
+        std::process::exit(1);
     }
+}
 
-    /// Returns optionally a chain of elements making up a cycle
-    fn detect_cycle<'a>(
-        &'a self,
-        tracked: &mut Vec<&'a ProcessLabel>,
-        visited: &mut HashMap<&'a ProcessLabel, DfsSearchStatus>,
-        result: &mut Vec<Vec<&'a ProcessLabel>>,
-    ) {
-        let processes = self.waits_for.get(tracked.iter().last().unwrap());
-        if let Some(processes) = processes {
-            for p in processes {
-                let process_track_status = visited.get(p).copied();
-                if process_track_status == Some(DfsSearchStatus::Tracked) {
-                    result.push(tracked.clone());
-                    tracked.clear();
-                    tracked.push(p);
-                } else if process_track_status == Some(DfsSearchStatus::NotVisited) {
-                    tracked.push(p);
-                    visited.insert(p, DfsSearchStatus::Tracked);
-                    return self.detect_cycle(tracked, visited, result);
-                }
-            }
-        }
-        if let Some(top) = tracked.iter().last() {
-            visited.insert(*top, DfsSearchStatus::Done);
-            tracked.pop();
+/// Finds the deadlocks of the general resource model by reducing the graph
+fn reduce(graph: &ResourceGraph) {
+    println!("Resources with several units or OR requests, reducing the graph");
+    println!("Units of the resources:");
+    println!("{:?}", graph.units);
+    println!("Held units:");
+    println!("{:?}", graph.held);
+    println!("Requests:");
+    println!("{:?}", graph.requests);
+    let waits_for = graph.waits_for();
+    println!("Recorded wait-for-graph:");
+    let ordered = waits_for.iter().collect::<BTreeMap<_, _>>();
+    println!("{ordered:?}");
+
+    let reduction = graph.reduce();
+    println!("Can finish in this order: {:?}", reduction.finished);
+    if reduction.deadlocked.is_empty() {
+        println!("No deadlock found");
+        return;
+    }
+    println!("Deadlocked processes: {:?}", reduction.deadlocked);
+    let knots = cycles::knots(&waits_for);
+    if !knots.is_empty() {
+        println!("Knots of the wait-for graph:");
+        for knot in &knots {
+            println!("{knot:?}");
         }
     }
 }
 
-#[derive(PartialEq, Eq, Debug, Hash, Clone, Copy)]
-enum DfsSearchStatus {
-    // coloring
-    NotVisited,
-    Tracked,
-    Done,
+/// Removes the option and its value from the arguments, returns the value
+fn take_option(args: &mut Vec<String>, option: &str) -> Option<String> {
+    args.iter().position(|a| a == option).map(|idx| {
+        args.remove(idx);
+        assert!(idx < args.len(), "expected a value after `{option}`");
+        args.remove(idx)
+    })
 }
 
 fn main() {
-    let filename = std::env::args()
-        .nth(1)
-        .expect("expected a filename as an input");
+    let mut args = std::env::args().skip(1).collect::<Vec<_>>();
+    let protocol = take_option(&mut args, "--protocol")
+        .map(|name| name.parse::<Protocol>().unwrap_or_else(|e| panic!("{e}")))
+        .unwrap_or_default();
+    let victims = take_option(&mut args, "--resolve")
+        .map(|name| name.parse::<Victims>().unwrap_or_else(|e| panic!("{e}")));
+    let filename = args.first().expect("expected a filename as an input");
     println!("Reading from file {filename}");
-    let file = std::fs::File::open(&filename).expect("failed to open the file");
-    let nodes =
-        serde_json::from_reader(file).expect("failed to deserialize into a vector of nodes");
-
-    let mut net = Network {
-        nodes,
-        controller: Controller::default(),
+    let file = std::fs::File::open(filename).expect("failed to open the file");
+    let input = serde_json::from_reader(file)
+        .expect("failed to deserialize into a vector of nodes or a scenario");
+    let (nodes, updates) = match input {
+        Input::Sites(nodes) => (nodes, Vec::new()),
+        Input::Scenario { sites, updates } => (sites, updates),
     };
 
-    for node in net.nodes {
-        net.controller
-            .collect_tables(&node.resources, &node.processes);
+    // the updates cannot change the units or the requests, the sites tell it
+    // before the controller reads any of them
+    let general = reduction::is_needed(&nodes);
+    if general && (protocol != Protocol::Snapshot || victims.is_some()) {
+        eprintln!(
+            "{}",
+            "Resources with several units and OR requests are supported only with \
+             the snapshot protocol and without resolution"
+                .red()
+        );
+        std::process::exit(1);
     }
+    check_sites(&nodes);
+    check_updates(&nodes, &updates);
+    let mut net = Network::new(nodes, updates);
+    if general {
+        net.settle();
+        return reduce(&ResourceGraph::collect(&net.nodes));
+    }
+    protocol.collect(&mut net);
 
     net.controller
         .update_wait_for_graph()
         .expect("provided data was insufficient to build a complete wait-for-graph");
 
-    println!("Recorded wait-for-graph:");
-    println!("{:?}", &net.controller.waits_for);
-
-    println!("Recorded status table:");
-    println!("{:?}", &net.controller.status_table);
+    net.controller.print_state();
 
-    let cycles = net.controller.detect_all_cycles();
+    let waits_for = &net.controller.waits_for;
+    let cycles = cycles::elementary_cycles(waits_for);
     if cycles.is_empty() {
         println!("No cycles found");
-    } else {
-        println!("Found cycles causing deadlocks.");
-        for c in &cycles {
-            println!("Found deadlock invloving nodes: {c:?}");
-        }
+        return;
+    }
+    println!("Found cycles causing deadlocks.");
+    for c in &cycles {
+        println!("Found deadlock involving processes: {c:?}");
+    }
+    let deadlocked = cycles::deadlocked_sets(waits_for);
+    println!("Deadlocked sets of processes:");
+    for set in &deadlocked {
+        println!("{set:?}");
+    }
+    let waiting = cycles::waiting_on_deadlock(waits_for, &deadlocked);
+    if !waiting.is_empty() {
+        println!("Waiting on a deadlocked process: {waiting:?}");
+    }
+
+    if let Some(victims) = victims {
+        let costs = net
+            .nodes
+            .iter()
+            .flat_map(|node| node.costs.clone())
+            .collect::<HashMap<_, _>>();
+        resolution::resolve(&mut net.controller, victims, &costs);
     }
 }
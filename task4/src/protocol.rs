@@ -0,0 +1,277 @@
+//! The ways the controller gets the state of the sites, after Ho and
+//! Ramamoorthy. The controller reads one site at a time and the sites keep
+//! changing in between, so the tables put together from a round of reads
+//! might show a deadlock that never existed (a phantom deadlock).
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::{Controller, Network, Node, ProcessLabel, ResourceLabel, ResourceState};
+
+/// How the controller collects the status tables
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// All the sites at once, in the state they end in. Not something a real
+    /// controller can do, but it shows what the truth is
+    #[default]
+    Snapshot,
+    /// Two rounds of reads, only the processes whose status is the same in
+    /// both are kept
+    TwoPhase,
+    /// A single round of reads of the resource and the process tables, the
+    /// requests that the queues of the resources contradict are left out
+    OnePhase,
+}
+
+impl std::str::FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "snapshot" => Ok(Protocol::Snapshot),
+            "two-phase" => Ok(Protocol::TwoPhase),
+            "one-phase" => Ok(Protocol::OnePhase),
+            other => Err(format!(
+                "unknown protocol `{other}`, expected `snapshot`, `two-phase` or `one-phase`"
+            )),
+        }
+    }
+}
+
+/// New tables of a site, the ones not given stay as they are
+#[derive(serde::Deserialize)]
+pub struct SiteUpdate {
+    /// The update happens just before this read of the controller, they are
+    /// counted from 1 over all the sites and rounds
+    pub at: usize,
+    /// The index of the site in the input
+    pub site: usize,
+    pub resources: Option<HashMap<ResourceLabel, ProcessLabel>>,
+    pub processes: Option<HashMap<ProcessLabel, Vec<ResourceLabel>>>,
+    pub waiting: Option<HashMap<ResourceLabel, Vec<ProcessLabel>>>,
+}
+
+impl SiteUpdate {
+    pub fn apply(self, node: &mut Node) {
+        if let Some(resources) = self.resources {
+            node.resources = resources;
+        }
+        if let Some(processes) = self.processes {
+            node.processes = processes;
+        }
+        if let Some(waiting) = self.waiting {
+            node.waiting = Some(waiting);
+        }
+    }
+}
+
+type StatusTable = HashMap<ProcessLabel, HashMap<ResourceLabel, ResourceState>>;
+
+/// The status table in the order of the labels, for printing
+pub fn sorted(
+    table: &StatusTable,
+) -> BTreeMap<&ProcessLabel, BTreeMap<&ResourceLabel, ResourceState>> {
+    table
+        .iter()
+        .map(|(proc, res)| (proc, res.iter().map(|(res, state)| (res, *state)).collect()))
+        .collect()
+}
+
+impl Protocol {
+    /// Fills the status table of the controller of the network
+    pub fn collect(self, net: &mut Network) {
+        match self {
+            Protocol::Snapshot => {
+                net.settle();
+                for node in &net.nodes {
+                    net.controller
+                        .collect_tables(&node.resources, &node.processes);
+                }
+            }
+            Protocol::TwoPhase => {
+                let first = read_round(net);
+                println!("Status table of the first round:");
+                println!("{:?}", sorted(&first));
+                let second = read_round(net);
+                println!("Status table of the second round:");
+                println!("{:?}", sorted(&second));
+                let changed = first
+                    .keys()
+                    .chain(second.keys())
+                    .filter(|proc| first.get(proc) != second.get(proc))
+                    .collect::<BTreeSet<_>>();
+                if !changed.is_empty() {
+                    println!("Left out, changed between the rounds: {changed:?}");
+                }
+                net.controller.status_table = second
+                    .iter()
+                    .filter(|(proc, res)| first.get(*proc) == Some(res))
+                    .map(|(proc, res)| (proc.clone(), res.clone()))
+                    .collect();
+            }
+            Protocol::OnePhase => {
+                let sites = (0..net.nodes.len())
+                    .map(|site| net.read(site))
+                    .collect::<Vec<_>>();
+                let mut round = Controller::default();
+                for node in &sites {
+                    round.collect_tables(&node.resources, &node.processes);
+                }
+                // the queue of the site holding the resource, if it has one
+                let queues = sites
+                    .iter()
+                    .filter_map(|site| site.waiting.as_ref().map(|waiting| (site, waiting)))
+                    .flat_map(|(site, waiting)| {
+                        site.resources.keys().map(move |res| {
+                            (res, waiting.get(res).map(Vec::as_slice).unwrap_or_default())
+                        })
+                    })
+                    .collect::<HashMap<_, _>>();
+                let mut contradicted = Vec::new();
+                for (proc, table) in &mut round.status_table {
+                    table.retain(|res, state| {
+                        let queued = queues.get(res).is_none_or(|queue| queue.contains(proc));
+                        if *state == ResourceState::Requested && !queued {
+                            contradicted.push(format!("{proc:?} {state:?} {res:?}"));
+                        }
+                        *state == ResourceState::InUse || queued
+                    });
+                }
+                if !contradicted.is_empty() {
+                    contradicted.sort();
+                    println!("Left out, not in the queue of the resource: {contradicted:?}");
+                }
+                net.controller.status_table = round.status_table;
+            }
+        }
+    }
+}
+
+/// Reads every site once, in order, and puts their tables together
+fn read_round(net: &mut Network) -> StatusTable {
+    let mut round = Controller::default();
+    for site in 0..net.nodes.len() {
+        let node = net.read(site);
+        round.collect_tables(&node.resources, &node.processes);
+    }
+    round.status_table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn label(name: &str) -> ProcessLabel {
+        ProcessLabel(name.to_string())
+    }
+
+    /// P1 holds r1 and waits for r2 at the second site, P2 the other way
+    /// round. The update before the given read takes the request of P2 back.
+    fn network(sites: &str, at: usize) -> Network {
+        let nodes: Vec<Node> = serde_json::from_str(sites).unwrap();
+        let updates = serde_json::from_str(&format!(
+            r#"[{{"at": {at}, "site": 0, "processes": {{}}}}]"#
+        ))
+        .unwrap();
+        Network::new(nodes, updates)
+    }
+
+    const DEADLOCK: &str = r#"[
+        {"resources": {"r1": "P1"}, "processes": {"P2": ["r1"]}},
+        {"resources": {"r2": "P2"}, "processes": {"P1": ["r2"]}}
+    ]"#;
+
+    fn status(net: &Network, proc: &str) -> Option<BTreeMap<String, ResourceState>> {
+        net.controller.status_table.get(&label(proc)).map(|table| {
+            table
+                .iter()
+                .map(|(res, state)| (res.0.clone(), *state))
+                .collect()
+        })
+    }
+
+    fn states(states: &[(&str, ResourceState)]) -> Option<BTreeMap<String, ResourceState>> {
+        Some(
+            states
+                .iter()
+                .map(|(res, state)| (res.to_string(), *state))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn protocols_are_parsed_by_name() {
+        assert_eq!("two-phase".parse(), Ok(Protocol::TwoPhase));
+        assert_eq!("one-phase".parse(), Ok(Protocol::OnePhase));
+        assert_eq!("snapshot".parse(), Ok(Protocol::Snapshot));
+        assert!("three-phase".parse::<Protocol>().is_err());
+    }
+
+    #[test]
+    fn snapshot_sees_the_final_state() {
+        let mut net = network(DEADLOCK, 3);
+        Protocol::Snapshot.collect(&mut net);
+        assert_eq!(status(&net, "P2"), states(&[("r2", ResourceState::InUse)]));
+        assert!(net.updates.is_empty());
+    }
+
+    #[test]
+    fn updates_happen_just_before_their_read() {
+        let mut net = network(DEADLOCK, 3);
+        assert!(net.read(0).processes.contains_key(&label("P2")));
+        net.read(1);
+        assert!(net.read(0).processes.is_empty());
+        assert_eq!(net.step, 3);
+    }
+
+    #[test]
+    fn two_phase_leaves_out_what_changed_between_the_rounds() {
+        // the first round sees the deadlock, the second one does not
+        let mut net = network(DEADLOCK, 3);
+        Protocol::TwoPhase.collect(&mut net);
+        assert_eq!(net.step, 4);
+        assert_eq!(
+            status(&net, "P1"),
+            states(&[
+                ("r1", ResourceState::InUse),
+                ("r2", ResourceState::Requested)
+            ])
+        );
+        assert_eq!(status(&net, "P2"), None);
+
+        // a change in the first round is seen by both rounds
+        let mut net = network(DEADLOCK, 1);
+        Protocol::TwoPhase.collect(&mut net);
+        assert_eq!(status(&net, "P2"), states(&[("r2", ResourceState::InUse)]));
+    }
+
+    #[test]
+    fn one_phase_leaves_out_the_requests_the_queues_contradict() {
+        let sites = r#"[
+            {"resources": {"r1": "P1"}, "processes": {"P2": ["r1"]}, "waiting": {"r1": []}},
+            {"resources": {"r2": "P2"}, "processes": {"P1": ["r2"]}}
+        ]"#;
+        let mut net = network(sites, 10);
+        Protocol::OnePhase.collect(&mut net);
+        assert_eq!(net.step, 2);
+        // nothing is known about the queue of r2, the request stays
+        assert_eq!(
+            status(&net, "P1"),
+            states(&[
+                ("r1", ResourceState::InUse),
+                ("r2", ResourceState::Requested)
+            ])
+        );
+        assert_eq!(status(&net, "P2"), states(&[("r2", ResourceState::InUse)]));
+
+        // the queue agrees with the request
+        let mut net = network(&sites.replace(r#""r1": []"#, r#""r1": ["P2"]"#), 10);
+        Protocol::OnePhase.collect(&mut net);
+        assert_eq!(
+            status(&net, "P2"),
+            states(&[
+                ("r1", ResourceState::Requested),
+                ("r2", ResourceState::InUse)
+            ])
+        );
+    }
+}
@@ -0,0 +1,249 @@
+//! The general resource model: resources with several units and requests for
+//! all of several resources (the AND model) or for any one of them (the OR
+//! model). A cycle in the wait-for graph does not have to be a deadlock then,
+//! so the graph is reduced instead: a process whose request can be granted
+//! with the free units finishes and gives back everything it holds, and the
+//! processes that are left when nothing more can finish are deadlocked.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::{Node, ProcessLabel, ResourceLabel};
+
+/// What a blocked process waits for
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Request {
+    /// All of the resources, with the number of units of each
+    All(HashMap<ResourceLabel, usize>),
+    /// A unit of any one of the resources
+    Any(Vec<ResourceLabel>),
+}
+
+impl std::fmt::Debug for Request {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Request::All(units) => {
+                write!(f, "all of {:?}", units.iter().collect::<BTreeMap<_, _>>())
+            }
+            Request::Any(resources) => write!(f, "any of {resources:?}"),
+        }
+    }
+}
+
+impl Request {
+    fn resources(&self) -> Vec<&ResourceLabel> {
+        match self {
+            Request::All(units) => units.keys().collect(),
+            Request::Any(resources) => resources.iter().collect(),
+        }
+    }
+
+    fn can_be_granted(&self, free: &BTreeMap<ResourceLabel, usize>) -> bool {
+        let free = |res| free.get(res).copied().unwrap_or(0);
+        match self {
+            Request::All(units) => units.iter().all(|(res, count)| free(res) >= *count),
+            Request::Any(resources) => resources.iter().any(|res| free(res) > 0),
+        }
+    }
+}
+
+/// Whether any of the sites needs the general model, otherwise the cycles
+/// of the wait-for graph tell the deadlocks
+pub fn is_needed(nodes: &[Node]) -> bool {
+    nodes.iter().any(|node| {
+        !node.requests.is_empty()
+            || !node.allocation.is_empty()
+            || node.units.values().any(|units| *units != 1)
+    })
+}
+
+/// The resources of all the sites, who holds them and who waits for them
+pub struct ResourceGraph {
+    pub units: BTreeMap<ResourceLabel, usize>,
+    pub held: BTreeMap<ProcessLabel, BTreeMap<ResourceLabel, usize>>,
+    pub requests: BTreeMap<ProcessLabel, Request>,
+}
+
+/// What the reduction of the graph ended with
+pub struct Reduction {
+    /// In the order they can finish in
+    pub finished: Vec<ProcessLabel>,
+    pub deadlocked: BTreeSet<ProcessLabel>,
+}
+
+impl ResourceGraph {
+    /// Puts the tables of all the sites together. A resource held without a
+    /// count in `allocation` is a single unit, and the resources listed in
+    /// `processes` but not held are requested all together, a unit of each,
+    /// unless `requests` says otherwise
+    pub fn collect(nodes: &[Node]) -> Self {
+        let mut graph = Self {
+            units: BTreeMap::new(),
+            held: BTreeMap::new(),
+            requests: BTreeMap::new(),
+        };
+        for node in nodes {
+            for (res, proc) in &node.resources {
+                if !node.allocation.contains_key(res) {
+                    graph.hold(proc, res, 1);
+                }
+            }
+            for (res, holders) in &node.allocation {
+                for (proc, count) in holders {
+                    graph.hold(proc, res, *count);
+                }
+            }
+            for (res, units) in &node.units {
+                graph.units.insert(res.clone(), *units);
+            }
+            for (proc, request) in &node.requests {
+                graph.requests.insert(proc.clone(), request.clone());
+            }
+        }
+        for node in nodes {
+            for (proc, listed) in &node.processes {
+                let held = graph.held.get(proc);
+                let wanted = listed
+                    .iter()
+                    .filter(|res| held.is_none_or(|held| !held.contains_key(*res)))
+                    .map(|res| (res.clone(), 1))
+                    .collect::<HashMap<_, _>>();
+                if !wanted.is_empty() && !graph.requests.contains_key(proc) {
+                    graph.requests.insert(proc.clone(), Request::All(wanted));
+                }
+            }
+        }
+        let requested = graph
+            .requests
+            .values()
+            .flat_map(Request::resources)
+            .cloned()
+            .collect::<Vec<_>>();
+        for res in requested {
+            graph.units.entry(res).or_insert(1);
+        }
+        graph
+    }
+
+    fn hold(&mut self, proc: &ProcessLabel, res: &ResourceLabel, count: usize) {
+        *self
+            .held
+            .entry(proc.clone())
+            .or_default()
+            .entry(res.clone())
+            .or_default() += count;
+        self.units.entry(res.clone()).or_insert(1);
+    }
+
+    fn held_units(&self, res: &ResourceLabel) -> usize {
+        self.held.values().filter_map(|held| held.get(res)).sum()
+    }
+
+    /// The units nobody holds
+    pub fn free(&self) -> BTreeMap<ResourceLabel, usize> {
+        self.units
+            .iter()
+            .map(|(res, units)| (res.clone(), units - self.held_units(res)))
+            .collect()
+    }
+
+    fn processes(&self) -> BTreeSet<&ProcessLabel> {
+        self.held.keys().chain(self.requests.keys()).collect()
+    }
+
+    /// Lets the processes finish one by one, the first one by label that can
+    pub fn reduce(&self) -> Reduction {
+        let mut free = self.free();
+        let mut left = self.processes();
+        let mut finished = Vec::new();
+        while let Some(&proc) = left.iter().find(|proc| {
+            self.requests
+                .get(**proc)
+                .is_none_or(|request| request.can_be_granted(&free))
+        }) {
+            left.remove(proc);
+            for (res, count) in self.held.get(proc).into_iter().flatten() {
+                *free.get_mut(res).unwrap() += count;
+            }
+            finished.push(proc.clone());
+        }
+        Reduction {
+            finished,
+            deadlocked: left.into_iter().cloned().collect(),
+        }
+    }
+
+    /// A process that cannot get what it asks for waits for all the holders
+    /// of the resources it asks for
+    pub fn waits_for(&self) -> HashMap<ProcessLabel, Vec<ProcessLabel>> {
+        let free = self.free();
+        let mut waits_for = HashMap::<ProcessLabel, Vec<ProcessLabel>>::new();
+        for (proc, request) in &self.requests {
+            if request.can_be_granted(&free) {
+                continue;
+            }
+            let holders = request.resources().into_iter().flat_map(|res| {
+                self.held
+                    .iter()
+                    .filter(move |(_, held)| held.contains_key(res))
+                    .map(|(holder, _)| holder.clone())
+            });
+            let waits = waits_for.entry(proc.clone()).or_default();
+            waits.extend(holders);
+            waits.sort();
+            waits.dedup();
+        }
+        waits_for
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cycles;
+
+    fn example(json: &str) -> ResourceGraph {
+        let nodes = serde_json::from_str::<Vec<Node>>(json).unwrap();
+        assert!(is_needed(&nodes));
+        ResourceGraph::collect(&nodes)
+    }
+
+    fn labels(procs: &[&str]) -> Vec<ProcessLabel> {
+        procs.iter().map(|p| ProcessLabel(p.to_string())).collect()
+    }
+
+    #[test]
+    fn several_units_without_a_deadlock() {
+        // P1 and P2 wait for each other, but P3 gives back the second unit of R1
+        let graph = example(include_str!("../../inputs/task4/example8.json"));
+        let free = graph.free().into_values().collect::<Vec<_>>();
+        assert_eq!(free, vec![0, 0]);
+        let reduction = graph.reduce();
+        assert_eq!(reduction.finished, labels(&["P3", "P2", "P1"]));
+        assert!(reduction.deadlocked.is_empty());
+    }
+
+    #[test]
+    fn or_requests_with_a_knot() {
+        let graph = example(include_str!("../../inputs/task4/example9.json"));
+        let reduction = graph.reduce();
+        assert_eq!(reduction.finished, labels(&["P3", "P1", "P2"]));
+        let deadlocked = reduction.deadlocked.into_iter().collect::<Vec<_>>();
+        assert_eq!(deadlocked, labels(&["P4", "P5", "P6"]));
+
+        let waits_for = graph.waits_for();
+        let knots = cycles::knots(&waits_for)
+            .into_iter()
+            .map(|knot| knot.into_iter().cloned().collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(knots, vec![labels(&["P4", "P5"])]);
+    }
+
+    #[test]
+    fn not_needed_for_single_units() {
+        let nodes =
+            serde_json::from_str::<Vec<Node>>(include_str!("../../inputs/task4/example1.json"))
+                .unwrap();
+        assert!(!is_needed(&nodes));
+    }
+}
@@ -0,0 +1,299 @@
+//! Breaking the deadlocks by aborting some of the processes. The victims
+//! release their resources, which go to the processes waiting for them, and
+//! the wait-for graph is built again until no cycle is left in it.
+
+use std::collections::{BTreeSet, HashMap};
+
+use crate::{cycles, Controller, ProcessLabel, ResourceState};
+
+/// With more candidates than that the victims are chosen greedily instead of
+/// trying every set of them
+const MAX_EXACT_CANDIDATES: usize = 20;
+
+/// What the victims are chosen by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Victims {
+    /// As few processes as possible, the cheaper ones if there is a choice
+    Fewest,
+    /// The lowest total cost of aborting them
+    Cheapest,
+}
+
+impl std::str::FromStr for Victims {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fewest" => Ok(Victims::Fewest),
+            "cheapest" => Ok(Victims::Cheapest),
+            other => Err(format!(
+                "unknown way of choosing victims `{other}`, expected `fewest` or `cheapest`"
+            )),
+        }
+    }
+}
+
+impl Victims {
+    /// What to minimise for a set of victims, compared as a tuple
+    fn key(self, count: usize, cost: usize) -> (usize, usize) {
+        match self {
+            Victims::Fewest => (count, cost),
+            Victims::Cheapest => (cost, count),
+        }
+    }
+
+    /// A set of processes such that every cycle goes through one of them
+    fn choose<'a>(
+        self,
+        cycles: &[Vec<&'a ProcessLabel>],
+        cost: impl Fn(&ProcessLabel) -> usize,
+    ) -> Vec<&'a ProcessLabel> {
+        let candidates = cycles
+            .iter()
+            .flatten()
+            .copied()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        if candidates.len() > MAX_EXACT_CANDIDATES {
+            return self.choose_greedily(cycles, cost);
+        }
+        let masks = cycles
+            .iter()
+            .map(|cycle| {
+                cycle.iter().fold(0u32, |mask, proc| {
+                    mask | 1 << candidates.binary_search(proc).unwrap()
+                })
+            })
+            .collect::<Vec<_>>();
+        let members = |set: u32| {
+            candidates
+                .iter()
+                .enumerate()
+                .filter(move |(idx, _)| set & 1 << idx != 0)
+                .map(|(_, proc)| *proc)
+        };
+        let best = (1..1u32 << candidates.len())
+            .filter(|set| masks.iter().all(|mask| mask & set != 0))
+            .min_by_key(|set| {
+                let cost = members(*set).map(&cost).sum();
+                self.key(set.count_ones() as usize, cost)
+            })
+            .expect("all the candidates break all the cycles");
+        members(best).collect()
+    }
+
+    /// Takes the process in the most cycles for its cost until no cycle is
+    /// left, not the best set of victims but a good one
+    fn choose_greedily<'a>(
+        self,
+        cycles: &[Vec<&'a ProcessLabel>],
+        cost: impl Fn(&ProcessLabel) -> usize,
+    ) -> Vec<&'a ProcessLabel> {
+        let mut left = cycles.iter().collect::<Vec<_>>();
+        let mut victims = Vec::new();
+        while !left.is_empty() {
+            let mut counts = HashMap::<&ProcessLabel, usize>::new();
+            for proc in left.iter().copied().flatten() {
+                *counts.entry(proc).or_default() += 1;
+            }
+            let (victim, _) = counts
+                .into_iter()
+                .max_by(|(a, a_count), (b, b_count)| {
+                    let by_count = a_count.cmp(b_count).then(cost(b).cmp(&cost(a)));
+                    let by_cost = (a_count * cost(b)).cmp(&(b_count * cost(a)));
+                    match self {
+                        Victims::Fewest => by_count,
+                        Victims::Cheapest => by_cost.then(by_count),
+                    }
+                    // the smaller label wins a tie
+                    .then_with(|| b.cmp(a))
+                })
+                .unwrap();
+            left.retain(|cycle| !cycle.contains(&victim));
+            victims.push(victim);
+        }
+        victims.sort();
+        victims
+    }
+}
+
+/// Aborts the victims until the wait-for graph of the controller has no
+/// cycles left
+pub fn resolve(
+    controller: &mut Controller,
+    victims: Victims,
+    costs: &HashMap<ProcessLabel, usize>,
+) {
+    let cost = |proc: &ProcessLabel| costs.get(proc).copied().unwrap_or(1);
+    loop {
+        let cycles = cycles::elementary_cycles(&controller.waits_for);
+        if cycles.is_empty() {
+            println!("No cycles left");
+            return;
+        }
+        let chosen = victims
+            .choose(&cycles, cost)
+            .into_iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        let total = chosen.iter().map(cost).sum::<usize>();
+        println!("Aborting {chosen:?} at the cost of {total}");
+        for victim in &chosen {
+            controller.abort(victim);
+        }
+        controller
+            .update_wait_for_graph()
+            .expect("provided data was insufficient to build a complete wait-for-graph");
+        controller.print_state();
+    }
+}
+
+impl Controller {
+    /// Forgets the process, every resource it held goes to the first of the
+    /// processes waiting for it
+    pub fn abort(&mut self, victim: &ProcessLabel) {
+        let Some(table) = self.status_table.remove(victim) else {
+            return;
+        };
+        let mut held = table
+            .into_iter()
+            .filter(|(_, state)| *state == ResourceState::InUse)
+            .map(|(res, _)| res)
+            .collect::<Vec<_>>();
+        held.sort();
+        for res in held {
+            let next = self
+                .status_table
+                .iter_mut()
+                .filter(|(_, table)| table.get(&res) == Some(&ResourceState::Requested))
+                .min_by(|(a, _), (b, _)| a.cmp(b));
+            match next {
+                Some((proc, table)) => {
+                    println!("{victim:?} releases {res:?}, it goes to {proc:?}");
+                    table.insert(res, ResourceState::InUse);
+                }
+                None => println!("{victim:?} releases {res:?}, nobody waits for it"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ResourceLabel;
+
+    fn labels(names: &[&str]) -> Vec<ProcessLabel> {
+        names
+            .iter()
+            .map(|name| ProcessLabel(name.to_string()))
+            .collect()
+    }
+
+    fn costs(costs: &[(&str, usize)]) -> HashMap<ProcessLabel, usize> {
+        costs
+            .iter()
+            .map(|(proc, cost)| (ProcessLabel(proc.to_string()), *cost))
+            .collect()
+    }
+
+    /// The controller with the wait-for graph built from who holds and who
+    /// requests which resources
+    fn controller(held: &[(&str, &str)], requested: &[(&str, &[&str])]) -> Controller {
+        let resources = held
+            .iter()
+            .map(|(res, proc)| {
+                (
+                    ResourceLabel(res.to_string()),
+                    ProcessLabel(proc.to_string()),
+                )
+            })
+            .collect();
+        let processes = requested
+            .iter()
+            .map(|(proc, res)| {
+                let res = res.iter().map(|res| ResourceLabel(res.to_string()));
+                (ProcessLabel(proc.to_string()), res.collect())
+            })
+            .collect();
+        let mut controller = Controller::default();
+        controller.collect_tables(&resources, &processes);
+        controller.update_wait_for_graph().unwrap();
+        controller
+    }
+
+    #[test]
+    fn victims_are_chosen_by_their_number_or_by_their_cost() {
+        let procs = labels(&["P1", "P2", "P3"]);
+        let cycles = vec![vec![&procs[0], &procs[1]], vec![&procs[1], &procs[2]]];
+        let costs = costs(&[("P2", 5)]);
+        let cost = |proc: &ProcessLabel| costs.get(proc).copied().unwrap_or(1);
+        for choose in [Victims::choose, Victims::choose_greedily] {
+            assert_eq!(choose(Victims::Fewest, &cycles, cost), [&procs[1]]);
+            assert_eq!(
+                choose(Victims::Cheapest, &cycles, cost),
+                [&procs[0], &procs[2]]
+            );
+        }
+    }
+
+    #[test]
+    fn ties_go_to_the_cheaper_and_then_the_smaller_label() {
+        let procs = labels(&["P1", "P2", "P3"]);
+        let cycles = vec![procs.iter().collect::<Vec<_>>()];
+        let costs = costs(&[("P1", 2)]);
+        let cost = |proc: &ProcessLabel| costs.get(proc).copied().unwrap_or(1);
+        for choose in [Victims::choose, Victims::choose_greedily] {
+            assert_eq!(choose(Victims::Fewest, &cycles, cost), [&procs[1]]);
+            assert_eq!(choose(Victims::Cheapest, &cycles, cost), [&procs[1]]);
+        }
+    }
+
+    #[test]
+    fn many_candidates_are_chosen_greedily() {
+        // too many for the sets of them to be tried
+        let procs = (0..2 * MAX_EXACT_CANDIDATES)
+            .map(|idx| ProcessLabel(format!("P{idx:02}")))
+            .collect::<Vec<_>>();
+        let cycles = procs
+            .chunks(2)
+            .map(|pair| pair.iter().collect())
+            .collect::<Vec<_>>();
+        let victims = Victims::Fewest.choose(&cycles, |_| 1);
+        assert_eq!(victims, procs.iter().step_by(2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn aborted_process_hands_its_resources_over() {
+        let mut controller = controller(
+            &[("r1", "P1")],
+            &[("P1", &[]), ("P3", &["r1"]), ("P2", &["r1"])],
+        );
+        controller.abort(&ProcessLabel("P1".to_string()));
+        let state = |proc: &str| {
+            controller.status_table[&ProcessLabel(proc.to_string())]
+                [&ResourceLabel("r1".to_string())]
+        };
+        assert_eq!(state("P2"), ResourceState::InUse);
+        assert_eq!(state("P3"), ResourceState::Requested);
+        assert!(!controller.processes().any(|proc| proc.0 == "P1"));
+    }
+
+    #[test]
+    fn resolution_breaks_every_cycle() {
+        let mut controller = controller(
+            &[("r1", "P1"), ("r2", "P2")],
+            &[("P1", &["r2"]), ("P2", &["r1"])],
+        );
+        resolve(&mut controller, Victims::Cheapest, &costs(&[("P1", 3)]));
+        assert!(cycles::elementary_cycles(&controller.waits_for).is_empty());
+        // the cheaper P2 has gone, P1 got what it waited for
+        assert_eq!(
+            controller.processes().collect::<Vec<_>>(),
+            labels(&["P1"]).iter().collect::<Vec<_>>()
+        );
+        let table = &controller.status_table[&ProcessLabel("P1".to_string())];
+        assert!(table.values().all(|state| *state == ResourceState::InUse));
+    }
+}
@@ -0,0 +1,195 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Display,
+};
+
+use crate::{Node, ProcessLabel, ResourceLabel};
+
+/// Tables of the sites that cannot all be true at once
+#[derive(Debug, PartialEq, Eq)]
+pub enum Conflict {
+    /// A resource in the `resources` table of several sites, with the
+    /// site and the holder of each
+    SeveralSites {
+        resource: ResourceLabel,
+        holders: Vec<(usize, ProcessLabel)>,
+    },
+    /// More units of a resource are held than it has
+    TooManyHeld {
+        resource: ResourceLabel,
+        units: usize,
+        held: usize,
+    },
+}
+
+impl Display for Conflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Conflict::SeveralSites { resource, holders } => {
+                let holders = holders
+                    .iter()
+                    .map(|(site, proc)| format!("{proc:?} at site {site}"))
+                    .collect::<Vec<_>>();
+                write!(
+                    f,
+                    "resource {resource:?} is held by {}",
+                    holders.join(" and ")
+                )
+            }
+            Conflict::TooManyHeld {
+                resource,
+                units,
+                held,
+            } => write!(
+                f,
+                "resource {resource:?} has {units} units but {held} of them are held"
+            ),
+        }
+    }
+}
+
+/// Checks that the tables of the sites agree with each other
+pub fn validate(nodes: &[Node]) -> Result<(), Vec<Conflict>> {
+    let mut conflicts = Vec::new();
+
+    let mut holders = BTreeMap::<&ResourceLabel, Vec<(usize, ProcessLabel)>>::new();
+    let mut units = BTreeMap::<&ResourceLabel, usize>::new();
+    let mut held = BTreeMap::<&ResourceLabel, usize>::new();
+    for (site, node) in nodes.iter().enumerate() {
+        for (res, proc) in &node.resources {
+            holders.entry(res).or_default().push((site, proc.clone()));
+            if !node.allocation.contains_key(res) {
+                *held.entry(res).or_default() += 1;
+            }
+        }
+        units.extend(node.units.iter().map(|(res, count)| (res, *count)));
+        for (res, allocation) in &node.allocation {
+            *held.entry(res).or_default() += allocation.values().sum::<usize>();
+        }
+    }
+
+    let mut reported = BTreeSet::new();
+    for (resource, mut holders) in holders {
+        if holders.len() > 1 {
+            reported.insert(resource);
+            holders.sort();
+            conflicts.push(Conflict::SeveralSites {
+                resource: resource.clone(),
+                holders,
+            });
+        }
+    }
+    for (resource, held) in held {
+        let units = units.get(resource).copied().unwrap_or(1);
+        // several sites holding it have been reported already
+        if held > units && !reported.contains(resource) {
+            conflicts.push(Conflict::TooManyHeld {
+                resource: resource.clone(),
+                units,
+                held,
+            });
+        }
+    }
+
+    if conflicts.is_empty() {
+        Ok(())
+    } else {
+        Err(conflicts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::{Controller, ResourceState};
+
+    fn sites(json: &str) -> Vec<Node> {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn process(label: &str) -> ProcessLabel {
+        ProcessLabel(label.to_string())
+    }
+
+    fn resource(label: &str) -> ResourceLabel {
+        ResourceLabel(label.to_string())
+    }
+
+    #[test]
+    fn provided_examples_agree() {
+        for json in [
+            include_str!("../../inputs/task4/example1.json"),
+            include_str!("../../inputs/task4/example6.json"),
+            include_str!("../../inputs/task4/example8.json"),
+            include_str!("../../inputs/task4/example9.json"),
+        ] {
+            assert_eq!(validate(&sites(json)), Ok(()));
+        }
+    }
+
+    #[test]
+    fn resource_at_several_sites() {
+        let nodes = sites(
+            r#"[
+                { "resources": { "R1": "P1" }, "processes": { "P1": [ "R1" ] } },
+                { "resources": { "R1": "P2" }, "processes": { "P2": [ "R1" ] } }
+            ]"#,
+        );
+        // held twice as well, but that is reported only once
+        assert_eq!(
+            validate(&nodes),
+            Err(vec![Conflict::SeveralSites {
+                resource: resource("R1"),
+                holders: vec![(0, process("P1")), (1, process("P2"))],
+            }])
+        );
+    }
+
+    #[test]
+    fn process_at_several_sites() {
+        // a process can use the resources of several sites, the controller
+        // puts together what each of them knows about it
+        let nodes = sites(
+            r#"[
+                { "resources": { "R1": "P1" }, "processes": { "P1": [ "R1" ] } },
+                { "resources": { "R2": "P2" }, "processes": { "P1": [ "R2" ], "P2": [ "R2" ] } }
+            ]"#,
+        );
+        assert_eq!(validate(&nodes), Ok(()));
+        let mut controller = Controller::default();
+        for node in &nodes {
+            controller.collect_tables(&node.resources, &node.processes);
+        }
+        assert_eq!(
+            controller.status_table[&process("P1")],
+            HashMap::from([
+                (resource("R1"), ResourceState::InUse),
+                (resource("R2"), ResourceState::Requested),
+            ])
+        );
+    }
+
+    #[test]
+    fn more_units_held_than_there_are() {
+        let nodes = sites(
+            r#"[
+                {
+                    "resources": {},
+                    "processes": {},
+                    "units": { "R1": 2 },
+                    "allocation": { "R1": { "P1": 2, "P2": 1 } }
+                }
+            ]"#,
+        );
+        assert_eq!(
+            validate(&nodes),
+            Err(vec![Conflict::TooManyHeld {
+                resource: resource("R1"),
+                units: 2,
+                held: 3,
+            }])
+        );
+    }
+}
@@ -63,11 +63,9 @@ impl Node {
                     self.name, self.public_lbl, self.private_lbl
                 );
 
-                if let Some(blocked) = self.received_req_from.pop() {
-                    Some((blocked, Msg::TransmitBlock { public_lbl }))
-                } else {
-                    None
-                }
+                self.received_req_from
+                    .pop()
+                    .map(|blocked| (blocked, Msg::TransmitBlock { public_lbl }))
             }
             Msg::TransmitBlock { public_lbl } => {
                 println!("Node {name} received the transit message", name = self.name);
@@ -83,11 +81,9 @@ impl Node {
                         self.name, self.public_lbl, self.private_lbl
                     );
 
-                    if let Some(blocked) = self.received_req_from.pop() {
-                        Some((blocked, Msg::TransmitBlock { public_lbl }))
-                    } else {
-                        None
-                    }
+                    self.received_req_from
+                        .pop()
+                        .map(|blocked| (blocked, Msg::TransmitBlock { public_lbl }))
                 } else {
                     None
                 }
@@ -133,18 +129,15 @@ impl Network {
     pub fn build(from: Vec<NodeDescription>) -> Self {
         let mut requests = Vec::with_capacity(from.len());
         let mut nodes = HashMap::with_capacity(from.len());
-        let mut id = 1;
-
-        for node in from {
+        for (id, node) in (1..).zip(from) {
             nodes.insert(node.name.clone(), Node::new(node.name.clone(), id));
 
             for req in node.requests {
                 requests.push((node.name.clone(), req));
             }
-            id += 1;
         }
 
-        requests.sort_by(|r1, r2| r2.1.global_order.cmp(&r1.1.global_order));
+        requests.sort_by_key(|r| std::cmp::Reverse(r.1.global_order));
 
         Self { nodes, requests }
     }